@@ -7,8 +7,9 @@ use ringbuf::{
     traits::{Producer, Split},
 };
 
+use tracing::error;
+
 use crate::state::StateHandle;
-use crate::ui;
 
 pub fn start_mic(
     state: StateHandle,
@@ -28,13 +29,12 @@ pub fn start_mic(
             .build_input_stream(
                 &config.into(),
                 move |data: &[f32], _| {
-                    let current = state.read();
-                    if !current.system_mute && !current.user_mute {
+                    if !state.is_muted() {
                         let _ = producer.push_slice(data);
                     }
                 },
                 |e| {
-                    ui::error_stream(e);
+                    error!("mic stream error: {:?}", e);
                 },
                 None,
             )
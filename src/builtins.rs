@@ -0,0 +1,632 @@
+//! Tools that come with the program rather than a python file, a manifest or an MCP server.
+//! Each assistant picks the ones it wants with `builtin_tools`.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use chrono::{FixedOffset, Local, NaiveTime, Utc};
+use serde_json::{Map, Value};
+
+use crate::calculator;
+use crate::config::BuiltinTool;
+use crate::scheduler;
+use crate::state::{StateHandle, TimerKind, unix_timestamp};
+use crate::tools::{Tool, ToolResult};
+
+const TIME_FORMAT: &str = "%A %d %B %Y, %H:%M (UTC%:z)";
+
+pub struct Builtins {
+    enabled: Vec<BuiltinTool>,
+    notes_directory: PathBuf,
+    state: StateHandle,
+}
+
+impl Builtins {
+    /// `None` when the assistant has none of them turned on
+    pub fn new(enabled: &[BuiltinTool], notes_directory: &str, state: StateHandle) -> Option<Self> {
+        if enabled.is_empty() {
+            return None;
+        }
+
+        Some(Builtins {
+            enabled: enabled.to_vec(),
+            notes_directory: PathBuf::from(notes_directory),
+            state,
+        })
+    }
+
+    pub fn tools(&self) -> Vec<Tool> {
+        self.enabled
+            .iter()
+            .flat_map(|builtin| describe(*builtin))
+            .collect()
+    }
+
+    /// `None` when `tool` isn't one of the enabled built-in tools. Mistakes in the arguments come
+    /// back as a failed call, so the llm can have another go.
+    pub fn call(&self, tool: &str, arguments: &Map<String, Value>) -> Option<ToolResult> {
+        if !self.tools().iter().any(|builtin| builtin.name == tool) {
+            return None;
+        }
+
+        let outcome = match tool {
+            "get_current_time" => current_time(optional_text(arguments, "timezone")),
+            "calculate" => text(arguments, "expression")
+                .and_then(calculator::evaluate)
+                .map(calculator::format_number),
+            "convert_units" => number(arguments, "value").and_then(|value| {
+                let from = text(arguments, "from_unit")?;
+                let to = text(arguments, "to_unit")?;
+                calculator::convert(value, from, to)
+                    .map(|converted| format!("{} {}", calculator::format_number(converted), to))
+            }),
+            "set_timer" => self.set_timer(arguments),
+            "set_alarm" => self.set_alarm(arguments),
+            "set_reminder" => self.set_reminder(arguments),
+            "list_timers" => Ok(self.list_timers()),
+            "cancel_timer" => self.cancel_timer(arguments),
+            "write_note" => self.write_note(arguments),
+            "read_note" => self.read_note(arguments),
+            "list_notes" => self.list_notes(),
+            "end_conversation" => {
+                self.state.update(|s| {
+                    s.is_waiting_for_name = true;
+                    s.time_since_name_was_said = None;
+                });
+                Ok("The conversation is over until your name is said again.".into())
+            }
+            "mute_microphone" => {
+                self.state.update(|s| s.user_mute = true);
+                Ok("The microphone is muted until it's turned back on with m.".into())
+            }
+            _ => Err(format!("{} isn't a built-in tool", tool)),
+        };
+
+        Some(match outcome {
+            Ok(text) => ToolResult {
+                exit_code: Some(0),
+                value: Some(Value::String(text)),
+                ..ToolResult::default()
+            },
+            Err(error) => ToolResult {
+                exit_code: Some(1),
+                error: Some(error),
+                ..ToolResult::default()
+            },
+        })
+    }
+
+    fn set_timer(&self, arguments: &Map<String, Value>) -> Result<String, String> {
+        let seconds = seconds(arguments)?;
+        let label = optional_text(arguments, "label").unwrap_or("");
+        let id = scheduler::add(
+            &self.state,
+            TimerKind::Timer,
+            label,
+            unix_timestamp() + seconds,
+        );
+
+        Ok(format!(
+            "Timer {} set for {}",
+            id,
+            scheduler::describe_duration(seconds)
+        ))
+    }
+
+    fn set_alarm(&self, arguments: &Map<String, Value>) -> Result<String, String> {
+        let time = clock_time(arguments)?;
+        let label = optional_text(arguments, "label").unwrap_or("");
+        let id = scheduler::add(
+            &self.state,
+            TimerKind::Alarm,
+            label,
+            scheduler::next_occurrence(time)?,
+        );
+
+        Ok(format!("Alarm {} set for {}", id, time.format("%H:%M")))
+    }
+
+    fn set_reminder(&self, arguments: &Map<String, Value>) -> Result<String, String> {
+        let label = text(arguments, "text")?;
+        let due = match (
+            arguments.contains_key("seconds"),
+            arguments.contains_key("time"),
+        ) {
+            (true, false) => unix_timestamp() + seconds(arguments)?,
+            (false, true) => scheduler::next_occurrence(clock_time(arguments)?)?,
+            _ => return Err("Reminders need either seconds or a time, not both".into()),
+        };
+        let id = scheduler::add(&self.state, TimerKind::Reminder, label, due);
+
+        Ok(format!(
+            "Reminder {} set for {} from now",
+            id,
+            scheduler::describe_duration(due.saturating_sub(unix_timestamp()))
+        ))
+    }
+
+    fn list_timers(&self) -> String {
+        let now = unix_timestamp();
+        let timers = self.state.read_with(|s| s.timers.clone());
+        if timers.is_empty() {
+            return "There are no timers, alarms or reminders".into();
+        }
+
+        timers
+            .iter()
+            .map(|timer| {
+                format!(
+                    "{} {}{}: {} left",
+                    match timer.kind {
+                        TimerKind::Timer => "Timer",
+                        TimerKind::Alarm => "Alarm",
+                        TimerKind::Reminder => "Reminder",
+                    },
+                    timer.id,
+                    if timer.label.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" ({})", timer.label)
+                    },
+                    scheduler::describe_duration(timer.due.saturating_sub(now))
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn cancel_timer(&self, arguments: &Map<String, Value>) -> Result<String, String> {
+        let id = number(arguments, "id")? as u64;
+
+        let mut cancelled = false;
+        self.state.update(|s| {
+            let before = s.timers.len();
+            s.timers.retain(|timer| timer.id != id);
+            cancelled = s.timers.len() != before;
+        });
+
+        if cancelled {
+            Ok(format!("Cancelled {}", id))
+        } else {
+            Err(format!("There's nothing set with the id {}", id))
+        }
+    }
+
+    fn write_note(&self, arguments: &Map<String, Value>) -> Result<String, String> {
+        let path = self.note_path(text(arguments, "name")?)?;
+        let content = text(arguments, "text")?;
+        let append = arguments
+            .get("append")
+            .is_some_and(|append| append.as_bool() == Some(true) || append == "true");
+
+        fs::create_dir_all(&self.notes_directory).map_err(|e| e.to_string())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        writeln!(file, "{}", content.trim_end()).map_err(|e| e.to_string())?;
+
+        Ok(if append {
+            "Added to the note"
+        } else {
+            "Saved the note"
+        }
+        .into())
+    }
+
+    fn read_note(&self, arguments: &Map<String, Value>) -> Result<String, String> {
+        let name = text(arguments, "name")?;
+        fs::read_to_string(self.note_path(name)?)
+            .map(|note| note.trim_end().to_string())
+            .map_err(|_| format!("There's no note called {}", name))
+    }
+
+    fn list_notes(&self) -> Result<String, String> {
+        let Ok(entries) = fs::read_dir(&self.notes_directory) else {
+            return Ok("There are no notes".into());
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".txt"))
+                    .map(String::from)
+            })
+            .collect();
+        names.sort();
+
+        Ok(if names.is_empty() {
+            "There are no notes".into()
+        } else {
+            names.join("\n")
+        })
+    }
+
+    /// Notes can't be anywhere but directly in the notes directory
+    fn note_path(&self, name: &str) -> Result<PathBuf, String> {
+        let name = name.trim();
+        if name.is_empty()
+            || name.starts_with('.')
+            || name.contains(['/', '\\', '\0'])
+            || name.contains("..")
+        {
+            return Err(format!("{} can't be used as a note name", name));
+        }
+
+        Ok(self.notes_directory.join(format!("{}.txt", name)))
+    }
+}
+
+/// The tools in each group, with their arguments typed like python's so they read the same
+fn describe(builtin: BuiltinTool) -> Vec<Tool> {
+    let tool = |name: &str, description: &str, arguments: &[(&str, &str, &str, bool)]| {
+        let mut properties = HashMap::new();
+        let mut required = Vec::new();
+
+        for (argument, kind, description, is_required) in arguments {
+            properties.insert(
+                argument.to_string(),
+                (kind.to_string(), description.to_string()),
+            );
+            if *is_required {
+                required.push(argument.to_string());
+            }
+        }
+
+        Tool {
+            name: name.into(),
+            description: description.into(),
+            properties,
+            required,
+        }
+    };
+
+    match builtin {
+        BuiltinTool::Time => vec![tool(
+            "get_current_time",
+            "Gets the current date and time",
+            &[(
+                "timezone",
+                "str",
+                "An offset like +05:30 or UTC-3, or UTC. Local time if left out",
+                false,
+            )],
+        )],
+        BuiltinTool::Calculator => vec![
+            tool(
+                "calculate",
+                "Works out an arithmetic expression with + - * / % ^, brackets, pi, e and \
+                 sqrt, abs, round, floor, ceil, sin, cos, tan, ln and log",
+                &[(
+                    "expression",
+                    "str",
+                    "The expression, like (2 + 3) * 4",
+                    true,
+                )],
+            ),
+            tool(
+                "convert_units",
+                "Converts between units of length, mass, volume, time, speed, data or temperature",
+                &[
+                    ("value", "float", "How many of from_unit", true),
+                    (
+                        "from_unit",
+                        "str",
+                        "The unit to convert from, like km",
+                        true,
+                    ),
+                    ("to_unit", "str", "The unit to convert to, like miles", true),
+                ],
+            ),
+        ],
+        BuiltinTool::Timers => vec![
+            tool(
+                "set_timer",
+                "Sets a timer that's said out loud when it's done",
+                &[
+                    ("seconds", "int", "How long the timer is", true),
+                    ("label", "str", "What the timer is for", false),
+                ],
+            ),
+            tool(
+                "set_alarm",
+                "Sets an alarm that goes off at the next time it's the given time of day",
+                &[
+                    ("time", "str", "The local time, like 07:30", true),
+                    ("label", "str", "What the alarm is for", false),
+                ],
+            ),
+            tool(
+                "set_reminder",
+                "Sets a reminder that's said out loud when it's due, either in some seconds or at \
+                 a time of day",
+                &[
+                    ("text", "str", "What to remind the user of", true),
+                    ("seconds", "int", "How long from now", false),
+                    ("time", "str", "The local time, like 07:30", false),
+                ],
+            ),
+            tool(
+                "list_timers",
+                "Lists the timers, alarms and reminders that are set",
+                &[],
+            ),
+            tool(
+                "cancel_timer",
+                "Cancels a timer, alarm or reminder",
+                &[("id", "int", "The number it was given when it was set", true)],
+            ),
+        ],
+        BuiltinTool::Notes => vec![
+            tool(
+                "write_note",
+                "Saves a note, replacing any note with the same name unless appending to it",
+                &[
+                    ("name", "str", "The note's name", true),
+                    ("text", "str", "What to write", true),
+                    (
+                        "append",
+                        "bool",
+                        "Adds to the end of the note (defaults to False)",
+                        false,
+                    ),
+                ],
+            ),
+            tool(
+                "read_note",
+                "Reads a note",
+                &[("name", "str", "The note's name", true)],
+            ),
+            tool("list_notes", "Lists the names of the saved notes", &[]),
+        ],
+        BuiltinTool::EndConversation => vec![tool(
+            "end_conversation",
+            "Stops listening until you're called by name again, for when the user is done talking",
+            &[],
+        )],
+        BuiltinTool::MuteMicrophone => vec![tool(
+            "mute_microphone",
+            "Mutes the microphone, for when the user wants you to stop listening altogether",
+            &[],
+        )],
+    }
+}
+
+fn current_time(timezone: Option<&str>) -> Result<String, String> {
+    let timezone = timezone.map(str::trim).unwrap_or("");
+    if timezone.is_empty() || timezone.eq_ignore_ascii_case("local") {
+        return Ok(Local::now().format(TIME_FORMAT).to_string());
+    }
+
+    let offset = parse_offset(timezone).ok_or_else(|| {
+        format!(
+            "Don't know the timezone {}, only offsets like +05:30 or UTC-3",
+            timezone
+        )
+    })?;
+    Ok(Utc::now()
+        .with_timezone(&offset)
+        .format(TIME_FORMAT)
+        .to_string())
+}
+
+/// `UTC`, `GMT`, `+2`, `UTC+5:30`, `-0800` and the like
+fn parse_offset(timezone: &str) -> Option<FixedOffset> {
+    let upper = timezone.to_uppercase();
+    let offset = upper
+        .strip_prefix("UTC")
+        .or_else(|| upper.strip_prefix("GMT"))
+        .unwrap_or(&upper)
+        .trim();
+    if offset.is_empty() {
+        return FixedOffset::east_opt(0);
+    }
+
+    let (sign, offset) = if let Some(offset) = offset.strip_prefix('+') {
+        (1, offset)
+    } else if let Some(offset) = offset.strip_prefix('-') {
+        (-1, offset)
+    } else {
+        return None;
+    };
+    let (hours, minutes) = match offset.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if offset.len() == 4 && offset.is_ascii() => offset.split_at(2),
+        None => (offset, "0"),
+    };
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60) as i32)
+}
+
+/// Whole seconds from now, at least one
+fn seconds(arguments: &Map<String, Value>) -> Result<u64, String> {
+    let seconds = number(arguments, "seconds")?;
+    if seconds < 1.0 {
+        return Err("Timers need to be at least a second long".into());
+    }
+
+    Ok(seconds.round() as u64)
+}
+
+fn clock_time(arguments: &Map<String, Value>) -> Result<NaiveTime, String> {
+    let time = text(arguments, "time")?;
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| format!("{} isn't a time like 07:30", time))
+}
+
+fn text<'a>(arguments: &'a Map<String, Value>, name: &str) -> Result<&'a str, String> {
+    optional_text(arguments, name).ok_or_else(|| format!("{} should be a string", name))
+}
+
+fn optional_text<'a>(arguments: &'a Map<String, Value>, name: &str) -> Option<&'a str> {
+    arguments.get(name).and_then(Value::as_str)
+}
+
+/// Numbers sometimes come in as strings, depending on the model's tool call format
+fn number(arguments: &Map<String, Value>, name: &str) -> Result<f64, String> {
+    match arguments.get(name) {
+        Some(Value::Number(number)) => number.as_f64(),
+        Some(Value::String(text)) => text.trim().parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{} should be a number", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_path;
+
+    fn builtins(enabled: &[BuiltinTool], notes_directory: &str) -> Builtins {
+        Builtins::new(enabled, notes_directory, StateHandle::new()).unwrap()
+    }
+
+    fn arguments(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn said(result: Option<ToolResult>) -> String {
+        let result = result.unwrap();
+        assert_eq!(result.exit_code, Some(0), "{:?}", result.error);
+        result.for_llm()
+    }
+
+    #[test]
+    fn only_enabled_tools_are_offered() {
+        assert!(Builtins::new(&[], "notes", StateHandle::new()).is_none());
+
+        let builtins = builtins(&[BuiltinTool::Calculator], "notes");
+        let names: Vec<String> = builtins.tools().into_iter().map(|tool| tool.name).collect();
+        assert_eq!(names, ["calculate", "convert_units"]);
+        assert!(builtins.call("get_current_time", &Map::new()).is_none());
+
+        let sum = builtins.call(
+            "calculate",
+            &arguments(serde_json::json!({ "expression": "6 * 7" })),
+        );
+        assert_eq!(said(sum), "42");
+        let converted = builtins.call(
+            "convert_units",
+            &arguments(serde_json::json!({ "value": "5", "from_unit": "km", "to_unit": "m" })),
+        );
+        assert_eq!(said(converted), "5000 m");
+
+        let failed = builtins
+            .call(
+                "calculate",
+                &arguments(serde_json::json!({ "expression": "1 / 0" })),
+            )
+            .unwrap();
+        assert_eq!(failed.exit_code, Some(1));
+    }
+
+    #[test]
+    fn reads_timezone_offsets() {
+        assert_eq!(parse_offset("UTC"), FixedOffset::east_opt(0));
+        assert_eq!(parse_offset("+05:30"), FixedOffset::east_opt(19800));
+        assert_eq!(parse_offset("UTC-3"), FixedOffset::east_opt(-10800));
+        assert_eq!(parse_offset("-0800"), FixedOffset::east_opt(-28800));
+        assert_eq!(parse_offset("Europe/Paris"), None);
+        assert_eq!(parse_offset("+25"), None);
+    }
+
+    #[test]
+    fn timers_go_into_the_state() {
+        let builtins = builtins(&[BuiltinTool::Timers], "notes");
+
+        said(builtins.call(
+            "set_timer",
+            &arguments(serde_json::json!({ "seconds": 90, "label": "tea" })),
+        ));
+        said(builtins.call(
+            "set_alarm",
+            &arguments(serde_json::json!({ "time": "07:30" })),
+        ));
+
+        let timers = builtins.state.read_with(|s| s.timers.clone());
+        assert_eq!(timers.len(), 2);
+        assert_eq!(timers[0].label, "tea");
+        assert_eq!(timers[1].kind, TimerKind::Alarm);
+        assert!(said(builtins.call("list_timers", &Map::new())).contains("Timer 1 (tea)"));
+
+        said(builtins.call("cancel_timer", &arguments(serde_json::json!({ "id": 1 }))));
+        assert_eq!(builtins.state.read_with(|s| s.timers.len()), 1);
+        let missing = builtins
+            .call("cancel_timer", &arguments(serde_json::json!({ "id": 1 })))
+            .unwrap();
+        assert_eq!(missing.exit_code, Some(1));
+
+        said(builtins.call(
+            "set_reminder",
+            &arguments(serde_json::json!({ "text": "stretch", "seconds": 600 })),
+        ));
+        let both = builtins
+            .call(
+                "set_reminder",
+                &arguments(serde_json::json!({ "text": "x", "seconds": 1, "time": "07:30" })),
+            )
+            .unwrap();
+        assert_eq!(both.exit_code, Some(1));
+        assert!(said(builtins.call("list_timers", &Map::new())).contains("Reminder 3 (stretch)"));
+    }
+
+    #[test]
+    fn notes_stay_in_their_directory() {
+        let directory = temp_path("notes", "d");
+        let builtins = builtins(&[BuiltinTool::Notes], directory.to_str().unwrap());
+
+        said(builtins.call(
+            "write_note",
+            &arguments(serde_json::json!({ "name": "shopping", "text": "eggs" })),
+        ));
+        said(builtins.call(
+            "write_note",
+            &arguments(serde_json::json!({ "name": "shopping", "text": "milk", "append": true })),
+        ));
+        let read = builtins.call(
+            "read_note",
+            &arguments(serde_json::json!({ "name": "shopping" })),
+        );
+        assert_eq!(said(read), "eggs\nmilk");
+        assert_eq!(said(builtins.call("list_notes", &Map::new())), "shopping");
+
+        for name in ["../escape", "/etc/passwd", ".hidden", "a\\b"] {
+            let refused = builtins
+                .call(
+                    "write_note",
+                    &arguments(serde_json::json!({ "name": name, "text": "x" })),
+                )
+                .unwrap();
+            assert_eq!(refused.exit_code, Some(1), "{}", name);
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn controls_act_on_the_state() {
+        let builtins = builtins(
+            &[BuiltinTool::EndConversation, BuiltinTool::MuteMicrophone],
+            "notes",
+        );
+
+        said(builtins.call("end_conversation", &Map::new()));
+        assert!(builtins.state.read_with(|s| s.is_waiting_for_name));
+
+        said(builtins.call("mute_microphone", &Map::new()));
+        assert!(builtins.state.is_muted());
+    }
+}
@@ -0,0 +1,397 @@
+//! Arithmetic and unit conversion for the built-in `calculate` and `convert_units` tools, since
+//! small models are bad at both and python shouldn't be needed for them.
+
+use std::f64::consts::{E, PI};
+
+/// Works out an expression like `2 * (3 + 4) ^ 2` or `sqrt(2) / 2`
+pub fn evaluate(expression: &str) -> Result<f64, String> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+    };
+
+    let value = parser.expression()?;
+    if let Some(token) = parser.peek() {
+        return Err(format!("Didn't expect {} there", token));
+    }
+    if !value.is_finite() {
+        return Err("The answer isn't a finite number".into());
+    }
+
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Operator(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Operator(operator) => write!(f, "{}", operator),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                // Thousands separators come out of speech as often as not
+                if c == ',' {
+                    chars.next();
+                    continue;
+                }
+                if !c.is_ascii_digit() && c != '.' {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            let number = number
+                .parse()
+                .map_err(|_| format!("{} isn't a number", number))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !c.is_alphanumeric() {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Name(name.to_lowercase()));
+        } else if "+-*/%^()×÷".contains(c) {
+            let operator = match c {
+                '×' => '*',
+                '÷' => '/',
+                c => c,
+            };
+            tokens.push(Token::Operator(operator));
+            chars.next();
+        } else {
+            return Err(format!("Don't know what {} means", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, operator: char) -> bool {
+        if self.peek() == Some(&Token::Operator(operator)) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Sums, the loosest binding
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    return Err("Can't divide by zero".into());
+                }
+                value /= divisor;
+            } else if self.eat('%') {
+                value %= self.unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// `-2 ^ 2` is -4, like on paper
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.eat('-') {
+            return Ok(-self.unary()?);
+        }
+        if self.eat('+') {
+            return self.unary();
+        }
+        self.power()
+    }
+
+    /// Right associative, so `2 ^ 3 ^ 2` is `2 ^ 9`
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(base.powf(self.unary()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(number),
+            Some(Token::Operator('(')) => {
+                let value = self.expression()?;
+                if !self.eat(')') {
+                    return Err("A bracket was never closed".into());
+                }
+                Ok(value)
+            }
+            Some(Token::Name(name)) => match name.as_str() {
+                "pi" => Ok(PI),
+                "e" => Ok(E),
+                _ => {
+                    let argument = self.atom()?;
+                    apply(&name, argument)
+                }
+            },
+            Some(token) => Err(format!("Didn't expect {} there", token)),
+            None => Err("The expression ends too soon".into()),
+        }
+    }
+}
+
+fn apply(function: &str, x: f64) -> Result<f64, String> {
+    Ok(match function {
+        "sqrt" if x < 0.0 => return Err("Can't take the square root of a negative number".into()),
+        "sqrt" => x.sqrt(),
+        "abs" => x.abs(),
+        "round" => x.round(),
+        "floor" => x.floor(),
+        "ceil" => x.ceil(),
+        "sin" => x.sin(),
+        "cos" => x.cos(),
+        "tan" => x.tan(),
+        "ln" if x <= 0.0 => return Err("Logarithms need a positive number".into()),
+        "ln" => x.ln(),
+        "log" if x <= 0.0 => return Err("Logarithms need a positive number".into()),
+        "log" => x.log10(),
+        _ => return Err(format!("There's no function called {}", function)),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dimension {
+    Length,
+    Mass,
+    Volume,
+    Time,
+    Speed,
+    Data,
+    Temperature,
+}
+
+/// Names a unit goes by, what it measures and how many of the dimension's base unit it is.
+/// Temperatures are converted on their own since they don't start at zero.
+const UNITS: &[(&[&str], Dimension, f64)] = &[
+    (
+        &["mm", "millimeter", "millimetre"],
+        Dimension::Length,
+        0.001,
+    ),
+    (&["cm", "centimeter", "centimetre"], Dimension::Length, 0.01),
+    (&["m", "meter", "metre"], Dimension::Length, 1.0),
+    (&["km", "kilometer", "kilometre"], Dimension::Length, 1000.0),
+    (&["in", "inch", "inches"], Dimension::Length, 0.0254),
+    (&["ft", "foot", "feet"], Dimension::Length, 0.3048),
+    (&["yd", "yard"], Dimension::Length, 0.9144),
+    (&["mi", "mile"], Dimension::Length, 1609.344),
+    (&["nmi", "nautical mile"], Dimension::Length, 1852.0),
+    (&["mg", "milligram"], Dimension::Mass, 0.001),
+    (&["g", "gram"], Dimension::Mass, 1.0),
+    (&["kg", "kilogram", "kilo"], Dimension::Mass, 1000.0),
+    (&["t", "tonne", "metric ton"], Dimension::Mass, 1_000_000.0),
+    (&["oz", "ounce"], Dimension::Mass, 28.349523125),
+    (&["lb", "lbs", "pound"], Dimension::Mass, 453.59237),
+    (&["st", "stone"], Dimension::Mass, 6350.29318),
+    (
+        &["ml", "milliliter", "millilitre"],
+        Dimension::Volume,
+        0.001,
+    ),
+    (&["cl", "centiliter", "centilitre"], Dimension::Volume, 0.01),
+    (&["l", "liter", "litre"], Dimension::Volume, 1.0),
+    (&["tsp", "teaspoon"], Dimension::Volume, 0.00492892159375),
+    (&["tbsp", "tablespoon"], Dimension::Volume, 0.01478676478125),
+    (&["cup"], Dimension::Volume, 0.2365882365),
+    (
+        &["fl oz", "fluid ounce"],
+        Dimension::Volume,
+        0.0295735295625,
+    ),
+    (&["pt", "pint"], Dimension::Volume, 0.473176473),
+    (&["gal", "gallon"], Dimension::Volume, 3.785411784),
+    (&["ms", "millisecond"], Dimension::Time, 0.001),
+    (&["s", "sec", "second"], Dimension::Time, 1.0),
+    (&["min", "minute"], Dimension::Time, 60.0),
+    (&["h", "hr", "hour"], Dimension::Time, 3600.0),
+    (&["d", "day"], Dimension::Time, 86400.0),
+    (&["wk", "week"], Dimension::Time, 604800.0),
+    (&["yr", "year"], Dimension::Time, 31_557_600.0),
+    (
+        &["m/s", "meters per second", "metres per second"],
+        Dimension::Speed,
+        1.0,
+    ),
+    (
+        &["km/h", "kph", "kilometers per hour", "kilometres per hour"],
+        Dimension::Speed,
+        1.0 / 3.6,
+    ),
+    (&["mph", "miles per hour"], Dimension::Speed, 0.44704),
+    (&["kn", "knot"], Dimension::Speed, 0.514444),
+    (&["b", "byte"], Dimension::Data, 1.0),
+    (&["kb", "kilobyte"], Dimension::Data, 1e3),
+    (&["mb", "megabyte"], Dimension::Data, 1e6),
+    (&["gb", "gigabyte"], Dimension::Data, 1e9),
+    (&["tb", "terabyte"], Dimension::Data, 1e12),
+    (&["kib", "kibibyte"], Dimension::Data, 1024.0),
+    (&["mib", "mebibyte"], Dimension::Data, 1_048_576.0),
+    (&["gib", "gibibyte"], Dimension::Data, 1_073_741_824.0),
+    (
+        &["c", "°c", "celsius", "degrees celsius"],
+        Dimension::Temperature,
+        0.0,
+    ),
+    (
+        &["f", "°f", "fahrenheit", "degrees fahrenheit"],
+        Dimension::Temperature,
+        0.0,
+    ),
+    (&["k", "kelvin"], Dimension::Temperature, 0.0),
+];
+
+fn find_unit(name: &str) -> Option<(&'static str, Dimension, f64)> {
+    let name = name.trim().to_lowercase();
+    // Plurals are the same unit, apart from the ones that end in s anyway
+    let singular = name.strip_suffix('s').unwrap_or(&name);
+
+    UNITS.iter().find_map(|(names, dimension, factor)| {
+        names
+            .iter()
+            .find(|unit| **unit == name || **unit == singular)
+            .map(|_| (names[0], *dimension, *factor))
+    })
+}
+
+/// Converts `value` between two units of the same kind, like `km` and `miles`
+pub fn convert(value: f64, from: &str, to: &str) -> Result<f64, String> {
+    let (from_unit, from_dimension, from_factor) =
+        find_unit(from).ok_or_else(|| format!("Don't know the unit {}", from))?;
+    let (to_unit, to_dimension, to_factor) =
+        find_unit(to).ok_or_else(|| format!("Don't know the unit {}", to))?;
+
+    if from_dimension != to_dimension {
+        return Err(format!("Can't convert {} to {}", from, to));
+    }
+
+    if from_dimension == Dimension::Temperature {
+        let kelvin = match from_unit {
+            "c" => value + 273.15,
+            "f" => (value - 32.0) * 5.0 / 9.0 + 273.15,
+            _ => value,
+        };
+        return Ok(match to_unit {
+            "c" => kelvin - 273.15,
+            "f" => (kelvin - 273.15) * 9.0 / 5.0 + 32.0,
+            _ => kelvin,
+        });
+    }
+
+    Ok(value * from_factor / to_factor)
+}
+
+/// Rounded to something that's easy to say out loud
+pub fn format_number(value: f64) -> String {
+    let rounded = (value * 1e6).round() / 1e6;
+    if rounded == rounded.trunc() && rounded.abs() < 1e15 {
+        format!("{}", rounded as i64)
+    } else {
+        format!("{}", rounded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_expressions() {
+        assert_eq!(evaluate("2 + 3 * 4"), Ok(14.0));
+        assert_eq!(evaluate("(2 + 3) * 4"), Ok(20.0));
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(evaluate("10 % 4"), Ok(2.0));
+        assert_eq!(evaluate("1,000 × 3"), Ok(3000.0));
+        assert_eq!(evaluate("sqrt(16) + abs(-2)"), Ok(6.0));
+        assert_eq!(format_number(evaluate("cos(pi)").unwrap()), "-1");
+
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("2 +").is_err());
+        assert!(evaluate("import os").is_err());
+    }
+
+    #[test]
+    fn converts_units() {
+        assert_eq!(
+            format_number(convert(1.0, "mile", "km").unwrap()),
+            "1.609344"
+        );
+        assert_eq!(format_number(convert(2.0, "kg", "grams").unwrap()), "2000");
+        assert_eq!(
+            format_number(convert(100.0, "celsius", "F").unwrap()),
+            "212"
+        );
+        assert_eq!(
+            format_number(convert(0.0, "°C", "kelvin").unwrap()),
+            "273.15"
+        );
+        assert_eq!(
+            format_number(convert(90.0, "minutes", "hours").unwrap()),
+            "1.5"
+        );
+
+        assert!(convert(1.0, "kg", "metres").is_err());
+        assert!(convert(1.0, "furlongs", "metres").is_err());
+    }
+}
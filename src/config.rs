@@ -1,7 +1,10 @@
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::time::{Duration, Instant};
 
+use crate::export::ExportFormat;
 use crate::ui;
 
 const CONFIG_FILE: &str = "./config.toml";
@@ -19,8 +22,249 @@ pub struct GlobalConfig {
     pub default_assistant: Option<String>,
     pub enable_word_by_word_response: bool,
     pub tool_path: Option<String>,
+    /// TOML or JSON files declaring tools that are programs, see manifest.rs
+    #[serde(default)]
+    pub tool_manifests: Vec<String>,
     #[serde(default)]
     pub orb_mode: bool,
+    #[serde(default)]
+    pub embedding_model_path: Option<String>,
+    #[serde(default = "default_memory_top_k")]
+    pub memory_top_k: usize,
+    #[serde(default = "default_memory_token_budget")]
+    pub memory_token_budget: usize,
+    #[serde(default = "default_export_directory")]
+    pub export_directory: String,
+    #[serde(default = "default_export_formats")]
+    pub export_formats: Vec<ExportFormat>,
+    #[serde(default)]
+    pub export_include_debug: bool,
+    #[serde(default = "default_true")]
+    pub keep_alternative_replies: bool,
+    #[serde(default = "default_session_directory")]
+    pub session_directory: String,
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+    #[serde(default)]
+    pub tool_limits: ToolLimits,
+    #[serde(default)]
+    pub tool_sandbox: ToolSandbox,
+    #[serde(default)]
+    pub tool_permissions: ToolPermissions,
+    #[serde(default = "default_metrics_file")]
+    pub metrics_file: String,
+    #[serde(default = "default_log_directory")]
+    pub log_directory: String,
+    /// Which logs are kept, in `RUST_LOG` syntax
+    #[serde(default = "default_log_filter")]
+    pub log_filter: String,
+}
+
+/// Stops a model that keeps calling tools from looping forever within one turn, or filling its
+/// context with one tool's output
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ToolLimits {
+    /// Times the llm can go back to tools before it has to answer
+    pub max_rounds: usize,
+    pub max_calls: usize,
+    /// A turn is stopped once it has taken this long, tools and all
+    pub turn_timeout_secs: u64,
+    /// Longer results are cut down in the middle before the llm sees them
+    pub max_result_tokens: usize,
+}
+
+impl Default for ToolLimits {
+    fn default() -> Self {
+        ToolLimits {
+            max_rounds: 5,
+            max_calls: 10,
+            turn_timeout_secs: 60,
+            max_result_tokens: 1000,
+        }
+    }
+}
+
+/// Whether a tool call runs straight away, after the user says yes, or not at all
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolPermission {
+    #[default]
+    Auto,
+    Confirm,
+    Deny,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ToolPermissions {
+    /// For tools that aren't listed in `tools`
+    pub default: ToolPermission,
+    pub tools: HashMap<String, ToolPermission>,
+}
+
+impl ToolPermissions {
+    pub fn for_tool(&self, tool: &str) -> ToolPermission {
+        self.tools.get(tool).copied().unwrap_or(self.default)
+    }
+}
+
+/// What tool calls are allowed to do. Tools listed under `tools` get a policy of their own
+/// (and a python process of their own to go with it), where anything left unset is the default
+/// rather than what's set for the rest of the tools.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ToolSandbox {
+    #[serde(flatten)]
+    pub policy: SandboxPolicy,
+    pub tools: HashMap<String, SandboxPolicy>,
+}
+
+impl ToolSandbox {
+    pub fn policy_for(&self, tool: &str) -> &SandboxPolicy {
+        self.tools.get(tool).unwrap_or(&self.policy)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct SandboxPolicy {
+    /// A call that takes longer than this is stopped
+    pub timeout_secs: u64,
+    /// CPU time a single call can use
+    pub cpu_secs: Option<u64>,
+    /// Memory the tool's python process can use in total
+    pub memory_mb: Option<u64>,
+    /// Where the tools run, the tool file's directory when unset
+    pub working_directory: Option<String>,
+    /// Only lets tools write inside the working directory. They can still read anywhere.
+    /// Needs Landlock (Linux 5.13 and up)
+    pub confine_to_working_directory: bool,
+    /// Environment variables the tools can see, all of them when unset
+    pub allowed_env: Option<Vec<String>>,
+    /// Turning it off needs unprivileged user namespaces (Linux)
+    pub network: bool,
+}
+
+impl SandboxPolicy {
+    /// How long a call can take, cut short if the turn it's part of has less time left than that
+    pub fn timeout(&self, deadline: Option<Instant>) -> Duration {
+        let timeout = Duration::from_secs(self.timeout_secs);
+        deadline.map_or(timeout, |deadline| {
+            timeout.min(deadline.saturating_duration_since(Instant::now()))
+        })
+    }
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy {
+            timeout_secs: 30,
+            cpu_secs: None,
+            memory_mb: None,
+            working_directory: None,
+            confine_to_working_directory: false,
+            allowed_env: None,
+            network: true,
+        }
+    }
+}
+
+/// How replies are sampled. Anything left unset is skipped in the sampler chain.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SamplingConfig {
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub temperature: f32,
+    pub repeat_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// How many of the last tokens the repeat, presence and frequency penalties look at
+    pub penalty_last_n: i32,
+    pub dry_multiplier: Option<f32>,
+    pub dry_base: f32,
+    pub dry_allowed_length: i32,
+    pub dry_penalty_last_n: i32,
+    /// 1 or 2, replaces top_k, top_p, min_p and typical_p when set
+    pub mirostat: Option<u8>,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    /// Fixed seed so the same conversation always gets the same reply. Regenerating still
+    /// gives a different one.
+    pub seed: Option<u32>,
+    /// Tokens the whole turn may generate, tool calls included
+    pub max_tokens: Option<usize>,
+    /// Generation stops as soon as the reply contains one of these, which is left out of the reply
+    pub stop: Vec<String>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            top_k: None,
+            top_p: None,
+            min_p: Some(0.05),
+            typical_p: None,
+            temperature: 0.8,
+            repeat_penalty: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            penalty_last_n: 64,
+            dry_multiplier: None,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_penalty_last_n: -1,
+            mirostat: None,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            seed: None,
+            max_tokens: None,
+            stop: Vec::new(),
+        }
+    }
+}
+
+fn default_log_directory() -> String {
+    "logs".into()
+}
+
+fn default_log_filter() -> String {
+    "info".into()
+}
+
+fn default_metrics_file() -> String {
+    "metrics.jsonl".into()
+}
+
+fn default_session_directory() -> String {
+    "sessions".into()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_memory_top_k() -> usize {
+    5
+}
+
+fn default_memory_token_budget() -> usize {
+    256
+}
+
+fn default_export_directory() -> String {
+    "exports".into()
+}
+
+fn default_export_formats() -> Vec<ExportFormat> {
+    vec![
+        ExportFormat::Markdown,
+        ExportFormat::Jsonl,
+        ExportFormat::Text,
+    ]
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -35,8 +279,63 @@ pub struct Assistant {
     pub conversation_file: Option<String>,
     #[serde(default)]
     pub tool_path: Option<String>,
+    /// Added to the global manifests rather than replacing them
+    #[serde(default)]
+    pub tool_manifests: Vec<String>,
+    #[serde(default)]
+    pub mcp_servers: Vec<McpServer>,
+    /// Tools that come with the program, so they don't need python
+    #[serde(default)]
+    pub builtin_tools: Vec<BuiltinTool>,
+    /// Where the notes tools keep their notes, `{name}_notes` by default
+    #[serde(default)]
+    pub notes_directory: Option<String>,
     #[serde(default)]
     pub orb_colour: Option<u32>,
+    /// Replaces the global sampling settings as a whole
+    #[serde(default)]
+    pub sampling: Option<SamplingConfig>,
+    /// Small model sharing the main model's vocabulary, used for speculative decoding
+    #[serde(default)]
+    pub draft_model_path: Option<String>,
+    /// Most tokens the draft model guesses ahead at once
+    #[serde(default = "default_draft_tokens")]
+    pub draft_tokens: usize,
+}
+
+/// A Model Context Protocol server to use tools from, started when the assistant is and talked to
+/// over its stdin and stdout
+#[derive(Debug, Deserialize, Clone)]
+pub struct McpServer {
+    /// Also where its sandbox policy goes, under `[global.tool_sandbox.tools.<name>]`
+    pub name: String,
+    /// The program and its arguments
+    pub command: Vec<String>,
+    /// Set on top of whatever the sandbox lets through
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+/// A group of built-in tools that can be turned on for an assistant
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BuiltinTool {
+    /// The date and time, anywhere
+    Time,
+    /// Arithmetic and unit conversion
+    Calculator,
+    /// Timers, alarms and reminders that are said out loud when they go off
+    Timers,
+    /// Reading and writing notes in the notes directory
+    Notes,
+    /// Stops listening until the assistant's name is said again
+    EndConversation,
+    /// Mutes the mic until it's turned back on with `m`
+    MuteMicrophone,
+}
+
+fn default_draft_tokens() -> usize {
+    8
 }
 
 impl Assistant {
@@ -45,6 +344,7 @@ impl Assistant {
         llm_model_path: Option<String>,
         piper_model_path: Option<String>,
         tool_path: Option<String>,
+        tool_manifests: &[String],
     ) -> Assistant {
         Assistant {
             name: self.name.clone(),
@@ -53,7 +353,18 @@ impl Assistant {
             piper_model_path: self.piper_model_path.clone().or(piper_model_path),
             conversation_file: self.conversation_file.clone(),
             tool_path: self.tool_path.clone().or(tool_path),
+            tool_manifests: tool_manifests
+                .iter()
+                .chain(&self.tool_manifests)
+                .cloned()
+                .collect(),
+            mcp_servers: self.mcp_servers.clone(),
+            builtin_tools: self.builtin_tools.clone(),
+            notes_directory: self.notes_directory.clone(),
             orb_colour: self.orb_colour.or(Some(0x0120ad)),
+            sampling: self.sampling.clone(),
+            draft_model_path: self.draft_model_path.clone(),
+            draft_tokens: self.draft_tokens,
         }
     }
 
@@ -62,6 +373,31 @@ impl Assistant {
             format!("{}_history.txt", self.name.to_lowercase().replace(' ', "_"))
         })
     }
+
+    pub fn notes_directory(&self) -> String {
+        self.notes_directory
+            .clone()
+            .unwrap_or_else(|| format!("{}_notes", self.name.to_lowercase().replace(' ', "_")))
+    }
+
+    pub fn journal_file(&self) -> String {
+        format!(
+            "{}_journal.jsonl",
+            self.name.to_lowercase().replace(' ', "_")
+        )
+    }
+
+    /// Where timers, alarms and reminders are kept while they're waiting to go off
+    pub fn timers_file(&self) -> String {
+        format!("{}_timers.json", self.name.to_lowercase().replace(' ', "_"))
+    }
+
+    pub fn memory_index_file(&self) -> String {
+        format!(
+            "{}_memory_index.json",
+            self.name.to_lowercase().replace(' ', "_")
+        )
+    }
 }
 
 #[derive(Debug, Deserialize)]
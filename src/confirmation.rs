@@ -0,0 +1,152 @@
+use std::time::Instant;
+
+use crate::config::ToolPermission;
+use crate::state::{LifeCycleState, LlmState, PendingToolCall, StateHandle};
+use crate::tools::{self, ToolCall, ToolResult, Tools};
+
+const YES: &[&str] = &["yes", "yeah", "yep", "yup", "sure", "ok", "okay", "allow"];
+const NO: &[&str] = &[
+    "no", "nope", "nah", "don't", "dont", "stop", "cancel", "deny",
+];
+
+/// Runs a call from the llm if its tool's permission allows it, asking the user first for
+/// `confirm` tools. Refusals come back as `Ok` since they're for the llm to read and pass on.
+/// The call is stopped if it's still going at `deadline`, which is pushed back by however long
+/// the user took to answer so that isn't counted against the turn.
+pub fn run_if_allowed(
+    state: &StateHandle,
+    tools: &Tools,
+    call: &ToolCall,
+    mut deadline: Option<&mut Instant>,
+) -> anyhow::Result<ToolResult> {
+    let name = &call.name;
+
+    match tools.permissions.for_tool(name) {
+        ToolPermission::Auto => tools::run_tool(tools, call, deadline.copied()),
+        ToolPermission::Deny => Ok(ToolResult::not_run(format!(
+            "{} can't be used, the user hasn't allowed it.",
+            name
+        ))),
+        ToolPermission::Confirm => {
+            let asked_at = Instant::now();
+            let approved = ask(state, call, &question(name, &call.arguments));
+            if let Some(deadline) = deadline.as_deref_mut() {
+                *deadline += asked_at.elapsed();
+            }
+
+            if approved {
+                tools::run_tool(tools, call, deadline.copied())
+            } else {
+                Ok(ToolResult::not_run(format!(
+                    "The user said no, so {} wasn't run.",
+                    name
+                )))
+            }
+        }
+    }
+}
+
+/// Shows the call, asks about it out loud and waits for a yes or no by key or by voice.
+/// Shutting down counts as a no.
+fn ask(state: &StateHandle, call: &ToolCall, question: &str) -> bool {
+    let events = state.subscribe();
+
+    state.update(|s| {
+        s.pending_tool_call = Some(PendingToolCall {
+            call: call.to_string(),
+            approved: None,
+        });
+        // Not thinking any more, the user's turn
+        s.llm_state = LlmState::AwaitingInput;
+        s.tts_commands.push(question.into());
+    });
+
+    let approved = loop {
+        let (approved, is_shutting_down) = state.read_with(|s| {
+            (
+                s.pending_tool_call
+                    .as_ref()
+                    .and_then(|pending| pending.approved),
+                s.life_cycle_state == LifeCycleState::ShuttingDown,
+            )
+        });
+
+        if let Some(approved) = approved {
+            break approved;
+        }
+        if is_shutting_down || !events.wait() {
+            break false;
+        }
+    };
+
+    state.update(|s| {
+        s.pending_tool_call = None;
+        s.llm_state = LlmState::RunningInference;
+        s.system_mute = true;
+    });
+
+    approved
+}
+
+fn question(name: &str, arguments: &serde_json::Map<String, serde_json::Value>) -> String {
+    let name = name.replace('_', " ");
+    if arguments.is_empty() {
+        return format!("Should I run {}?", name);
+    }
+
+    let arguments = arguments
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(text) => text.clone(),
+                value => value.to_string(),
+            };
+            format!("{} {}", key.replace('_', " "), value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("Should I run {} with {}?", name, arguments)
+}
+
+/// Answers the tool call that's waiting, if there is one
+pub fn answer(state: &StateHandle, approved: bool) {
+    state.update(|s| {
+        if let Some(pending) = s.pending_tool_call.as_mut() {
+            pending.approved = Some(approved);
+        }
+    });
+}
+
+/// Answers the tool call that's waiting with what the user said, asking again if it was neither
+pub fn answer_by_voice(state: &StateHandle, text: &str) {
+    match parse_answer(text) {
+        Some(approved) => answer(state, approved),
+        None => state.update(|s| {
+            s.system_mute = true;
+            s.tts_commands.push("Sorry, was that a yes or a no?".into());
+        }),
+    }
+}
+
+/// Yes or no, or `None` if it's neither or both
+pub fn parse_answer(text: &str) -> Option<bool> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphabetic() || *c == '\'')
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .collect();
+
+    let yes = words.iter().any(|word| YES.contains(&word.as_str()));
+    let no = words.iter().any(|word| NO.contains(&word.as_str()));
+
+    match (yes, no) {
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        _ => None,
+    }
+}
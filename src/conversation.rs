@@ -0,0 +1,398 @@
+use std::fs;
+use std::ops::Index;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::ConversationSnippet;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Node {
+    snippet: ConversationSnippet,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// Child last on the active branch, so switching back to a branch picks up where it left off
+    active_child: Option<usize>,
+}
+
+/// Every branch of the conversation. Most things only care about the active branch,
+/// so the Vec-like methods (`push`, `pop`, `last`, `iter`...) all act on that.
+/// Nodes are shared between clones, so copying the state around doesn't copy every message.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConversationTree {
+    nodes: Vec<Arc<Node>>,
+    roots: Vec<usize>,
+    /// Node ids from the root to the leaf of the active branch
+    path: Vec<usize>,
+}
+
+impl ConversationTree {
+    pub fn len(&self) -> usize {
+        self.path.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&ConversationSnippet> {
+        self.path.get(index).map(|&id| &self.nodes[id].snippet)
+    }
+
+    pub fn last(&self) -> Option<&ConversationSnippet> {
+        self.path.last().map(|&id| &self.nodes[id].snippet)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            tree: self,
+            ids: self.path.iter(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<ConversationSnippet> {
+        self.iter().cloned().collect()
+    }
+
+    /// Adds a snippet to the end of the active branch
+    pub fn push(&mut self, snippet: ConversationSnippet) {
+        let parent = self.path.last().copied();
+        let id = self.nodes.len();
+
+        self.nodes.push(Arc::new(Node {
+            snippet,
+            parent,
+            children: Vec::new(),
+            active_child: None,
+        }));
+
+        self.siblings_mut(parent).push(id);
+        self.set_active_child(parent, Some(id));
+        self.path.push(id);
+    }
+
+    /// Removes the last snippet of the active branch. Other branches are left alone.
+    pub fn pop(&mut self) -> Option<ConversationSnippet> {
+        let id = self.path.pop()?;
+        let parent = self.nodes[id].parent;
+        self.set_active_child(parent, None);
+
+        if !self.nodes[id].children.is_empty() {
+            // Still the root of other branches, so only step off it
+            return Some(self.nodes[id].snippet.clone());
+        }
+
+        self.siblings_mut(parent).retain(|&child| child != id);
+
+        // The last node takes its place, so nothing unreachable is left to be saved
+        let node = self.nodes.swap_remove(id);
+        let moved = self.nodes.len();
+        if id != moved {
+            self.renumber(moved, id);
+        }
+
+        Some(Arc::unwrap_or_clone(node).snippet)
+    }
+
+    /// Starts a new branch where the snippet at `index` is replaced by `snippet`.
+    /// The old branch is kept and can be switched back to.
+    pub fn fork(&mut self, index: usize, snippet: ConversationSnippet) {
+        self.path.truncate(index);
+        self.push(snippet);
+    }
+
+    /// Which of its siblings the snippet at `index` is and how many there are,
+    /// if there is more than one
+    pub fn branch_info(&self, index: usize) -> Option<(usize, usize)> {
+        let id = *self.path.get(index)?;
+        let siblings = self.siblings(self.nodes[id].parent);
+
+        if siblings.len() < 2 {
+            return None;
+        }
+
+        siblings
+            .iter()
+            .position(|&sibling| sibling == id)
+            .map(|position| (position + 1, siblings.len()))
+    }
+
+    /// Index of the deepest snippet on the active branch that has siblings
+    pub fn last_branch_point(&self) -> Option<usize> {
+        (0..self.path.len())
+            .rev()
+            .find(|&index| self.branch_info(index).is_some())
+    }
+
+    /// Switches the snippet at `index` to its next (or previous) sibling and follows
+    /// that branch down to where it was last left. Returns false if there's nothing to switch to.
+    pub fn switch_branch(&mut self, index: usize, forward: bool) -> bool {
+        let Some(&id) = self.path.get(index) else {
+            return false;
+        };
+
+        let parent = self.nodes[id].parent;
+        let siblings = self.siblings(parent);
+
+        if siblings.len() < 2 {
+            return false;
+        }
+
+        let position = siblings
+            .iter()
+            .position(|&sibling| sibling == id)
+            .unwrap_or(0);
+        let next = if forward {
+            siblings[(position + 1) % siblings.len()]
+        } else {
+            siblings[(position + siblings.len() - 1) % siblings.len()]
+        };
+
+        self.path.truncate(index);
+        self.set_active_child(parent, Some(next));
+
+        let mut current = Some(next);
+        while let Some(id) = current {
+            self.path.push(id);
+            let node = &self.nodes[id];
+            current = node.active_child.or(node.children.last().copied());
+        }
+
+        true
+    }
+
+    /// A tree saved by `save`, like a session from `sessions/`
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let tree: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        if !tree.is_consistent() {
+            anyhow::bail!("{} isn't a conversation this can read", path);
+        }
+
+        Ok(tree)
+    }
+
+    /// Whether a tree from a file can be trusted to be walked. Ids out of range would panic
+    /// when indexed, and a loop of children would keep `switch_branch` going forever.
+    fn is_consistent(&self) -> bool {
+        let in_range = |id: &usize| *id < self.nodes.len();
+        let ids_in_range = self.path.iter().chain(&self.roots).all(in_range)
+            && self.nodes.iter().all(|node| {
+                node.children.iter().all(in_range)
+                    && node.parent.iter().all(in_range)
+                    && node.active_child.iter().all(in_range)
+            });
+
+        if !ids_in_range {
+            return false;
+        }
+
+        // Parents and children have to agree on each other
+        let links_agree = self
+            .roots
+            .iter()
+            .all(|&root| self.nodes[root].parent.is_none())
+            && self.nodes.iter().enumerate().all(|(id, node)| {
+                node.children
+                    .iter()
+                    .all(|&child| self.nodes[child].parent == Some(id))
+                    && node
+                        .active_child
+                        .is_none_or(|child| node.children.contains(&child))
+            });
+
+        // Going up from any node has to reach a root before it's been through every node
+        let no_cycles = (0..self.nodes.len()).all(|id| {
+            let mut current = Some(id);
+            for _ in 0..=self.nodes.len() {
+                match current {
+                    Some(id) => current = self.nodes[id].parent,
+                    None => return true,
+                }
+            }
+            false
+        });
+
+        // The active branch has to be a walk down from a root
+        let path_follows_links = self.path.first().is_none_or(|id| self.roots.contains(id))
+            && self
+                .path
+                .windows(2)
+                .all(|pair| self.nodes[pair[0]].children.contains(&pair[1]));
+
+        links_agree && no_cycles && path_follows_links
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        if let Some(directory) = Path::new(path).parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn siblings(&self, parent: Option<usize>) -> &Vec<usize> {
+        match parent {
+            Some(parent) => &self.nodes[parent].children,
+            None => &self.roots,
+        }
+    }
+
+    fn siblings_mut(&mut self, parent: Option<usize>) -> &mut Vec<usize> {
+        match parent {
+            Some(parent) => &mut Arc::make_mut(&mut self.nodes[parent]).children,
+            None => &mut self.roots,
+        }
+    }
+
+    /// Points everything that referred to the node with id `from` at `to`, where it's been moved
+    fn renumber(&mut self, from: usize, to: usize) {
+        let parent = self.nodes[to].parent;
+        for sibling in self.siblings_mut(parent) {
+            if *sibling == from {
+                *sibling = to;
+            }
+        }
+        if parent.is_some_and(|parent| self.nodes[parent].active_child == Some(from)) {
+            self.set_active_child(parent, Some(to));
+        }
+
+        for child in self.nodes[to].children.clone() {
+            Arc::make_mut(&mut self.nodes[child]).parent = Some(to);
+        }
+        for id in &mut self.path {
+            if *id == from {
+                *id = to;
+            }
+        }
+    }
+
+    fn set_active_child(&mut self, parent: Option<usize>, child: Option<usize>) {
+        if let Some(parent) = parent {
+            Arc::make_mut(&mut self.nodes[parent]).active_child = child;
+        }
+    }
+}
+
+impl Index<usize> for ConversationTree {
+    type Output = ConversationSnippet;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.nodes[self.path[index]].snippet
+    }
+}
+
+pub struct Iter<'a> {
+    tree: &'a ConversationTree,
+    ids: std::slice::Iter<'a, usize>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a ConversationSnippet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tree = self.tree;
+        self.ids.next().map(move |&id| &tree.nodes[id].snippet)
+    }
+}
+
+impl<'a> IntoIterator for &'a ConversationTree {
+    type Item = &'a ConversationSnippet;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LlmRole;
+    use crate::test_support::{snippet, temp_path};
+
+    #[test]
+    fn saved_sessions_load_with_every_branch() {
+        let mut tree = ConversationTree::default();
+        tree.push(snippet(LlmRole::User, "Hi"));
+        tree.push(snippet(LlmRole::Assistant, "Hello"));
+        tree.fork(0, snippet(LlmRole::User, "Hey"));
+
+        let path = temp_path("session", "json").to_string_lossy().into_owned();
+        tree.save(&path).unwrap();
+        let mut loaded = ConversationTree::load(&path).unwrap();
+
+        fs::write(&path, r#"{"nodes": [], "roots": [], "path": [3]}"#).unwrap();
+        let broken = ConversationTree::load(&path);
+
+        // Two nodes that are each other's active child
+        let node = |parent: usize, child: usize| {
+            serde_json::json!({
+                "snippet": snippet(LlmRole::User, "Hi"),
+                "parent": parent,
+                "children": [child],
+                "active_child": child,
+            })
+        };
+        let looped = serde_json::json!({
+            "nodes": [node(1, 1), node(0, 0)],
+            "roots": [],
+            "path": [],
+        });
+        fs::write(&path, looped.to_string()).unwrap();
+        let cycle = ConversationTree::load(&path);
+
+        // A child its parent doesn't know about
+        let mut disowned = serde_json::to_value(&tree).unwrap();
+        disowned["nodes"][1]["parent"] = serde_json::json!(2);
+        fs::write(&path, disowned.to_string()).unwrap();
+        let mismatched = ConversationTree::load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded, tree);
+        assert_eq!(loaded.branch_info(0), Some((2, 2)));
+        assert!(loaded.switch_branch(0, false));
+        assert_eq!(loaded[1].message, "Hello");
+        assert!(broken.is_err());
+        assert!(cycle.is_err());
+        assert!(mismatched.is_err());
+    }
+
+    #[test]
+    fn popped_snippets_leave_no_nodes_behind() {
+        let mut tree = ConversationTree::default();
+        tree.push(snippet(LlmRole::User, "Hi"));
+        tree.push(snippet(LlmRole::Assistant, "Hello"));
+        tree.fork(1, snippet(LlmRole::Assistant, "Hey"));
+        tree.push(snippet(LlmRole::User, "How are you?"));
+        assert!(tree.switch_branch(1, false));
+
+        // "Hello" isn't the newest node, so the newest is moved to fill its place
+        assert_eq!(tree.pop().unwrap().message, "Hello");
+        assert_eq!(tree.nodes.len(), 3);
+        assert!(tree.is_consistent());
+
+        tree.push(snippet(LlmRole::Assistant, "Howdy"));
+        assert!(tree.switch_branch(1, false));
+        assert_eq!(
+            tree.to_vec(),
+            [
+                snippet(LlmRole::User, "Hi"),
+                snippet(LlmRole::Assistant, "Hey"),
+                snippet(LlmRole::User, "How are you?"),
+            ]
+        );
+        assert_eq!(tree.pop().unwrap().message, "How are you?");
+        assert_eq!(tree.pop().unwrap().message, "Hey");
+        assert_eq!(tree.nodes.len(), 2);
+        assert!(tree.is_consistent());
+        let left: Vec<&str> = tree
+            .nodes
+            .iter()
+            .map(|node| &*node.snippet.message)
+            .collect();
+        assert_eq!(left, ["Hi", "Howdy"]);
+    }
+}
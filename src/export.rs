@@ -0,0 +1,287 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::{Local, TimeZone};
+use serde::Deserialize;
+
+use crate::state::{ConversationSnippet, LlmRole, State};
+use crate::tools::{text_before_tool_call, try_parse_tool_call};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Markdown,
+    Jsonl,
+    Text,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Exporter {
+    pub directory: String,
+    pub formats: Vec<ExportFormat>,
+    /// Include the system prompt and the exact templated text decoded by the llm
+    pub include_debug: bool,
+    pub assistant_name: String,
+    pub system_prompt: String,
+    /// Identifies this run, exports from the same session overwrite each other
+    pub session: String,
+}
+
+impl Exporter {
+    /// Writes the conversation in every configured format, returning the files written
+    pub fn export(&self, state: &State) -> anyhow::Result<Vec<String>> {
+        fs::create_dir_all(&self.directory)?;
+
+        let mut written = Vec::new();
+
+        for format in &self.formats {
+            let content = match format {
+                ExportFormat::Markdown => self.to_markdown(state),
+                ExportFormat::Jsonl => self.to_jsonl(state)?,
+                ExportFormat::Text => self.to_text(state),
+            };
+
+            let path = Path::new(&self.directory).join(format!(
+                "{}_{}.{}",
+                self.assistant_name.to_lowercase().replace(' ', "_"),
+                self.session,
+                format.extension()
+            ));
+
+            fs::write(&path, content)?;
+            written.push(path.to_string_lossy().into());
+        }
+
+        Ok(written)
+    }
+
+    fn speaker<'a>(&'a self, role: &LlmRole) -> &'a str {
+        match role {
+            LlmRole::User => "You",
+            LlmRole::Assistant => &self.assistant_name,
+            LlmRole::Tool => "Tool",
+        }
+    }
+
+    fn to_markdown(&self, state: &State) -> String {
+        let mut out = format!("# {} ({})\n\n", self.assistant_name, self.session);
+
+        if self.include_debug {
+            out.push_str(&format!(
+                "## System prompt\n\n```\n{}\n```\n\n",
+                self.system_prompt
+            ));
+        }
+
+        for snippet in &state.conversation {
+            out.push_str(&format!(
+                "**{}** _{}_\n\n",
+                self.speaker(&snippet.role),
+                format_time(snippet.timestamp)
+            ));
+
+            if snippet.is_tool_call || snippet.role == LlmRole::Tool {
+                out.push_str(&format!("```\n{}\n```\n\n", snippet.message.trim()));
+            } else {
+                out.push_str(&format!("{}\n\n", snippet.message.trim()));
+            }
+        }
+
+        if self.include_debug {
+            out.push_str("## Decoded text\n\n");
+            for chunk in &state.decoded_text {
+                out.push_str(&format!("```\n{}\n```\n\n", chunk));
+            }
+        }
+
+        out
+    }
+
+    /// One OpenAI style chat message per line, tool calls included
+    fn to_jsonl(&self, state: &State) -> anyhow::Result<String> {
+        let mut lines = Vec::new();
+        let mut pending_call_ids: Vec<String> = Vec::new();
+        let mut call_count = 0;
+
+        if self.include_debug {
+            lines.push(serde_json::json!({ "role": "system", "content": self.system_prompt }));
+        }
+
+        for ConversationSnippet {
+            role,
+            message,
+            is_tool_call,
+            ..
+        } in &state.conversation
+        {
+            match role {
+                LlmRole::User => {
+                    // Calls that never got a result don't get one from a later turn
+                    pending_call_ids.clear();
+                    lines.push(serde_json::json!({ "role": "user", "content": message }));
+                }
+                LlmRole::Assistant if *is_tool_call => {
+                    pending_call_ids.clear();
+                    let calls = try_parse_tool_call(message)
+                        .map(|(_format, calls)| calls)
+                        .unwrap_or_default();
+
+                    let tool_calls: Vec<serde_json::Value> = calls
+                        .into_iter()
+                        .map(|call| {
+                            call_count += 1;
+                            let id = format!("call_{}", call_count);
+                            pending_call_ids.push(id.clone());
+
+                            serde_json::json!({
+                                "id": id,
+                                "type": "function",
+                                "function": {
+                                    "name": call.name,
+                                    "arguments": serde_json::Value::Object(call.arguments).to_string(),
+                                }
+                            })
+                        })
+                        .collect();
+
+                    // Anything said before the calls, like "Let me check"
+                    let content = match text_before_tool_call(message) {
+                        "" => serde_json::Value::Null,
+                        text => text.into(),
+                    };
+
+                    lines.push(serde_json::json!({
+                        "role": "assistant",
+                        "content": content,
+                        "tool_calls": tool_calls,
+                    }));
+                }
+                LlmRole::Assistant => {
+                    lines.push(serde_json::json!({ "role": "assistant", "content": message }));
+                }
+                LlmRole::Tool => {
+                    let mut line = serde_json::json!({ "role": "tool", "content": message });
+
+                    // Results that answer no call, like hitting the tool limit, go without an id
+                    if !pending_call_ids.is_empty() {
+                        line["tool_call_id"] = pending_call_ids.remove(0).into();
+                    }
+
+                    lines.push(line);
+                }
+            }
+        }
+
+        let mut out = lines
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<String>, _>>()?
+            .join("\n");
+
+        if self.include_debug {
+            for chunk in &state.decoded_text {
+                out.push('\n');
+                out.push_str(&serde_json::to_string(
+                    &serde_json::json!({ "decoded_text": chunk }),
+                )?);
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn to_text(&self, state: &State) -> String {
+        let mut out = String::new();
+
+        if self.include_debug {
+            out.push_str(&format!("System prompt:\n{}\n---\n", self.system_prompt));
+        }
+
+        out.push_str(
+            &state
+                .conversation
+                .iter()
+                .map(|snippet| {
+                    format!(
+                        "[{}] {}: {}",
+                        format_time(snippet.timestamp),
+                        self.speaker(&snippet.role),
+                        snippet.message.trim()
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+
+        if self.include_debug {
+            out.push_str("\n---\nDecoded text:\n");
+            out.push_str(&state.decoded_text.join(""));
+        }
+
+        out
+    }
+}
+
+fn format_time(timestamp: u64) -> String {
+    Local
+        .timestamp(timestamp as i64, 0)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{snippet, tool_call};
+
+    #[test]
+    fn jsonl_keeps_every_call_and_what_came_before_them() {
+        let exporter = Exporter {
+            directory: String::new(),
+            formats: vec![ExportFormat::Jsonl],
+            include_debug: false,
+            assistant_name: "Jarvis".into(),
+            system_prompt: String::new(),
+            session: "test".into(),
+        };
+        let mut state = State::default();
+        for snippet in [
+            snippet(LlmRole::User, "Weather in Paris and Oslo?"),
+            tool_call(
+                r#"Let me check. <tool_call>{"name": "weather", "arguments": {"city": "Paris"}}</tool_call>
+<tool_call>{"name": "weather", "arguments": {"city": "Oslo"}}</tool_call>"#,
+            ),
+            snippet(LlmRole::Tool, "sunny"),
+            snippet(LlmRole::Tool, "snowing"),
+            snippet(LlmRole::Tool, "Tool limit reached"),
+        ] {
+            state.conversation.push(snippet);
+        }
+
+        let lines: Vec<serde_json::Value> = exporter
+            .to_jsonl(&state)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines[1]["content"], "Let me check.");
+        assert_eq!(lines[1]["tool_calls"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            lines[1]["tool_calls"][1]["function"]["arguments"],
+            r#"{"city":"Oslo"}"#
+        );
+        assert_eq!(lines[3]["tool_call_id"], "call_2");
+        assert_eq!(lines[4].get("tool_call_id"), None);
+    }
+}
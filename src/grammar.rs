@@ -0,0 +1,552 @@
+use crate::tools::{Tool, ToolFormat};
+
+const JSON_RULES: &str = r#"ws ::= [ \t\n]*
+string ::= "\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\""
+integer ::= "-"? [0-9]+
+number ::= "-"? [0-9]+ ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+boolean ::= "true" | "false"
+null ::= "null"
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ws ":" ws value ( ws "," ws string ws ":" ws value )* )? ws "}"
+array ::= "[" ws ( value ( ws "," ws value )* )? ws "]"
+"#;
+
+const PYTHON_RULES: &str = r#"py-string ::= "\"" ( [^"\\\n] | "\\" [^\n] )* "\""
+py-integer ::= "-"? [0-9]+
+py-number ::= "-"? [0-9]+ ( "." [0-9]+ )?
+py-boolean ::= "True" | "False"
+py-none ::= "None"
+py-list ::= "[" ws ( py-value ( ws "," ws py-value )* )? ws "]"
+py-dict ::= "{" ws ( py-string ws ":" ws py-value ( ws "," ws py-string ws ":" ws py-value )* )? ws "}"
+py-value ::= py-string | py-number | py-boolean | py-none | py-list | py-dict
+"#;
+
+const XML_RULES: &str = r#"xml-value ::= [^<]*
+"#;
+
+/// Works out which tool call format a chat template expects the model to use
+pub fn tool_call_format(chat_template: &str) -> ToolFormat {
+    if chat_template.contains("<function=") {
+        ToolFormat::ToolCallXmlFunction
+    } else if chat_template.contains("<tool_call>") {
+        ToolFormat::ToolCallXml
+    } else if chat_template.contains("<|tool_call_start|>") {
+        ToolFormat::ToolCallTags
+    } else if chat_template.contains("<|python_tag|>") {
+        ToolFormat::PythonCall
+    } else if chat_template.contains("functools[") {
+        ToolFormat::Functools
+    } else {
+        ToolFormat::JsonStandard
+    }
+}
+
+/// The format of a call that started with the marker for `marker_format`. `<tool_call>` starts
+/// both kinds of XML call, so the template decides between those.
+pub fn format_after_marker(marker_format: ToolFormat, template_format: ToolFormat) -> ToolFormat {
+    match (marker_format, template_format) {
+        (ToolFormat::ToolCallXml, ToolFormat::ToolCallXmlFunction) => {
+            ToolFormat::ToolCallXmlFunction
+        }
+        (marker_format, _) => marker_format,
+    }
+}
+
+/// Builds a GBNF grammar that only accepts valid calls to `tools` in `format`.
+/// It starts right after the marker `tool_call_marker` looks for, so it can be switched on
+/// as soon as a tool call begins. Plain JSON calls have no marker, so theirs starts with the
+/// opening brace and is held to from the start of the reply.
+pub fn tool_call_grammar(tools: &[Tool], format: &ToolFormat) -> Option<String> {
+    if tools.is_empty() {
+        return None;
+    }
+
+    let mut rules = Vec::new();
+
+    let root = match format {
+        ToolFormat::JsonStandard => {
+            add_json_calls(&mut rules, tools, "parameters");
+            "ws call"
+        }
+        ToolFormat::Functools => {
+            add_json_calls(&mut rules, tools, "arguments");
+            r#"ws call ( ws "," ws call )* ws "]""#
+        }
+        ToolFormat::ToolCallXml => {
+            add_json_calls(&mut rules, tools, "arguments");
+            r#"ws call ws "</tool_call>" ( ws "<tool_call>" ws call ws "</tool_call>" )*"#
+        }
+        ToolFormat::PythonCall => {
+            add_python_calls(&mut rules, tools, true);
+            "ws call"
+        }
+        ToolFormat::ToolCallTags => {
+            add_python_calls(&mut rules, tools, false);
+            r#"ws "[" ws call ( ws "," ws call )* ws "]" ws "<|tool_call_end|>""#
+        }
+        ToolFormat::ToolCallXmlFunction => {
+            add_xml_function_calls(&mut rules, tools);
+            r#"ws call ws "</tool_call>" ( ws "<tool_call>" ws call ws "</tool_call>" )*"#
+        }
+    };
+
+    let calls = (0..tools.len())
+        .map(|i| format!("call-{}", i))
+        .collect::<Vec<String>>()
+        .join(" | ");
+
+    let mut grammar = format!("root ::= {}\ncall ::= {}\n", root, calls);
+
+    for rule in rules {
+        grammar.push_str(&rule);
+        grammar.push('\n');
+    }
+
+    grammar.push_str(JSON_RULES);
+    grammar.push_str(PYTHON_RULES);
+    grammar.push_str(XML_RULES);
+
+    Some(grammar)
+}
+
+/// `{"name": "tool", "<arguments_key>": {...}}`
+fn add_json_calls(rules: &mut Vec<String>, tools: &[Tool], arguments_key: &str) {
+    for (i, tool) in tools.iter().enumerate() {
+        let members: Vec<(String, bool)> = ordered_properties(tool)
+            .into_iter()
+            .map(|(name, prop_type, required)| {
+                (
+                    format!(
+                        r#"{} ws ":" ws {}"#,
+                        literal(&format!("\"{}\"", name)),
+                        json_value_rule(&prop_type)
+                    ),
+                    required,
+                )
+            })
+            .collect();
+
+        rules.push(format!(
+            r#"call-{} ::= "{{" ws "\"name\"" ws ":" ws {} ws "," ws {} ws ":" ws args-{} ws "}}""#,
+            i,
+            literal(&format!("\"{}\"", tool.name)),
+            literal(&format!("\"{}\"", arguments_key)),
+            i
+        ));
+        rules.push(format!(
+            r#"args-{} ::= "{{" ws {} ws "}}""#,
+            i,
+            sequence(&members, r#"ws "," ws"#)
+        ));
+    }
+}
+
+/// `tool(arg="value", other=1)`, optionally as `tool.call(...)`
+fn add_python_calls(rules: &mut Vec<String>, tools: &[Tool], dot_call: bool) {
+    for (i, tool) in tools.iter().enumerate() {
+        let members: Vec<(String, bool)> = ordered_properties(tool)
+            .into_iter()
+            .map(|(name, prop_type, required)| {
+                (
+                    format!(
+                        r#"{} ws {}"#,
+                        literal(&format!("{}=", name)),
+                        python_value_rule(&prop_type)
+                    ),
+                    required,
+                )
+            })
+            .collect();
+
+        rules.push(format!(
+            r#"call-{} ::= {}{} "(" ws {} ws ")""#,
+            i,
+            literal(&tool.name),
+            if dot_call { r#" ".call"?"# } else { "" },
+            sequence(&members, r#"ws "," ws"#)
+        ));
+    }
+}
+
+/// `<function=tool>\n<parameter=arg>\nvalue\n</parameter>\n</function>`
+fn add_xml_function_calls(rules: &mut Vec<String>, tools: &[Tool]) {
+    for (i, tool) in tools.iter().enumerate() {
+        let members: Vec<(String, bool)> = ordered_properties(tool)
+            .into_iter()
+            .map(|(name, _prop_type, required)| {
+                (
+                    format!(
+                        r#"{} ws xml-value ws "</parameter>""#,
+                        literal(&format!("<parameter={}>", name))
+                    ),
+                    required,
+                )
+            })
+            .collect();
+
+        rules.push(format!(
+            r#"call-{} ::= {} ws {} ws "</function>""#,
+            i,
+            literal(&format!("<function={}>", tool.name)),
+            sequence(&members, "ws")
+        ));
+    }
+}
+
+/// Required properties first, then optional ones, each sorted by name so the grammar is stable
+fn ordered_properties(tool: &Tool) -> Vec<(String, String, bool)> {
+    let mut properties: Vec<(String, String, bool)> = tool
+        .properties
+        .iter()
+        .map(|(name, (prop_type, _description))| {
+            (
+                name.clone(),
+                prop_type.clone(),
+                tool.required.contains(name),
+            )
+        })
+        .collect();
+
+    properties.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    properties
+}
+
+/// Joins members with `separator` so required ones must appear and optional ones may be left out.
+/// Expects required members to come first.
+fn sequence(members: &[(String, bool)], separator: &str) -> String {
+    if members.is_empty() {
+        return String::new();
+    }
+
+    let optional_tail = |from: usize| -> String {
+        members[from..]
+            .iter()
+            .map(|(member, _)| format!(" ( {} {} )?", separator, member))
+            .collect()
+    };
+
+    let required: Vec<&String> = members
+        .iter()
+        .filter(|(_, required)| *required)
+        .map(|(member, _)| member)
+        .collect();
+
+    if !required.is_empty() {
+        let joined = required
+            .iter()
+            .map(|member| member.as_str())
+            .collect::<Vec<&str>>()
+            .join(&format!(" {} ", separator));
+
+        return format!("( {}{} )", joined, optional_tail(required.len()));
+    }
+
+    // Everything is optional, so any member can be the first one written
+    let alternatives = (0..members.len())
+        .map(|first| format!("{}{}", members[first].0, optional_tail(first + 1)))
+        .collect::<Vec<String>>()
+        .join(" | ");
+
+    format!("( {} )?", alternatives)
+}
+
+fn json_value_rule(prop_type: &str) -> &'static str {
+    match base_type(prop_type) {
+        "str" | "string" => "string",
+        "int" | "integer" => "integer",
+        "float" | "number" => "number",
+        "bool" | "boolean" => "boolean",
+        "list" | "array" => "array",
+        "dict" | "object" => "object",
+        "None" | "null" => "null",
+        _ => "value",
+    }
+}
+
+fn python_value_rule(prop_type: &str) -> &'static str {
+    match base_type(prop_type) {
+        "str" | "string" => "py-string",
+        "int" | "integer" => "py-integer",
+        "float" | "number" => "py-number",
+        "bool" | "boolean" => "py-boolean",
+        "list" | "array" => "py-list",
+        "dict" | "object" => "py-dict",
+        "None" | "null" => "py-none",
+        _ => "py-value",
+    }
+}
+
+/// `list[str]` is still a list as far as the grammar cares
+fn base_type(prop_type: &str) -> &str {
+    prop_type.split('[').next().unwrap_or(prop_type).trim()
+}
+
+/// Quotes text as a GBNF string literal
+fn literal(text: &str) -> String {
+    let mut out = String::from("\"");
+
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use llama_cpp_2::{
+        llama_backend::LlamaBackend,
+        model::{AddBos, LlamaModel, params::LlamaModelParams},
+        sampling::LlamaSampler,
+    };
+    use regex::Regex;
+
+    use super::*;
+
+    fn sample_tools() -> Vec<Tool> {
+        vec![
+            Tool {
+                name: "get_weather".into(),
+                description: "Get current weather for a location".into(),
+                properties: HashMap::from([
+                    ("city".into(), ("str".into(), String::new())),
+                    ("days".into(), ("int".into(), String::new())),
+                ]),
+                required: vec!["city".into()],
+            },
+            Tool {
+                name: "get_current_time".into(),
+                description: "Get current time".into(),
+                properties: HashMap::new(),
+                required: vec![],
+            },
+            Tool {
+                name: "set_volume".into(),
+                description: "Set the volume".into(),
+                properties: HashMap::from([
+                    ("level".into(), ("float".into(), String::new())),
+                    ("muted".into(), ("bool".into(), String::new())),
+                ]),
+                required: vec![],
+            },
+        ]
+    }
+
+    /// Every rule referenced on the right hand side has to be defined somewhere
+    fn assert_rules_defined(grammar: &str) {
+        let literals = Regex::new(r#""(\\.|[^"\\])*"|\[(\\.|[^\]\\])*\]"#).unwrap();
+        let identifiers = Regex::new(r"[a-zA-Z][a-zA-Z0-9-]*").unwrap();
+
+        let mut defined = HashSet::new();
+        let mut bodies = Vec::new();
+
+        for line in grammar.lines().filter(|line| !line.trim().is_empty()) {
+            let (name, body) = line.split_once("::=").expect("every line is a rule");
+            defined.insert(name.trim().to_string());
+            bodies.push(literals.replace_all(body, "").to_string());
+        }
+
+        for body in bodies {
+            for identifier in identifiers.find_iter(&body) {
+                assert!(
+                    defined.contains(identifier.as_str()),
+                    "rule {} is used but never defined",
+                    identifier.as_str()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn detects_format_from_template() {
+        let cases = [
+            (
+                "{{- '<tool_call>\\n<function=' + tool_call.name + '>\\n' }}",
+                ToolFormat::ToolCallXmlFunction,
+            ),
+            (
+                "{{- '<tool_call>\\n{\"name\": \"' }}",
+                ToolFormat::ToolCallXml,
+            ),
+            (
+                "{{- '<|tool_call_start|>[' + calls + ']<|tool_call_end|>' }}",
+                ToolFormat::ToolCallTags,
+            ),
+            (
+                "{{- '<|python_tag|>' + tool_call.name + '.call(' }}",
+                ToolFormat::PythonCall,
+            ),
+            ("{{- 'functools[' + calls + ']' }}", ToolFormat::Functools),
+            ("{% if tools is not none %}", ToolFormat::JsonStandard),
+        ];
+
+        for (template, expected) in cases {
+            assert_eq!(tool_call_format(template), expected, "{}", template);
+        }
+    }
+
+    #[test]
+    fn xml_markers_follow_the_template() {
+        assert_eq!(
+            format_after_marker(ToolFormat::ToolCallXml, ToolFormat::ToolCallXmlFunction),
+            ToolFormat::ToolCallXmlFunction
+        );
+        assert_eq!(
+            format_after_marker(ToolFormat::ToolCallXml, ToolFormat::JsonStandard),
+            ToolFormat::ToolCallXml
+        );
+        assert_eq!(
+            format_after_marker(ToolFormat::PythonCall, ToolFormat::ToolCallXml),
+            ToolFormat::PythonCall
+        );
+    }
+
+    #[test]
+    fn no_grammar_without_tools() {
+        assert!(tool_call_grammar(&[], &ToolFormat::ToolCallXml).is_none());
+    }
+
+    #[test]
+    fn builds_grammar_for_every_format() {
+        let tools = sample_tools();
+
+        for format in [
+            ToolFormat::JsonStandard,
+            ToolFormat::PythonCall,
+            ToolFormat::Functools,
+            ToolFormat::ToolCallTags,
+            ToolFormat::ToolCallXml,
+            ToolFormat::ToolCallXmlFunction,
+        ] {
+            let grammar = tool_call_grammar(&tools, &format).unwrap();
+
+            assert!(grammar.starts_with("root ::= "), "{:?}", format);
+            assert!(grammar.contains("call ::= call-0 | call-1 | call-2"));
+            assert!(grammar.contains("get_weather"), "{:?}", format);
+            assert!(grammar.contains("city"), "{:?}", format);
+            assert_rules_defined(&grammar);
+        }
+    }
+
+    #[test]
+    fn json_calls_use_the_right_arguments_key() {
+        let tools = sample_tools();
+
+        let standard = tool_call_grammar(&tools, &ToolFormat::JsonStandard).unwrap();
+        assert!(standard.contains(r#""\"parameters\"""#));
+
+        let xml = tool_call_grammar(&tools, &ToolFormat::ToolCallXml).unwrap();
+        assert!(xml.contains(r#""\"arguments\"""#));
+        assert!(xml.contains(r#""</tool_call>""#));
+    }
+
+    #[test]
+    fn argument_types_map_to_value_rules() {
+        let tools = sample_tools();
+
+        let json = tool_call_grammar(&tools, &ToolFormat::ToolCallXml).unwrap();
+        assert!(json.contains(r#""\"city\"" ws ":" ws string"#));
+        assert!(json.contains(r#""\"days\"" ws ":" ws integer"#));
+
+        let python = tool_call_grammar(&tools, &ToolFormat::ToolCallTags).unwrap();
+        assert!(python.contains(r#""city=" ws py-string"#));
+        assert!(python.contains(r#""muted=" ws py-boolean"#));
+        assert!(python.contains(r#""<|tool_call_end|>""#));
+
+        let pytag = tool_call_grammar(&tools, &ToolFormat::PythonCall).unwrap();
+        assert!(pytag.contains(r#""get_weather" ".call"?"#));
+    }
+
+    #[test]
+    fn required_arguments_are_not_optional() {
+        let members = vec![("a".to_string(), true), ("b".to_string(), false)];
+        assert_eq!(sequence(&members, "sep"), "( a ( sep b )? )");
+    }
+
+    #[test]
+    fn all_optional_arguments_can_start_anywhere() {
+        let members = vec![("a".to_string(), false), ("b".to_string(), false)];
+        assert_eq!(sequence(&members, "sep"), "( a ( sep b )? | b )?");
+    }
+
+    #[test]
+    fn literals_are_escaped() {
+        assert_eq!(literal("\"name\""), r#""\"name\"""#);
+        assert_eq!(literal("a\\b\n"), r#""a\\b\n""#);
+    }
+
+    /// Only the vocabulary of the model is loaded, any gguf will do
+    #[test]
+    #[ignore = "needs a gguf model, set LOCAL_ML_TEST_MODEL to its path"]
+    fn llama_compiles_and_follows_every_grammar() {
+        let path = std::env::var("LOCAL_ML_TEST_MODEL").expect("LOCAL_ML_TEST_MODEL isn't set");
+        let backend = LlamaBackend::init().unwrap();
+        let model = LlamaModel::load_from_file(
+            &backend,
+            path,
+            &LlamaModelParams::default().with_vocab_only(true),
+        )
+        .unwrap();
+        let tools = sample_tools();
+
+        // Each one as it's written after its marker, or from the start for plain JSON
+        let calls = [
+            (
+                ToolFormat::JsonStandard,
+                r#"{"name": "get_weather", "parameters": {"city": "Paris", "days": 2}}"#,
+            ),
+            (
+                ToolFormat::PythonCall,
+                r#"get_weather.call(city="a) first, b) second")"#,
+            ),
+            (
+                ToolFormat::Functools,
+                r#"{"name": "get_current_time", "arguments": {}}]"#,
+            ),
+            (
+                ToolFormat::ToolCallTags,
+                r#"[get_weather(city="Paris", days=3), set_volume(level=0.5, muted=False)]<|tool_call_end|>"#,
+            ),
+            (
+                ToolFormat::ToolCallXml,
+                "\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+            ),
+            (
+                ToolFormat::ToolCallXmlFunction,
+                "\n<function=get_weather>\n<parameter=city>\nParis\n</parameter>\n</function>\n</tool_call>",
+            ),
+        ];
+
+        for (format, call) in calls {
+            let grammar = tool_call_grammar(&tools, &format).unwrap();
+            let accepts = |text: &str| {
+                let mut sampler =
+                    LlamaSampler::grammar(&model, &grammar, "root").unwrap_or_else(|e| {
+                        panic!("{:?} doesn't compile: {:?}\n{}", format, e, grammar)
+                    });
+
+                model
+                    .str_to_token(text, AddBos::Never)
+                    .unwrap()
+                    .into_iter()
+                    .all(|token| sampler.try_accept(token).is_ok())
+            };
+
+            assert!(accepts(call), "{:?} refused {}", format, call);
+            // A tool that doesn't exist
+            assert!(
+                !accepts(&call.replace("get_", "fetch_")),
+                "{:?} took a made up tool",
+                format
+            );
+        }
+    }
+}
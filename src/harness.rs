@@ -0,0 +1,842 @@
+//! Runs the pipeline end to end without models, a mic or speakers. Speech comes from scripted
+//! transcripts, the llm answers with scripted replies and the speaker only writes down what it
+//! was asked to say, so every run goes exactly the same way.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ringbuf::{
+    HeapRb,
+    traits::{Observer, Producer, Split},
+};
+
+use crate::builtins::Builtins;
+use crate::config::{
+    BuiltinTool, McpServer, SamplingConfig, ToolLimits, ToolPermission, ToolSandbox,
+};
+use crate::llm::{self, Llm};
+use crate::pipeline;
+use crate::reply::{self, ReplyOptions};
+use crate::scheduler;
+use crate::shutdown;
+use crate::state::{
+    Event, LifeCycleState, LlmCommand, LlmRole, LlmState, State, StateHandle, Subscription,
+};
+use crate::stt::Transcriber;
+use crate::test_support::{ScriptedTokens, temp_path};
+use crate::tools::{self, ToolFormat, Tools};
+use crate::tts::{self, Speaker};
+use crate::vad;
+
+const ASSISTANT_NAME: &str = "Jarvis";
+const TIMEOUT: Duration = Duration::from_secs(5);
+const TOOLS_FIXTURE: &str = "tests/fixtures/tools.py";
+const MCP_SERVER_FIXTURE: &str = "tests/fixtures/mcp_server.py";
+
+/// Hears the same thing whatever the audio was
+struct Transcript(String);
+
+impl Transcriber for Transcript {
+    fn transcribe(&self, _audio: &[f32]) -> anyhow::Result<String> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Hears the same thing in every utterance, noting how long each one was
+struct RecordingTranscriber {
+    text: String,
+    heard: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Transcriber for RecordingTranscriber {
+    fn transcribe(&self, audio: &[f32]) -> anyhow::Result<String> {
+        self.heard.lock().unwrap().push(audio.len());
+        Ok(self.text.clone())
+    }
+}
+
+#[derive(Default)]
+struct RecordingSpeaker {
+    synthesized: Option<String>,
+    spoken: Arc<Mutex<Vec<String>>>,
+}
+
+impl Speaker for RecordingSpeaker {
+    fn synthesize(&mut self, text: &str) -> anyhow::Result<()> {
+        self.synthesized = Some(text.into());
+        Ok(())
+    }
+
+    fn play(&mut self) -> anyhow::Result<()> {
+        if let Some(text) = self.synthesized.take() {
+            self.spoken.lock().unwrap().push(text);
+        }
+        Ok(())
+    }
+}
+
+/// Answers every message with the next reply in the script, going through the same turn as
+/// the real llm, tool calls and all
+struct ScriptedLlm {
+    /// Taken out before the thread starts, so a message sent straight away isn't missed
+    events: Subscription,
+    tokens: ScriptedTokens,
+    tools: Option<Tools>,
+    tool_limits: ToolLimits,
+}
+
+impl Llm for ScriptedLlm {
+    fn run(mut self, state: StateHandle) -> anyhow::Result<()> {
+        let sampling = SamplingConfig::default();
+
+        while self.events.wait() {
+            let Some(LlmCommand::ContinueConversation(_)) =
+                state.read_with(|s| s.llm_command.clone())
+            else {
+                continue;
+            };
+
+            self.tokens.next_reply()?;
+            state.update(|s| {
+                s.llm_command = None;
+                s.llm_state = LlmState::RunningInference;
+            });
+
+            let reply = reply::generate_reply(
+                &state,
+                &mut self.tokens,
+                &ReplyOptions {
+                    tools: self.tools.as_ref(),
+                    tool_limits: &self.tool_limits,
+                    sampling: &sampling,
+                    template_format: ToolFormat::JsonStandard,
+                    enable_word_by_word_response: false,
+                    record_decoded_text: false,
+                },
+            )?;
+
+            state.update(|s| {
+                if reply.interrupted {
+                    while s
+                        .conversation
+                        .pop()
+                        .is_some_and(|snippet| snippet.role != LlmRole::User)
+                    {}
+                    s.llm_state = LlmState::AwaitingInput;
+                    s.turn_timings = None;
+                    return;
+                }
+
+                if let Some(timings) = s.turn_timings.as_mut() {
+                    timings.first_token = reply.first_token_at;
+                    timings.generated_tokens = reply.tokens;
+                    timings.generation_done = Some(Instant::now());
+                }
+
+                if s.life_cycle_state == LifeCycleState::ShuttingDown {
+                    s.llm_state = LlmState::AwaitingInput;
+                } else {
+                    s.llm_state = LlmState::InitializingTts;
+                    s.tts_commands.push(reply.text);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+struct Harness {
+    state: StateHandle,
+    spoken: Arc<Mutex<Vec<String>>>,
+    /// Everything published apart from plain state changes, in order
+    events: Arc<Mutex<Vec<Event>>>,
+    conversation_file: String,
+    timers_file: String,
+}
+
+impl Harness {
+    fn new(replies: &[&str], tools: Option<Tools>) -> Self {
+        Self::with_tools(replies, |_| tools)
+    }
+
+    /// For tools that need the harness's state, like the built-in ones
+    fn with_tools(replies: &[&str], tools: impl FnOnce(&StateHandle) -> Option<Tools>) -> Self {
+        Self::start(replies, tools, ToolLimits::default())
+    }
+
+    /// For turns that should run out of time sooner than usual
+    fn with_tool_limits(replies: &[&str], tools: Option<Tools>, tool_limits: ToolLimits) -> Self {
+        Self::start(replies, |_| tools, tool_limits)
+    }
+
+    fn start(
+        replies: &[&str],
+        tools: impl FnOnce(&StateHandle) -> Option<Tools>,
+        tool_limits: ToolLimits,
+    ) -> Self {
+        let state = StateHandle::new();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscription = state.subscribe();
+        let recorded = events.clone();
+        thread::spawn(move || {
+            while let Some(event) = subscription.recv() {
+                if event != Event::StateChanged {
+                    recorded.lock().unwrap().push(event);
+                }
+            }
+        });
+
+        llm::spawn_llm_thread(
+            state.clone(),
+            ScriptedLlm {
+                events: state.subscribe(),
+                tokens: ScriptedTokens::new(replies),
+                tools: tools(&state),
+                tool_limits,
+            },
+        );
+
+        let speaker = RecordingSpeaker::default();
+        let spoken = speaker.spoken.clone();
+        tts::spawn_tts_thread(state.clone(), speaker);
+
+        let timers_file = temp_path("harness", "json").to_string_lossy().into_owned();
+        scheduler::spawn_scheduler_thread(state.clone(), timers_file.clone());
+
+        state.update(|s| s.life_cycle_state = LifeCycleState::Running);
+
+        Harness {
+            state,
+            spoken,
+            events,
+            conversation_file: temp_path("harness", "txt").to_string_lossy().into_owned(),
+            timers_file,
+        }
+    }
+
+    /// As if the VAD had just picked up someone saying `text`
+    fn say(&self, text: &str) {
+        pipeline::handle_utterance(
+            &self.state,
+            &Transcript(text.into()),
+            ASSISTANT_NAME,
+            &self.conversation_file,
+            &[0.0; 16_000],
+        );
+    }
+
+    /// Runs the VAD over `audio` as if it came from the mic, sending anything it hears through
+    /// the pipeline as `transcript`. Returns how many samples long each utterance was.
+    fn listen(&self, audio: &[f32], transcript: &str) -> Vec<usize> {
+        let (mut producer, consumer) = HeapRb::<f32>::new(audio.len().max(1)).split();
+        producer.push_slice(audio);
+
+        // Its own state, so the VAD can be stopped without shutting everything else down
+        let mic = StateHandle::new();
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let transcriber = RecordingTranscriber {
+            text: transcript.into(),
+            heard: heard.clone(),
+        };
+        let state = self.state.clone();
+        let conversation_file = self.conversation_file.clone();
+
+        let vad = thread::spawn({
+            let mic = mic.clone();
+            move || {
+                vad::run_vad(mic, consumer, 16_000, |utterance| {
+                    pipeline::handle_utterance(
+                        &state,
+                        &transcriber,
+                        ASSISTANT_NAME,
+                        &conversation_file,
+                        &utterance,
+                    );
+                });
+            }
+        });
+
+        // Less than a frame is never taken, and the last one is dealt with before the VAD
+        // checks whether to stop
+        self.wait_until("the VAD has been through the audio", |_| {
+            producer.occupied_len() < 480
+        });
+        mic.update(|s| s.life_cycle_state = LifeCycleState::ShuttingDown);
+        vad.join().unwrap();
+
+        heard.lock().unwrap().clone()
+    }
+
+    fn wait_until(&self, what: &str, condition: impl Fn(&Self) -> bool) {
+        let started = Instant::now();
+
+        while !condition(self) {
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "timed out waiting until {}, state: {:#?}",
+                what,
+                self.state.read()
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Waits for the reply to be generated and spoken
+    fn wait_for_turn(&self) {
+        self.wait_until("the turn is over", |harness| {
+            // A call waiting on a yes or no leaves the llm awaiting input too
+            harness.state.read_with(|s| {
+                s.llm_command.is_none()
+                    && s.llm_state == LlmState::AwaitingInput
+                    && s.tts_commands.is_empty()
+                    && s.pending_tool_call.is_none()
+            }) && harness.events().last() == Some(&Event::TtsFinished)
+        });
+    }
+
+    fn read(&self) -> State {
+        self.state.read()
+    }
+
+    fn spoken(&self) -> Vec<String> {
+        self.spoken.lock().unwrap().clone()
+    }
+
+    fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn conversation(&self) -> Vec<(LlmRole, String)> {
+        self.read()
+            .conversation
+            .iter()
+            .map(|snippet| (snippet.role.clone(), snippet.message.clone()))
+            .collect()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.state
+            .update(|s| s.life_cycle_state = LifeCycleState::ShuttingDown);
+        let _ = fs::remove_file(&self.conversation_file);
+        let _ = fs::remove_file(&self.timers_file);
+    }
+}
+
+#[test]
+fn speech_is_answered_out_loud() {
+    let harness = Harness::new(&["It's sunny today."], None);
+
+    harness.say("What's the weather like?");
+    harness.wait_for_turn();
+
+    assert_eq!(
+        harness.conversation(),
+        vec![
+            (LlmRole::User, "What's the weather like?".into()),
+            (LlmRole::Assistant, "It's sunny today.".into()),
+        ]
+    );
+    assert_eq!(harness.spoken(), vec!["It's sunny today."]);
+    assert_eq!(
+        harness.events(),
+        vec![
+            Event::UserUtterance,
+            Event::Token("It's ".into()),
+            Event::Token("sunny ".into()),
+            Event::Token("today.".into()),
+            Event::TtsStarted,
+            Event::TtsFinished,
+        ]
+    );
+
+    harness.wait_until("the turn's metrics are in", |harness| {
+        harness.read().last_turn_metrics.is_some()
+    });
+    let state = harness.read();
+    assert!(!state.system_mute);
+    assert!(
+        state
+            .last_turn_metrics
+            .unwrap()
+            .time_to_first_audio_ms
+            .is_some()
+    );
+}
+
+#[test]
+fn blank_transcripts_are_ignored() {
+    let harness = Harness::new(&[], None);
+
+    harness.say("  ");
+    harness.say("[BLANK_AUDIO]");
+
+    let state = harness.read();
+    assert!(state.conversation.is_empty());
+    assert_eq!(state.llm_command, None);
+}
+
+#[test]
+fn only_answers_after_hearing_its_name() {
+    let harness = Harness::new(&["Jarvis at your service."], None);
+    harness
+        .state
+        .update(|s| s.is_only_responding_after_name = true);
+
+    harness.say("What's on TV tonight?");
+    assert!(harness.read().conversation.is_empty());
+
+    harness.say("Hey Jarvis");
+    harness.wait_for_turn();
+    assert_eq!(harness.spoken(), vec!["Yes?"]);
+
+    harness.say("Who are you?");
+    harness.wait_for_turn();
+
+    assert_eq!(
+        harness.conversation(),
+        vec![
+            (LlmRole::User, "Who are you?".into()),
+            (LlmRole::Assistant, "Jarvis at your service.".into()),
+        ]
+    );
+    assert_eq!(harness.spoken(), vec!["Yes?", "Jarvis at your service."]);
+}
+
+#[test]
+fn tool_calls_are_run_and_answered() {
+    let harness = Harness::new(
+        &[
+            r#"{"name": "shout", "parameters": {"text": "hello"}}"#,
+            "It said HELLO.",
+        ],
+        Some(tools::parse_python_functions(TOOLS_FIXTURE.into())),
+    );
+
+    harness.say("Shout hello for me");
+    harness.wait_for_turn();
+
+    let state = harness.read();
+    let tool_call = &state.conversation[1];
+    assert_eq!(tool_call.role, LlmRole::Assistant);
+    assert!(tool_call.is_tool_call);
+
+    assert_eq!(state.conversation[2].role, LlmRole::Tool);
+    assert_eq!(state.conversation[2].message.trim(), "HELLO");
+    let result = state.conversation[2].tool_result.as_ref().unwrap();
+    assert_eq!(result.exit_code, Some(0));
+    assert_eq!(result.value, Some("HELLO".into()));
+    assert_eq!(state.conversation[3].message, "It said HELLO.");
+    assert_eq!(harness.spoken(), vec!["It said HELLO."]);
+}
+
+#[test]
+fn mcp_tool_calls_go_to_the_server() {
+    let server = McpServer {
+        name: "fixture".into(),
+        command: vec!["python".into(), MCP_SERVER_FIXTURE.into()],
+        env: HashMap::from([("FIXTURE_GREETING".into(), "hi".into())]),
+    };
+    let harness = Harness::new(
+        &[
+            r#"{"name": "add", "parameters": {"a": 2, "b": 3}}"#,
+            "That's 5.",
+        ],
+        tools::load_tools(None, &[], &[server], None, &ToolSandbox::default()),
+    );
+
+    harness.say("What's two plus three?");
+    harness.wait_for_turn();
+
+    let state = harness.read();
+    assert_eq!(state.conversation[2].role, LlmRole::Tool);
+    assert_eq!(state.conversation[2].message, "5");
+    assert_eq!(harness.spoken(), vec!["That's 5."]);
+}
+
+fn builtin_tools(state: &StateHandle, enabled: &[BuiltinTool]) -> Option<Tools> {
+    tools::load_tools(
+        None,
+        &[],
+        &[],
+        Builtins::new(enabled, "notes", state.clone()),
+        &ToolSandbox::default(),
+    )
+}
+
+#[test]
+fn timers_are_said_when_they_go_off() {
+    let harness = Harness::with_tools(
+        &[
+            r#"{"name": "set_timer", "parameters": {"seconds": 1, "label": "tea"}}"#,
+            "Your timer's set.",
+        ],
+        |state| builtin_tools(state, &[BuiltinTool::Timers]),
+    );
+
+    harness.say("I'm making tea, give me a shout in a second");
+    harness.wait_for_turn();
+    assert_eq!(harness.read().timers.len(), 1);
+
+    harness.wait_until("the timer goes off", |harness| harness.spoken().len() == 2);
+    harness.wait_for_turn();
+
+    assert_eq!(
+        harness.spoken(),
+        vec!["Your timer's set.", "Your tea timer is done."]
+    );
+    let state = harness.read();
+    assert!(state.timers.is_empty());
+    assert!(!state.system_mute);
+    assert_eq!(
+        harness.conversation().last(),
+        Some(&(LlmRole::Assistant, "Your tea timer is done.".into()))
+    );
+}
+
+#[test]
+fn reminders_can_be_set_by_voice() {
+    let harness = Harness::new(&[], None);
+
+    harness.say("Remind me in 2 seconds to stretch.");
+    harness.wait_until("it answers", |harness| !harness.spoken().is_empty());
+    assert!(harness.spoken()[0].starts_with("Okay, I'll remind you in"));
+    assert_eq!(harness.read().timers[0].label, "stretch");
+    harness.wait_until("the reminder is saved", |harness| {
+        fs::read_to_string(&harness.timers_file).is_ok_and(|saved| saved.contains("stretch"))
+    });
+
+    harness.wait_until("the reminder goes off", |harness| {
+        harness.spoken().len() == 2
+    });
+    assert_eq!(harness.spoken()[1], "Reminder: stretch.");
+    assert_eq!(
+        harness.conversation(),
+        vec![(LlmRole::Assistant, "Reminder: stretch.".into())]
+    );
+}
+
+#[test]
+fn reminders_are_only_shown_while_muted() {
+    let harness = Harness::new(&[], None);
+    harness.state.update(|s| s.user_mute = true);
+
+    harness.say("Remind me in 1 second to stretch.");
+    harness.wait_until("it answers", |harness| !harness.spoken().is_empty());
+    harness.wait_until("the reminder goes off", |harness| {
+        !harness.read().conversation.is_empty()
+    });
+
+    assert_eq!(harness.spoken().len(), 1);
+    let state = harness.read();
+    assert!(state.timers.is_empty());
+    assert!(state.tts_commands.is_empty());
+}
+
+#[test]
+fn ending_the_conversation_waits_for_the_name() {
+    let harness = Harness::with_tools(
+        &[
+            r#"{"name": "end_conversation", "parameters": {}}"#,
+            "Bye for now.",
+            "Hello again.",
+        ],
+        |state| builtin_tools(state, &[BuiltinTool::EndConversation]),
+    );
+
+    harness.say("That's all, thanks");
+    harness.wait_for_turn();
+    assert!(harness.read().is_waiting_for_name);
+
+    harness.say("What's on TV tonight?");
+    assert_eq!(harness.read().conversation.len(), 4);
+
+    harness.say("Jarvis, are you there?");
+    harness.wait_for_turn();
+
+    assert!(!harness.read().is_waiting_for_name);
+    assert_eq!(
+        harness.conversation().last(),
+        Some(&(LlmRole::Assistant, "Hello again.".into()))
+    );
+}
+
+fn tools_where_shout_is(permission: ToolPermission) -> Tools {
+    let mut tools = tools::parse_python_functions(TOOLS_FIXTURE.into());
+    tools.permissions.tools.insert("shout".into(), permission);
+    tools
+}
+
+#[test]
+fn confirmed_tool_calls_wait_for_a_yes() {
+    let harness = Harness::new(
+        &[
+            r#"{"name": "shout", "parameters": {"text": "hello"}}"#,
+            "It said HELLO.",
+        ],
+        Some(tools_where_shout_is(ToolPermission::Confirm)),
+    );
+
+    harness.say("Shout hello for me");
+    harness.wait_until("it asks", |harness| !harness.spoken().is_empty());
+    assert_eq!(
+        harness.spoken(),
+        vec!["Should I run shout with text hello?"]
+    );
+    assert!(harness.read().pending_tool_call.is_some());
+
+    harness.say("Hmm, what?");
+    harness.wait_until("it asks again", |harness| harness.spoken().len() == 2);
+
+    harness.say("Yes please");
+    harness.wait_for_turn();
+
+    let state = harness.read();
+    assert_eq!(state.pending_tool_call, None);
+    assert_eq!(state.conversation[2].message.trim(), "HELLO");
+    assert_eq!(
+        harness.spoken(),
+        vec![
+            "Should I run shout with text hello?",
+            "Sorry, was that a yes or a no?",
+            "It said HELLO.",
+        ]
+    );
+}
+
+#[test]
+fn waiting_for_a_yes_doesnt_use_up_the_turn() {
+    let harness = Harness::with_tool_limits(
+        &[
+            r#"{"name": "shout", "parameters": {"text": "hello"}}"#,
+            "It said HELLO.",
+        ],
+        Some(tools_where_shout_is(ToolPermission::Confirm)),
+        ToolLimits {
+            turn_timeout_secs: 1,
+            ..ToolLimits::default()
+        },
+    );
+
+    harness.say("Shout hello for me");
+    harness.wait_until("it asks", |harness| !harness.spoken().is_empty());
+    // Well past when the turn would have run out
+    thread::sleep(Duration::from_millis(1500));
+    harness.say("Yes");
+    harness.wait_for_turn();
+
+    let state = harness.read();
+    assert_eq!(state.conversation[2].message.trim(), "HELLO");
+    assert_eq!(
+        harness.spoken().last().map(String::as_str),
+        Some("It said HELLO.")
+    );
+}
+
+#[test]
+fn refused_and_denied_tool_calls_are_not_run() {
+    let refused = Harness::new(
+        &[
+            r#"{"name": "shout", "parameters": {"text": "hello"}}"#,
+            "Okay, I won't.",
+        ],
+        Some(tools_where_shout_is(ToolPermission::Confirm)),
+    );
+
+    refused.say("Shout hello for me");
+    refused.wait_until("it asks", |harness| !harness.spoken().is_empty());
+    refused.say("No, don't.");
+    refused.wait_for_turn();
+
+    assert_eq!(
+        refused.read().conversation[2].message,
+        "The user said no, so shout wasn't run."
+    );
+
+    let denied = Harness::new(
+        &[
+            r#"{"name": "shout", "parameters": {"text": "hello"}}"#,
+            "I can't do that.",
+        ],
+        Some(tools_where_shout_is(ToolPermission::Deny)),
+    );
+
+    denied.say("Shout hello for me");
+    denied.wait_for_turn();
+
+    assert_eq!(
+        denied.read().conversation[2].message,
+        "shout can't be used, the user hasn't allowed it."
+    );
+    assert_eq!(
+        denied.read().conversation[2]
+            .tool_result
+            .as_ref()
+            .unwrap()
+            .exit_code,
+        None
+    );
+    assert_eq!(denied.spoken(), vec!["I can't do that."]);
+}
+
+#[test]
+fn memories_are_forgotten_on_request() {
+    let harness = Harness::new(&[], None);
+    fs::write(
+        &harness.conversation_file,
+        "- Likes tea\n- Has a cat called Miso",
+    )
+    .unwrap();
+
+    harness.say("Jarvis, forget about my cat Miso");
+    harness.wait_until("it asks", |harness| harness.spoken().len() == 3);
+    assert_eq!(
+        harness.spoken(),
+        vec![
+            "I remember this.",
+            "Has a cat called Miso",
+            "Should I forget it?"
+        ]
+    );
+    // Nothing goes until the user says so
+    assert_eq!(
+        fs::read_to_string(&harness.conversation_file).unwrap(),
+        "- Likes tea\n- Has a cat called Miso"
+    );
+
+    harness.say("Yes");
+    harness.wait_until("it confirms", |harness| harness.spoken().len() == 4);
+
+    assert_eq!(harness.spoken()[3], "Okay, I've forgotten that.");
+    assert_eq!(
+        fs::read_to_string(&harness.conversation_file).unwrap(),
+        "- Likes tea"
+    );
+    let state = harness.read();
+    assert_eq!(state.pending_forget, None);
+    // Handled without bothering the llm
+    assert!(state.conversation.is_empty());
+}
+
+#[test]
+fn memories_in_the_system_prompt_are_forgotten_next_session() {
+    let harness = Harness::new(&[], None);
+    harness.state.update(|s| s.memories_in_system_prompt = true);
+    fs::write(&harness.conversation_file, "- Has a cat called Miso").unwrap();
+
+    harness.say("Jarvis, forget about my cat Miso");
+    harness.wait_until("it asks", |harness| harness.spoken().len() == 3);
+    harness.say("Yes");
+    harness.wait_until("it confirms", |harness| harness.spoken().len() == 4);
+
+    // The llm still has it in its system prompt until then
+    assert_eq!(
+        harness.spoken()[3],
+        "Okay, I'll have forgotten that from the next time I'm started."
+    );
+    assert_eq!(fs::read_to_string(&harness.conversation_file).unwrap(), "");
+}
+
+#[test]
+fn memories_are_kept_when_forgetting_is_refused() {
+    let harness = Harness::new(&["I'll remind you about the milk."], None);
+    fs::write(&harness.conversation_file, "- Has a cat called Miso").unwrap();
+
+    harness.say("Forget that I have a cat");
+    harness.wait_until("it asks", |harness| harness.spoken().len() == 3);
+    harness.say("No, keep it");
+    harness.wait_until("it answers", |harness| harness.spoken().len() == 4);
+
+    assert_eq!(harness.spoken()[3], "Okay, I'll keep remembering it.");
+    assert_eq!(
+        fs::read_to_string(&harness.conversation_file).unwrap(),
+        "- Has a cat called Miso"
+    );
+
+    // Not a command, so it goes to the llm and nothing is forgotten
+    harness.say("Don't forget to buy milk");
+    harness.wait_for_turn();
+
+    assert_eq!(
+        harness.conversation(),
+        vec![
+            (LlmRole::User, "Don't forget to buy milk".into()),
+            (LlmRole::Assistant, "I'll remind you about the milk.".into()),
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(&harness.conversation_file).unwrap(),
+        "- Has a cat called Miso"
+    );
+}
+
+#[test]
+fn conversation_is_summarised_into_memory_on_shutdown() {
+    let harness = Harness::new(
+        &["Nice to meet you, Sam.", "- The user is called Sam"],
+        None,
+    );
+
+    harness.say("Hi, I'm Sam");
+    harness.wait_for_turn();
+
+    harness
+        .state
+        .update(|s| s.life_cycle_state = LifeCycleState::ShuttingDown);
+    shutdown::save_conversation(harness.state.clone(), &harness.conversation_file, false).unwrap();
+
+    assert_eq!(
+        fs::read_to_string(&harness.conversation_file).unwrap(),
+        "- The user is called Sam"
+    );
+    // Nothing new is said once shutting down
+    assert_eq!(harness.spoken(), vec!["Nice to meet you, Sam."]);
+}
+
+/// Samples of a 16kHz WAV made by tests/fixtures/synth_speech.py
+fn recording(name: &str) -> Vec<f32> {
+    hound::WavReader::open(format!("tests/fixtures/{}", name))
+        .unwrap()
+        .samples::<i16>()
+        .map(|sample| sample.unwrap() as f32 / i16::MAX as f32)
+        .collect()
+}
+
+#[test]
+fn speech_from_the_mic_is_answered() {
+    let harness = Harness::new(&["Hello there."], None);
+
+    let heard = harness.listen(&recording("speech.wav"), "Hi Jarvis");
+    harness.wait_for_turn();
+
+    // A second and a half of speech and the silence it took to decide it was over
+    assert_eq!(heard.len(), 1);
+    assert!((16_000 * 2..16_000 * 3).contains(&heard[0]), "{:?}", heard);
+    assert_eq!(
+        harness.conversation(),
+        vec![
+            (LlmRole::User, "Hi Jarvis".into()),
+            (LlmRole::Assistant, "Hello there.".into()),
+        ]
+    );
+    assert_eq!(harness.spoken(), vec!["Hello there."]);
+}
+
+#[test]
+fn silence_and_short_noises_never_reach_the_llm() {
+    let harness = Harness::new(&[], None);
+
+    let silence = harness.listen(&[0.0; 16_000 * 2], "This should never be heard");
+    let blip = harness.listen(&recording("blip.wav"), "This should never be heard");
+
+    assert!(silence.is_empty());
+    assert!(blip.is_empty());
+    assert!(harness.read().conversation.is_empty());
+    assert_eq!(harness.events(), vec![]);
+}
@@ -1,35 +1,42 @@
-use std::fs;
-use std::io::Write;
-use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
 use crossterm::event::{Event, KeyCode, KeyModifiers, poll, read};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 
+use crate::confirmation;
+use crate::conversation::ConversationTree;
+use crate::export::Exporter;
+use crate::logging::{self, WorkerHandle};
+use crate::memory;
 use crate::state::{
-    ConversationSnippet, LifeCycleState, LlmCommand, LlmRole, LlmState, StateHandle,
+    ConversationSnippet, LifeCycleState, LlmCommand, LlmRole, LlmState, MemoryView, StateHandle,
+    unix_timestamp,
 };
 
 pub struct InputHandle {
-    _handle: JoinHandle<()>,
+    pub handle: WorkerHandle,
 }
 
-pub fn spawn_input_thread(state: StateHandle) -> InputHandle {
-    let handle = thread::spawn(move || {
-        run_input_loop(state);
+pub fn spawn_input_thread(
+    state: StateHandle,
+    conversation_file: String,
+    exporter: Exporter,
+) -> InputHandle {
+    let handle = logging::spawn_worker("input", state.clone(), move || {
+        run_input_loop(state, conversation_file, exporter);
+        Ok(())
     });
 
-    InputHandle { _handle: handle }
+    InputHandle { handle }
 }
 
-fn run_input_loop(state: StateHandle) {
+fn run_input_loop(state: StateHandle, conversation_file: String, exporter: Exporter) {
     let _ = enable_raw_mode();
 
     let mut pre_edit_mute_state = false;
 
     loop {
-        let current_state = state.read();
-        if current_state.life_cycle_state == LifeCycleState::ShuttingDown {
+        if state.is_shutting_down() {
             break;
         }
 
@@ -41,6 +48,12 @@ fn run_input_loop(state: StateHandle) {
             continue;
         };
 
+        let current_state = state.read();
+
+        if current_state.status_line.is_some() {
+            state.update(|s| s.status_line = None);
+        }
+
         // Ctrl+C - shutdown
         if key.code == KeyCode::Char('c') && key.modifiers == KeyModifiers::CONTROL {
             let _ = disable_raw_mode();
@@ -48,12 +61,142 @@ fn run_input_loop(state: StateHandle) {
             break;
         }
 
+        if current_state.pending_tool_call.is_some() && current_state.text_input.is_none() {
+            match key.code {
+                KeyCode::Char('y') | KeyCode::Enter => confirmation::answer(&state, true),
+                KeyCode::Char('n') | KeyCode::Esc => confirmation::answer(&state, false),
+                _ => {}
+            }
+
+            continue;
+        }
+
+        if current_state.pending_forget.is_some() && current_state.text_input.is_none() {
+            match key.code {
+                KeyCode::Char('y') | KeyCode::Enter => {
+                    memory::answer_forget(&state, true, &conversation_file)
+                }
+                KeyCode::Char('n') | KeyCode::Esc => {
+                    memory::answer_forget(&state, false, &conversation_file)
+                }
+                _ => {}
+            }
+
+            continue;
+        }
+
+        if let Some(view) = current_state.memory_view.clone()
+            && current_state.text_input.is_none()
+        {
+            match key.code {
+                KeyCode::Up if view.selected > 0 => {
+                    state.update(|s| {
+                        s.memory_view = Some(MemoryView {
+                            selected: view.selected - 1,
+                            ..view
+                        });
+                    });
+                }
+                KeyCode::Down if view.selected + 1 < view.memories.len() => {
+                    state.update(|s| {
+                        s.memory_view = Some(MemoryView {
+                            selected: view.selected + 1,
+                            ..view
+                        });
+                    });
+                }
+                KeyCode::Delete | KeyCode::Char('x') if view.selected < view.memories.len() => {
+                    let mut memories = view.memories.clone();
+                    memories.remove(view.selected);
+                    let _ = memory::save_memories(&conversation_file, &memories);
+
+                    state.update(|s| {
+                        s.memory_view = Some(MemoryView {
+                            selected: view.selected.min(memories.len().saturating_sub(1)),
+                            memories,
+                        });
+                    });
+                }
+                KeyCode::Enter | KeyCode::Char('e') => {
+                    if let Some(memory) = view.memories.get(view.selected) {
+                        let cursor_pos = memory.chars().count();
+                        state.update(|s| {
+                            s.text_input = Some((memory.clone(), cursor_pos));
+                        });
+                    }
+                }
+                KeyCode::Esc | KeyCode::Char('l') => {
+                    state.update(|s| {
+                        s.memory_view = None;
+                        s.user_mute = pre_edit_mute_state;
+                    });
+                }
+                _ => {}
+            }
+
+            continue;
+        }
+
         if let Some((edit_buffer, cursor_pos)) = current_state.text_input {
             match key.code {
+                KeyCode::Down | KeyCode::Esc if current_state.memory_view.is_some() => {
+                    state.update(|s| {
+                        s.text_input = None;
+                    });
+                }
+                KeyCode::Enter if current_state.memory_view.is_some() => {
+                    if let Some(view) = current_state.memory_view {
+                        let mut memories = view.memories;
+                        if let Some(memory) = memories.get_mut(view.selected) {
+                            *memory = edit_buffer.trim().into();
+                        }
+                        memories.retain(|memory| !memory.is_empty());
+                        let _ = memory::save_memories(&conversation_file, &memories);
+
+                        state.update(|s| {
+                            s.text_input = None;
+                            s.memory_view = Some(MemoryView {
+                                selected: view.selected.min(memories.len().saturating_sub(1)),
+                                memories,
+                            });
+                        });
+                    }
+                }
+                KeyCode::Up if current_state.is_editing => {
+                    if let Some(target) = current_state.edit_target
+                        && let Some(previous) =
+                            previous_user_message(&current_state.conversation, target)
+                    {
+                        let edit_buffer = current_state.conversation[previous].message.clone();
+                        let cursor_pos = edit_buffer.chars().count();
+                        state.update(|s| {
+                            s.text_input = Some((edit_buffer, cursor_pos));
+                            s.edit_target = Some(previous);
+                        });
+                    }
+                }
+                KeyCode::Down
+                    if current_state.is_editing
+                        && current_state.edit_target.is_some_and(|target| {
+                            next_user_message(&current_state.conversation, target).is_some()
+                        }) =>
+                {
+                    if let Some(target) = current_state.edit_target
+                        && let Some(next) = next_user_message(&current_state.conversation, target)
+                    {
+                        let edit_buffer = current_state.conversation[next].message.clone();
+                        let cursor_pos = edit_buffer.chars().count();
+                        state.update(|s| {
+                            s.text_input = Some((edit_buffer, cursor_pos));
+                            s.edit_target = Some(next);
+                        });
+                    }
+                }
                 KeyCode::Down | KeyCode::Esc => {
                     state.update(|s| {
                         s.text_input = None;
                         s.is_editing = false;
+                        s.edit_target = None;
                         s.user_mute = pre_edit_mute_state;
                     });
                 }
@@ -75,26 +218,35 @@ fn run_input_loop(state: StateHandle) {
                     });
                 }
                 KeyCode::Enter => {
+                    // The fork is rebuilt in the KV cache, so not while a reply is going into it
+                    if current_state.is_editing
+                        && current_state.llm_state != LlmState::AwaitingInput
+                    {
+                        continue;
+                    }
+
                     let text = edit_buffer.clone();
                     state.update(|s| {
                         s.system_mute = true;
                         if s.is_editing {
                             s.user_mute = pre_edit_mute_state;
                             s.text_input = None;
-                            loop {
-                                if let Some(snippet) = s.conversation.pop()
-                                    && snippet.role != LlmRole::User
-                                {
-                                    break;
-                                }
+
+                            // Editing starts a new branch from that message, the old one is kept
+                            if let Some(target) = s.edit_target.take() {
+                                s.conversation.fork(
+                                    target,
+                                    ConversationSnippet {
+                                        message: text.clone(),
+                                        role: LlmRole::User,
+                                        is_tool_call: false,
+                                        timestamp: unix_timestamp(),
+                                        tool_result: None,
+                                    },
+                                );
+                                s.llm_command = Some(LlmCommand::RebuildBranch);
                             }
-                            s.conversation.pop();
-                            s.conversation.push(ConversationSnippet {
-                                message: text.clone(),
-                                role: LlmRole::User,
-                                is_tool_call: false,
-                            });
-                            s.llm_command = Some(LlmCommand::EditLastMessage(text));
+
                             s.is_editing = false;
                         } else {
                             s.text_input = Some(("".into(), 0));
@@ -102,6 +254,8 @@ fn run_input_loop(state: StateHandle) {
                                 message: text.clone(),
                                 role: LlmRole::User,
                                 is_tool_call: false,
+                                timestamp: unix_timestamp(),
+                                tool_result: None,
                             });
                             s.llm_command = Some(LlmCommand::ContinueConversation(text));
                         }
@@ -143,30 +297,74 @@ fn run_input_loop(state: StateHandle) {
         } else {
             match key.code {
                 KeyCode::Up => {
-                    let current = state.read();
-                    let mut len = current.conversation.len() - 1;
-                    loop {
-                        if len == 0 || current.conversation[len].role == LlmRole::User {
-                            break;
-                        }
-
-                        len -= 1;
+                    if current_state.llm_state != LlmState::AwaitingInput {
+                        continue;
                     }
 
-                    let edit_buffer = current.conversation[len].clone().message;
+                    let Some(target) = previous_user_message(
+                        &current_state.conversation,
+                        current_state.conversation.len(),
+                    ) else {
+                        continue;
+                    };
+
+                    let edit_buffer = current_state.conversation[target].message.clone();
                     let cursor_pos = edit_buffer.chars().count();
                     state.update(|s| {
                         s.text_input = Some((edit_buffer, cursor_pos));
                         s.is_editing = true;
+                        s.edit_target = Some(target);
                         pre_edit_mute_state = s.user_mute;
                         s.user_mute = true;
                     });
                 }
+                KeyCode::Char('[') | KeyCode::Char(']') => {
+                    if current_state.llm_state != LlmState::AwaitingInput {
+                        continue;
+                    }
+
+                    state.update(|s| {
+                        if let Some(index) = s.conversation.last_branch_point()
+                            && s.conversation
+                                .switch_branch(index, key.code == KeyCode::Char(']'))
+                        {
+                            s.system_mute = true;
+                            s.llm_command = Some(LlmCommand::RebuildBranch);
+                        }
+                    });
+                }
                 KeyCode::Esc => {
                     state.update(|s| {
                         s.llm_command = Some(LlmCommand::CancelInference);
                     });
                 }
+                KeyCode::Char('r')
+                    if current_state.llm_state == LlmState::AwaitingInput
+                        && current_state
+                            .conversation
+                            .iter()
+                            .any(|snippet| snippet.role == LlmRole::User) =>
+                {
+                    state.update(|s| {
+                        s.system_mute = true;
+                        s.llm_command = Some(LlmCommand::Regenerate);
+                    });
+                }
+                KeyCode::Left | KeyCode::Right => {
+                    let count = current_state.reply_alternatives.len();
+
+                    if count > 1 && current_state.llm_state == LlmState::AwaitingInput {
+                        let index = if key.code == KeyCode::Left {
+                            (current_state.selected_alternative + count - 1) % count
+                        } else {
+                            (current_state.selected_alternative + 1) % count
+                        };
+
+                        state.update(|s| {
+                            s.llm_command = Some(LlmCommand::SelectAlternative(index));
+                        });
+                    }
+                }
                 KeyCode::Char('m') => {
                     state.update(|s| {
                         s.user_mute = !s.user_mute;
@@ -180,6 +378,17 @@ fn run_input_loop(state: StateHandle) {
                         s.user_mute = true;
                     });
                 }
+                KeyCode::Char('l') => {
+                    let memories = memory::load_memories(&conversation_file);
+                    state.update(|s| {
+                        s.memory_view = Some(MemoryView {
+                            memories,
+                            selected: 0,
+                        });
+                        pre_edit_mute_state = s.user_mute;
+                        s.user_mute = true;
+                    });
+                }
                 KeyCode::Char('?') => {
                     state.update(|s| {
                         s.is_hiding_think_tags = !s.is_hiding_think_tags;
@@ -189,25 +398,33 @@ fn run_input_loop(state: StateHandle) {
                     state.update(|s| {
                         s.is_only_responding_after_name = !s.is_only_responding_after_name;
                         s.time_since_name_was_said = None;
+                        s.is_waiting_for_name = false;
                     });
                 }
                 KeyCode::Char('d') => {
-                    let state = state.read();
-                    if let Ok(mut file) = fs::File::create("conversation_dump.txt") {
-                        let _ = write!(
-                            file,
-                            "{}",
-                            state
-                                .conversation
-                                .iter()
-                                .map(|snippet| { format!("{}:{}", snippet.role, snippet.message) })
-                                .collect::<Vec<String>>()
-                                .join("\n---\n")
-                        );
-                    }
+                    let status = match exporter.export(&current_state) {
+                        Ok(written) => format!("Exported to {}", written.join(", ")),
+                        Err(e) => {
+                            tracing::warn!("couldn't export the conversation: {:?}", e);
+                            format!("Couldn't export the conversation: {}", e)
+                        }
+                    };
+                    state.update(|s| s.status_line = Some(status));
                 }
                 _ => {}
             }
         }
     }
 }
+
+/// Index of the closest user message before `index` on the active branch
+fn previous_user_message(conversation: &ConversationTree, index: usize) -> Option<usize> {
+    (0..index.min(conversation.len()))
+        .rev()
+        .find(|&i| conversation[i].role == LlmRole::User)
+}
+
+/// Index of the closest user message after `index` on the active branch
+fn next_user_message(conversation: &ConversationTree, index: usize) -> Option<usize> {
+    (index + 1..conversation.len()).find(|&i| conversation[i].role == LlmRole::User)
+}
@@ -0,0 +1,157 @@
+# Prints the public top level functions of a python file as JSON, for tools.rs.
+# The file is only parsed, never imported, so nothing in it runs.
+import ast
+import json
+import re
+import sys
+
+PARAMETER_SECTIONS = {"args", "arguments", "parameters", "params", "keyword args", "keyword arguments"}
+OTHER_SECTIONS = {
+    "returns", "return", "yields", "yield", "raises", "raise", "examples", "example",
+    "notes", "note", "see also", "references", "warnings", "warning", "attributes",
+}
+GOOGLE_PARAMETER = re.compile(r"^\*{0,2}(\w+)\s*(?:\((.*)\))?\s*:\s*(.*)$")
+NUMPY_PARAMETER = re.compile(r"^\*{0,2}(\w+)\s*(?::\s*(.*))?$")
+OPTIONAL = re.compile(r"^(?:typing\.)?Optional\[(.*)\]$")
+
+
+def section_name(line):
+    name = line.strip().rstrip(":").lower()
+    if name in PARAMETER_SECTIONS or name in OTHER_SECTIONS:
+        return name
+    return None
+
+
+def is_underline(line):
+    return bool(line.strip()) and set(line.strip()) == {"-"}
+
+
+def indent(line):
+    return len(line) - len(line.lstrip())
+
+
+def parse_parameters(lines, numpy):
+    """Google style has `name (type): description`, NumPy style has `name : type`
+    with the description indented on the lines below"""
+    parameters = {}
+    current = None
+    base = None
+
+    for line in lines:
+        if not line.strip():
+            continue
+
+        if base is None:
+            base = indent(line)
+
+        if indent(line) <= base:
+            match = (NUMPY_PARAMETER if numpy else GOOGLE_PARAMETER).match(line.strip())
+            if not match:
+                current = None
+                continue
+
+            current = match.group(1)
+            if numpy:
+                parameters[current] = {"type": (match.group(2) or "").strip(), "description": ""}
+            else:
+                parameters[current] = {
+                    "type": (match.group(2) or "").split(",")[0].strip(),
+                    "description": match.group(3).strip(),
+                }
+        elif current is not None:
+            description = parameters[current]["description"]
+            parameters[current]["description"] = (description + " " + line.strip()).strip()
+
+    return parameters
+
+
+def parse_docstring(docstring):
+    """Splits a docstring into its summary and per parameter type and description"""
+    lines = docstring.splitlines()
+    summary = []
+    parameters = {}
+    i = 0
+
+    while i < len(lines):
+        name = section_name(lines[i])
+        if name is None:
+            summary.append(lines[i])
+            i += 1
+            continue
+
+        numpy = i + 1 < len(lines) and is_underline(lines[i + 1])
+        i += 2 if numpy else 1
+
+        body = []
+        while i < len(lines) and section_name(lines[i]) is None:
+            body.append(lines[i])
+            i += 1
+
+        if name in PARAMETER_SECTIONS:
+            parameters.update(parse_parameters(body, numpy))
+
+    return "\n".join(summary).strip(), parameters
+
+
+def annotation_type(annotation):
+    """`Optional[int]` and `int | None` can only be given an int by the llm, so they're just int"""
+    if annotation is None:
+        return ""
+
+    if isinstance(annotation, ast.BinOp) and isinstance(annotation.op, ast.BitOr):
+        sides = [annotation.left, annotation.right]
+        rest = [side for side in sides if not (isinstance(side, ast.Constant) and side.value is None)]
+        if len(rest) == 1:
+            return annotation_type(rest[0])
+
+    text = ast.unparse(annotation)
+    optional = OPTIONAL.match(text)
+    if optional:
+        return optional.group(1)
+
+    union = re.match(r"^(?:typing\.)?Union\[(.*),\s*None\]$", text)
+    if union:
+        return union.group(1)
+
+    return text
+
+
+def describe(function):
+    summary, documented = parse_docstring(ast.get_docstring(function) or "")
+    arguments = function.args
+
+    # *args and **kwargs can't be given by name, so the llm never gets to use them
+    positional = arguments.posonlyargs + arguments.args
+    defaults = [None] * (len(positional) - len(arguments.defaults)) + list(arguments.defaults)
+    pairs = list(zip(positional, defaults)) + list(zip(arguments.kwonlyargs, arguments.kw_defaults))
+
+    parameters = []
+    for argument, default in pairs:
+        if argument.arg in ("self", "cls"):
+            continue
+
+        docs = documented.get(argument.arg, {})
+        parameter = {
+            "name": argument.arg,
+            "type": annotation_type(argument.annotation) or docs.get("type", ""),
+            "description": docs.get("description", ""),
+            "required": default is None,
+        }
+        if default is not None:
+            parameter["default"] = ast.unparse(default)
+
+        parameters.append(parameter)
+
+    return {"name": function.name, "description": summary, "parameters": parameters}
+
+
+with open(sys.argv[1], encoding="utf-8") as file:
+    module = ast.parse(file.read(), filename=sys.argv[1])
+
+functions = [
+    describe(node)
+    for node in module.body
+    if isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef)) and not node.name.startswith("_")
+]
+
+print(json.dumps(functions))
@@ -0,0 +1,261 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::logging::{self, WorkerHandle};
+use crate::state::{ConversationSnippet, LifeCycleState, LlmRole, LlmState, StateHandle};
+use crate::ui;
+
+/// One line of the journal. Replaying truncates the conversation to `index`
+/// and then pushes `snippet`, so edits and cancellations can be recorded too.
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    index: usize,
+    snippet: Option<ConversationSnippet>,
+}
+
+pub struct JournalHandle {
+    pub handle: WorkerHandle,
+}
+
+/// Journals every finished message and keeps the session file up to date alongside it,
+/// so every branch is kept even if the app never gets to shut down
+pub fn spawn_journal_thread(
+    state: StateHandle,
+    journal_file: String,
+    session_file: String,
+) -> JournalHandle {
+    let handle = logging::spawn_worker("journal", state.clone(), move || {
+        run_journal_loop(state, journal_file, session_file)
+    });
+
+    JournalHandle { handle }
+}
+
+fn run_journal_loop(
+    state: StateHandle,
+    journal_file: String,
+    session_file: String,
+) -> anyhow::Result<()> {
+    let events = state.subscribe();
+    // Only what was written is compared against, and only when the end of it doesn't line up,
+    // so a token arriving doesn't mean going through the whole conversation.
+    // A resumed session was already remembered so it's left out.
+    let mut written: Vec<ConversationSnippet> = state.read_with(|s| {
+        s.conversation
+            .iter()
+            .take(s.resumed_snippets)
+            .cloned()
+            .collect()
+    });
+
+    while events.wait() {
+        let update = state.read_with(|s| {
+            // Anything after this is the memory summary, which gets saved properly
+            if s.life_cycle_state == LifeCycleState::ShuttingDown {
+                return None;
+            }
+
+            let mut finalised = s.conversation.len();
+
+            // The reply being streamed isn't final until inference finishes
+            if s.llm_state == LlmState::RunningInference
+                && s.conversation
+                    .last()
+                    .is_some_and(|snippet| snippet.role == LlmRole::Assistant)
+            {
+                finalised -= 1;
+            }
+
+            let lines_up = finalised >= written.len()
+                && (written.is_empty() || s.conversation.get(written.len() - 1) == written.last());
+
+            // Edited, cancelled or regenerated, so find where it stopped matching
+            let common = if lines_up {
+                written.len()
+            } else {
+                written
+                    .iter()
+                    .zip(s.conversation.iter().take(finalised))
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            };
+
+            Some((
+                common,
+                finalised,
+                s.conversation
+                    .iter()
+                    .take(finalised)
+                    .skip(common)
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            ))
+        });
+
+        let Some((common, finalised, new)) = update else {
+            break;
+        };
+
+        if common == written.len() && common == finalised {
+            continue;
+        }
+
+        let mut entries = Vec::new();
+
+        if finalised == common {
+            entries.push(JournalEntry {
+                index: common,
+                snippet: None,
+            });
+        }
+
+        for (index, snippet) in new.iter().enumerate() {
+            entries.push(JournalEntry {
+                index: common + index,
+                snippet: Some(snippet.clone()),
+            });
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_file)?;
+
+        for entry in entries {
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+
+        file.sync_data()?;
+        written.truncate(common);
+        written.extend(new);
+
+        state
+            .read_with(|s| s.conversation.clone())
+            .save(&session_file)?;
+    }
+
+    Ok(())
+}
+
+/// Rebuilds the conversation left behind by a session that didn't shut down cleanly
+pub fn recover(journal_file: &str) -> Option<Vec<ConversationSnippet>> {
+    let content = fs::read_to_string(journal_file).ok()?;
+    let mut conversation = Vec::new();
+
+    for line in content.lines() {
+        // A crash mid write can leave the last line torn
+        let Ok(entry) = serde_json::from_str::<JournalEntry>(line) else {
+            continue;
+        };
+
+        conversation.truncate(entry.index);
+
+        // Entries past the end follow on from a resumed session, which isn't in the journal
+        // since it was already remembered
+        if let Some(snippet) = entry.snippet
+            && entry.index >= conversation.len()
+        {
+            conversation.push(snippet);
+        }
+    }
+
+    if conversation.is_empty() {
+        None
+    } else {
+        Some(conversation)
+    }
+}
+
+/// Asks whether an orphaned journal should be summarised into memory.
+/// Returns the recovered conversation if so, otherwise the journal is thrown away.
+pub fn offer_recovery(journal_file: &str) -> anyhow::Result<Option<Vec<ConversationSnippet>>> {
+    let Some(conversation) = recover(journal_file) else {
+        clear(journal_file);
+        return Ok(None);
+    };
+
+    ui::journal_recovery_prompt(conversation.len());
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    if input.trim().eq_ignore_ascii_case("n") {
+        clear(journal_file);
+        return Ok(None);
+    }
+
+    Ok(Some(conversation))
+}
+
+pub fn clear(journal_file: &str) {
+    let _ = fs::remove_file(journal_file);
+}
+
+/// Moves a journal that couldn't be summarised out of the way, so starting up doesn't keep
+/// failing on it. Returns where it went.
+pub fn set_aside(journal_file: &str) -> String {
+    let failed = format!("{}.failed", journal_file);
+    if let Err(e) = fs::rename(journal_file, &failed) {
+        tracing::warn!("couldn't move {} aside: {:?}", journal_file, e);
+    }
+    failed
+}
+
+/// The recovered conversation as a transcript to summarise, keeping only the most recent lines
+/// when all of it would take more than `budget` tokens
+pub fn transcript(
+    conversation: &[ConversationSnippet],
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> String {
+    let mut lines = Vec::new();
+    let mut used = 0;
+
+    for snippet in conversation
+        .iter()
+        .rev()
+        .filter(|snippet| !snippet.is_tool_call)
+    {
+        let line = format!("{}: {}", snippet.role, snippet.message);
+        // One for the newline between lines
+        let cost = count_tokens(&line) + 1;
+
+        if used + cost > budget {
+            break;
+        }
+
+        used += cost;
+        lines.push(line);
+    }
+
+    lines.reverse();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::snippet;
+
+    #[test]
+    fn long_transcripts_keep_the_end() {
+        let conversation = [
+            snippet(LlmRole::User, "one two three"),
+            snippet(LlmRole::Assistant, "four five"),
+            snippet(LlmRole::User, "six"),
+        ];
+        let words = |text: &str| text.split_whitespace().count();
+
+        assert_eq!(
+            transcript(&conversation, 100, words),
+            "User: one two three\nAssistant: four five\nUser: six"
+        );
+        assert_eq!(
+            transcript(&conversation, 7, words),
+            "Assistant: four five\nUser: six"
+        );
+        assert_eq!(transcript(&conversation, 1, words), "");
+    }
+}
@@ -1,74 +1,116 @@
+use std::collections::VecDeque;
 use std::num::NonZeroU32;
-use std::thread;
-use std::thread::JoinHandle;
 use std::time::Instant;
 
 use chrono::Timelike;
 use llama_cpp_2::{
-    context::params::LlamaContextParams,
+    context::{LlamaContext, params::LlamaContextParams},
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
     model::{
-        AddBos, LlamaChatMessage, LlamaModel,
+        AddBos, LlamaChatMessage, LlamaChatTemplate, LlamaModel,
         params::{self},
     },
     sampling::LlamaSampler,
+    token::LlamaToken,
 };
 use regex::Regex;
-
-use crate::{state::{ConversationSnippet, LifeCycleState, LlmCommand, LlmRole, LlmState}, tools::{is_start_of_tool_call, is_tool_call_complete}};
-use crate::tools::{
-    ToJson, parse_python_functions, run_tool, split_tool_calls, supports_tools, try_parse_tool_call,
-};
+use tracing::{debug, info, warn};
+
+use crate::{state::{ConversationSnippet, LifeCycleState, LlmCommand, LlmRole, LlmState}, tools::ToolFormat};
+use crate::tools::{ToJson, load_tools, supports_tools};
+use crate::builtins::Builtins;
+use crate::conversation::ConversationTree;
+use crate::grammar;
+use crate::journal;
+use crate::logging::{self, WorkerHandle};
+use crate::memory::{self, EMBEDDING_CONTEXT_SIZE, MemoryIndex, RetrievalConfig};
+use crate::metrics::{self, TurnTimings};
+use crate::reply::{self, ReplyOptions, TokenSource};
+use crate::shutdown::SUMMARY_PROMPT;
+use crate::speculative::{self, Draft};
 use crate::ui;
-use crate::{config::Assistant, state::StateHandle};
+use crate::{
+    config::{Assistant, SamplingConfig, ToolLimits, ToolPermissions, ToolSandbox},
+    state::StateHandle,
+};
 use rand::RngCore;
 
 const BATCH_SIZE: i32 = 2048;
 
 pub struct LlmHandle {
-    _handle: JoinHandle<()>,
+    pub handle: WorkerHandle,
 }
 
-pub fn spawn_llm_thread(
-    state: StateHandle,
-    assistant: Assistant,
-    llm_threads: i32,
-    llm_context_size: u32,
-    enable_word_by_word_response: bool,
-) -> LlmHandle {
-    let handle = thread::spawn(move || {
-        let _ = run_llm_loop(
-            state,
-            assistant,
-            llm_threads,
-            llm_context_size,
-            enable_word_by_word_response,
-        );
-    });
+pub struct LlmOptions {
+    pub threads: i32,
+    pub context_size: u32,
+    pub enable_word_by_word_response: bool,
+    pub retrieval: Option<RetrievalConfig>,
+    /// Conversation from a session that crashed, to be summarised before starting
+    pub recovered: Option<Vec<ConversationSnippet>>,
+    /// Keep every chunk of templated text that gets decoded, for debug exports
+    pub record_decoded_text: bool,
+    /// Keep earlier replies around when regenerating so they can be switched between
+    pub keep_alternative_replies: bool,
+    pub sampling: SamplingConfig,
+    pub tool_limits: ToolLimits,
+    pub tool_sandbox: ToolSandbox,
+    pub tool_permissions: ToolPermissions,
+}
+
+/// Answers `llm_command`s until the app quits. llama.cpp in the app, scripted replies in tests.
+pub trait Llm: Send + 'static {
+    fn run(self, state: StateHandle) -> anyhow::Result<()>;
+}
+
+pub struct Llama {
+    pub assistant: Assistant,
+    pub options: LlmOptions,
+}
+
+impl Llm for Llama {
+    fn run(self, state: StateHandle) -> anyhow::Result<()> {
+        run_llm_loop(state, self.assistant, self.options)
+    }
+}
+
+pub fn spawn_llm_thread(state: StateHandle, llm: impl Llm) -> LlmHandle {
+    let handle = logging::spawn_worker("llm", state.clone(), move || llm.run(state));
 
-    LlmHandle { _handle: handle }
+    LlmHandle { handle }
 }
 
 fn run_llm_loop(
     state: StateHandle,
     assistant: Assistant,
-    llm_threads: i32,
-    llm_context_size: u32,
-    enable_word_by_word_response: bool,
+    options: LlmOptions,
 ) -> anyhow::Result<()> {
-    let mut backend = Box::new(LlamaBackend::init()?);
-    let end_sentence = Regex::new(r"[.?;:]")?;
-    backend.void_logs();
+    let LlmOptions {
+        threads: llm_threads,
+        context_size: llm_context_size,
+        enable_word_by_word_response,
+        retrieval,
+        recovered,
+        record_decoded_text,
+        keep_alternative_replies,
+        sampling,
+        tool_limits,
+        tool_sandbox,
+        tool_permissions,
+    } = options;
+
+    let backend = Box::new(LlamaBackend::init()?);
 
     ui::status_llm_loaded();
     let params = params::LlamaModelParams::default().with_n_gpu_layers(99);
 
     let model = Box::new(LlamaModel::load_from_file(
         &backend,
-        assistant.llm_model_path.unwrap(),
+        assistant.llm_model_path.clone().unwrap(),
         &params,
     )?);
+    info!(path = ?assistant.llm_model_path, "loaded llm");
 
     let context_params = LlamaContextParams::default()
         .with_n_threads(llm_threads)
@@ -76,14 +118,121 @@ fn run_llm_loop(
 
     let mut ctx = model.new_context(&backend, context_params)?;
 
+    // Embedding model for memory retrieval, shares the backend with the main model
+    let embedding_model = match &retrieval {
+        Some(retrieval) => Some(Box::new(LlamaModel::load_from_file(
+            &backend,
+            &retrieval.embedding_model_path,
+            &params,
+        )?)),
+        None => None,
+    };
+
+    let mut embedding_ctx = match &embedding_model {
+        Some(embedding_model) => Some(
+            embedding_model.new_context(
+                &backend,
+                LlamaContextParams::default()
+                    .with_n_threads(llm_threads)
+                    .with_n_threads_batch(llm_threads)
+                    .with_n_ctx(NonZeroU32::new(EMBEDDING_CONTEXT_SIZE))
+                    .with_n_batch(EMBEDDING_CONTEXT_SIZE)
+                    .with_n_ubatch(EMBEDDING_CONTEXT_SIZE)
+                    .with_embeddings(true),
+            )?,
+        ),
+        None => None,
+    };
+
+    // Small model that guesses ahead for the main one to check, also shares the backend
+    let draft_model = match &assistant.draft_model_path {
+        Some(draft_model_path) => Some(Box::new(LlamaModel::load_from_file(
+            &backend,
+            draft_model_path,
+            &params,
+        )?)),
+        None => None,
+    };
+    // Only a speed-up, so a draft that can't be used is left out rather than stopping the llm
+    let draft_model = draft_model.filter(|draft_model| {
+        speculative::check_vocab(draft_model, &model)
+            .inspect_err(|e| warn!("not using the draft model: {}", e))
+            .is_ok()
+    });
+
+    let mut draft = match &draft_model {
+        Some(draft_model) => Some(Draft::new(
+            &backend,
+            draft_model,
+            llm_threads,
+            llm_context_size,
+            assistant.draft_tokens,
+        )?),
+        None => None,
+    };
+
+    let mut memory_index = MemoryIndex::default();
+
+    if let Some(retrieval) = &retrieval
+        && let Some(embedding_model) = &embedding_model
+        && let Some(embedding_ctx) = embedding_ctx.as_mut()
+    {
+        memory_index = MemoryIndex::load(&retrieval.index_file);
+
+        if memory_index.sync(
+            &memory::load_memories(&retrieval.conversation_file),
+            |text| memory::embed(embedding_ctx, embedding_model, text),
+        )? {
+            memory_index.save(&retrieval.index_file)?;
+        }
+    }
+
     let _chat_template = model.chat_template(None).unwrap();
 
+    // Summarise a session that crashed before it could be remembered. If that fails the journal
+    // is moved aside rather than left to fail the same way every time the app starts.
+    if let Some(conversation) = recovered {
+        ui::status_recovering();
+
+        if let Err(e) = summarise_recovered(
+            &model,
+            &mut ctx,
+            &_chat_template,
+            &conversation,
+            &assistant.conversation_file(),
+        ) {
+            let journal_file = journal::set_aside(&assistant.journal_file());
+            warn!("couldn't summarise the last session: {:?}", e);
+            state.update(|s| {
+                s.worker_errors.push(format!(
+                    "The last session couldn't be remembered, its journal was moved to {}",
+                    journal_file
+                ))
+            });
+        } else {
+            journal::clear(&assistant.journal_file());
+        }
+    }
+
     let mut batch = LlamaBatch::new(BATCH_SIZE as usize, 1);
 
     let (prompt, tools) = if supports_tools(_chat_template.to_str()?)
-        && let Some(tool_directory) = assistant.tool_path.clone()
+        && let Some(mut tools) = load_tools(
+            assistant.tool_path.as_deref(),
+            &assistant.tool_manifests,
+            &assistant.mcp_servers,
+            Builtins::new(
+                &assistant.builtin_tools,
+                &assistant.notes_directory(),
+                state.clone(),
+            ),
+            &tool_sandbox,
+        )
     {
-        let tools = parse_python_functions(tool_directory.clone());
+        tools.permissions = tool_permissions.clone();
+        if let Err(e) = tools.start_worker() {
+            warn!("couldn't start the tool worker: {:?}", e);
+        }
         let tools_str = tools.tools.to_json().unwrap();
 
         let greeting_time = match chrono::offset::Local::now().time().hour() {
@@ -110,8 +259,12 @@ fn run_llm_loop(
             false,
         );
 
-        // println!("{:?}\n\r{:?}\n\r{:?}", tools_str, tool_directory, proompt);
-        // std::thread::sleep(std::time::Duration::from_millis(8000));
+        debug!(
+            tool_path = ?assistant.tool_path,
+            tool_manifests = ?assistant.tool_manifests,
+            tools = %tools_str,
+            "applied chat template with tools"
+        );
 
         match proompt {
             Ok(data) => (data.prompt, Some(tools)),
@@ -143,44 +296,133 @@ fn run_llm_loop(
         "user" // fallback for older templates
     };
 
-    let system_tokens = model.str_to_token(&prompt, AddBos::Always).unwrap();
+    // Grammars are built for the format of the marker a call starts with, the template only
+    // settles which kind of XML call and whether a reply starting with a brace is a JSON call
+    let template_format = grammar::tool_call_format(_chat_template.to_str().unwrap_or(""));
+    let tool_grammar = |format: ToolFormat| {
+        tools
+            .as_ref()
+            .and_then(|tools| grammar::tool_call_grammar(&tools.tools, &format))
+    };
+
+    if record_decoded_text {
+        state.update(|s| s.decoded_text.push(prompt.clone()));
+    }
+
+    let system_tokens = model.str_to_token(&prompt, AddBos::Always)?;
 
     for (i, token) in system_tokens.iter().enumerate() {
         if batch.n_tokens() >= BATCH_SIZE {
-            ctx.decode(&mut batch).unwrap();
+            ctx.decode(&mut batch)?;
             batch.clear();
         }
         let is_last = i == system_tokens.len() - 1 && batch.n_tokens() < BATCH_SIZE - 1;
-        batch.add(*token, i as i32, &[0], is_last).unwrap();
+        batch.add(*token, i as i32, &[0], is_last)?;
     }
 
     ui::status_llm_context_init();
 
     if batch.n_tokens() > 0 {
-        ctx.decode(&mut batch).unwrap();
+        ctx.decode(&mut batch)?;
     }
 
     let system_token_len = system_tokens.len() as i32;
     let mut n_past = system_token_len;
+    // Every token in the KV cache, so the draft model can be kept in line with it
+    let mut kv_tokens: Vec<LlamaToken> = system_tokens.clone();
     let mut exchange_checkpoints: Vec<i32> = vec![];
+    // Index of the user snippet that started each exchange, alongside its checkpoint
+    let mut exchange_indices: Vec<usize> = vec![];
+    // The branch of the conversation currently held in the KV cache
+    let mut kv_path: Vec<ConversationSnippet> = vec![];
+    // Where the last exchange's reply started, for switching between alternatives
+    let mut reply_start = n_past;
+    // How many times the last exchange has been regenerated, so each attempt samples differently
+    let mut attempt: u32 = 0;
+
+    // The memories most relevant to a message, to go in ahead of it
+    let mut relevant_memories = |message: &str| -> Option<String> {
+        let retrieval = retrieval.as_ref()?;
+        let embedding_model = embedding_model.as_ref()?;
+        let embedding_ctx = embedding_ctx.as_mut()?;
+
+        // Memories can be edited or forgotten mid session so catch the index up first
+        if let Ok(true) = memory_index.sync(
+            &memory::load_memories(&retrieval.conversation_file),
+            |text| memory::embed(embedding_ctx, embedding_model, text),
+        ) {
+            let _ = memory_index.save(&retrieval.index_file);
+        }
+
+        let query = memory::embed(embedding_ctx, embedding_model, message).ok()?;
+        memory::retrieve_memories(
+            &memory_index,
+            &query,
+            retrieval.top_k,
+            retrieval.token_budget,
+            |text| {
+                model
+                    .str_to_token(text, AddBos::Never)
+                    .map(|tokens| tokens.len())
+                    .unwrap_or(usize::MAX)
+            },
+        )
+    };
 
+    // Subscribed first so a command that came in while loading, like rebuilding a resumed
+    // session, gets picked up straight away
+    let events = state.subscribe();
     state.update(|s| {
         s.llm_state = LlmState::AwaitingInput;
         s.system_mute = false;
         s.life_cycle_state = LifeCycleState::Running;
     });
 
-    while state.subscribe().recv().is_ok() {
+    while events.wait() {
         let current_state = state.read();
+        let is_regenerating = current_state.llm_command == Some(LlmCommand::Regenerate);
 
         let messages: Vec<LlamaChatMessage> = if let Some(command) = current_state.llm_command {
+            debug!(?command, "llm command");
             match command {
                 LlmCommand::CancelInference => continue,
                 LlmCommand::ContinueConversation(message) => {
-                    vec![LlamaChatMessage::new("user".into(), message).unwrap()]
+                    let mut messages = vec![];
+
+                    // Reminders that went off since the last turn were said without the llm, so
+                    // it hears about them before the message they came ahead of
+                    let active = current_state.conversation.to_vec();
+                    if active.len() > kv_path.len() + 1 && active.starts_with(&kv_path) {
+                        for snippet in &active[kv_path.len()..active.len() - 1] {
+                            if snippet.role == LlmRole::Assistant {
+                                messages.push(
+                                    LlamaChatMessage::new(
+                                        "system".into(),
+                                        format!("Meanwhile, you said: {}", snippet.message),
+                                    )
+                                    .unwrap(),
+                                );
+                            }
+                        }
+                    }
+
+                    // Inject the memories most relevant to this turn ahead of it
+                    if current_state.life_cycle_state != LifeCycleState::ShuttingDown
+                        && let Some(memories) = relevant_memories(&message)
+                    {
+                        messages.push(LlamaChatMessage::new("system".into(), memories).unwrap());
+                    }
+
+                    messages.push(LlamaChatMessage::new("user".into(), message).unwrap());
+                    messages
                 }
                 LlmCommand::DestroyContextAndRunFromNothing(llama_chat_messages) => {
+                    // Nothing is left in the cache to roll back to or pick up from
                     ctx.clear_kv_cache();
+                    kv_tokens.clear();
+                    n_past = 0;
+                    exchange_checkpoints.clear();
+                    exchange_indices.clear();
                     llama_chat_messages
                         .iter()
                         .map(|(role, message)| {
@@ -188,17 +430,182 @@ fn run_llm_loop(
                         })
                         .collect()
                 }
-                LlmCommand::EditLastMessage(message) => {
-                    if let Some(checkpoint) = exchange_checkpoints.pop() {
-                        ctx.clear_kv_cache_seq(
-                            Some(0),
-                            Some((checkpoint - 1) as u32),
-                            Some(ctx.kv_cache_seq_pos_max(0) as u32),
-                        )
+                LlmCommand::RebuildBranch => {
+                    let active = current_state.conversation.to_vec();
+
+                    // Keep every exchange that is the same on the new branch and roll back the rest
+                    let common = kv_path
+                        .iter()
+                        .zip(&active)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    let keep = (0..exchange_indices.len())
+                        .take_while(|&j| {
+                            exchange_indices
+                                .get(j + 1)
+                                .copied()
+                                .unwrap_or(kv_path.len())
+                                <= common
+                        })
+                        .count();
+
+                    let mut decoded_len = kv_path.len();
+
+                    if keep < exchange_checkpoints.len() {
+                        decoded_len = exchange_indices[keep];
+                        n_past = exchange_checkpoints[keep];
+                        ctx.clear_kv_cache_seq(Some(0), Some(n_past as u32), None)
+                            .unwrap_or(false);
+                        exchange_checkpoints.truncate(keep);
+                        exchange_indices.truncate(keep);
+                    }
+
+                    // A branch ending on a user message still needs a reply generating
+                    let needs_reply = active
+                        .last()
+                        .is_some_and(|snippet| snippet.role == LlmRole::User)
+                        && active.len() > decoded_len;
+                    let replay_to = if needs_reply {
+                        active.len() - 1
+                    } else {
+                        active.len()
+                    };
+
+                    for (index, snippet) in
+                        active.iter().enumerate().take(replay_to).skip(decoded_len)
+                    {
+                        if snippet.role == LlmRole::User {
+                            exchange_checkpoints.push(n_past);
+                            exchange_indices.push(index);
+                        }
+
+                        let text = replay_text(&model, &_chat_template, tool_result_role, snippet)?;
+                        if record_decoded_text {
+                            state.update(|s| s.decoded_text.push(text.clone()));
+                        }
+
+                        decode_text(
+                            &model,
+                            &mut ctx,
+                            &mut batch,
+                            &text,
+                            &mut n_past,
+                            &mut kv_tokens,
+                        )?;
+                    }
+
+                    if needs_reply {
+                        vec![
+                            LlamaChatMessage::new("user".into(), active[replay_to].message.clone())
+                                .unwrap(),
+                        ]
+                    } else {
+                        kv_path = active;
+
+                        state.update(|s| {
+                            s.llm_command = None;
+                            s.system_mute = false;
+                            s.llm_state = LlmState::AwaitingInput;
+                            // They were for the last exchange of the branch that was left
+                            s.reply_alternatives.clear();
+                            s.selected_alternative = 0;
+                        });
+
+                        continue;
+                    }
+                }
+                LlmCommand::Regenerate => {
+                    exchange_indices.pop();
+                    let Some(checkpoint) = exchange_checkpoints.pop() else {
+                        state.update(|s| {
+                            s.llm_command = None;
+                            s.system_mute = false;
+                            s.llm_state = LlmState::AwaitingInput;
+                        });
+                        continue;
+                    };
+
+                    // Roll back to before the last exchange and run it again, with the creative
+                    // sampler reseeded for this attempt so even a configured seed gives a new reply
+                    attempt += 1;
+                    ctx.clear_kv_cache_seq(Some(0), Some(checkpoint as u32), None)
                         .unwrap_or(false);
+                    n_past = checkpoint;
+
+                    let mut message = None;
+                    state.update(|s| {
+                        let (previous, user_message) = take_last_exchange(&mut s.conversation);
+                        message = user_message;
+
+                        if keep_alternative_replies
+                            && s.reply_alternatives.is_empty()
+                            && !previous.is_empty()
+                        {
+                            s.reply_alternatives.push(previous);
+                        }
+                    });
+
+                    // Asked again from the active branch, which a branch switch, a resumed
+                    // session or an interrupted turn may have changed since the last reply
+                    let Some(message) = message else {
+                        state.update(|s| {
+                            s.llm_command = None;
+                            s.system_mute = false;
+                            s.llm_state = LlmState::AwaitingInput;
+                        });
+                        continue;
+                    };
+
+                    let mut messages = vec![];
+                    if let Some(memories) = relevant_memories(&message) {
+                        messages.push(LlamaChatMessage::new("system".into(), memories).unwrap());
+                    }
+                    messages.push(LlamaChatMessage::new("user".into(), message).unwrap());
+                    messages
+                }
+                LlmCommand::SelectAlternative(index) => {
+                    let Some(alternative) = current_state.reply_alternatives.get(index).cloned()
+                    else {
+                        state.update(|s| s.llm_command = None);
+                        continue;
+                    };
+
+                    // Swap the exchange in the KV cache for the chosen one, tool calls and all
+                    ctx.clear_kv_cache_seq(Some(0), Some(reply_start as u32), None)
+                        .unwrap_or(false);
+                    n_past = reply_start;
+
+                    for snippet in &alternative {
+                        let text = replay_text(&model, &_chat_template, tool_result_role, snippet)?;
+                        if record_decoded_text {
+                            state.update(|s| s.decoded_text.push(text.clone()));
+                        }
+
+                        decode_text(
+                            &model,
+                            &mut ctx,
+                            &mut batch,
+                            &text,
+                            &mut n_past,
+                            &mut kv_tokens,
+                        )?;
                     }
 
-                    vec![LlamaChatMessage::new("user".into(), message).unwrap()]
+                    state.update(|s| {
+                        for _ in 0..last_exchange(&s.conversation).len() {
+                            s.conversation.pop();
+                        }
+
+                        for snippet in alternative {
+                            s.conversation.push(snippet);
+                        }
+                        s.selected_alternative = index;
+                        s.llm_command = None;
+                    });
+
+                    kv_path = state.read().conversation.to_vec();
+
+                    continue;
                 }
             }
         } else {
@@ -208,216 +615,108 @@ fn run_llm_loop(
         state.update(|s| {
             s.llm_command = None;
             s.llm_state = LlmState::RunningInference;
+
+            if !is_regenerating {
+                s.reply_alternatives.clear();
+                s.selected_alternative = 0;
+            }
+
+            // A voice turn already has its timings from the end of speech, anything else starts here
+            if !s
+                .turn_timings
+                .as_ref()
+                .is_some_and(|timings| timings.prompt_decoded.is_none())
+            {
+                s.turn_timings = Some(TurnTimings::new(Instant::now()));
+            }
         });
 
         let n_past_before = n_past;
         exchange_checkpoints.push(n_past_before);
+        exchange_indices.push({
+            let conversation = state.read().conversation;
+            match conversation.last() {
+                Some(snippet) if snippet.role == LlmRole::User => conversation.len() - 1,
+                _ => conversation.len(),
+            }
+        });
 
         let chat_message = model
             .apply_chat_template(&_chat_template, messages.as_slice(), true)
             .unwrap();
-        let user_tokens = model.str_to_token(&chat_message, AddBos::Never).unwrap();
+        if record_decoded_text {
+            state.update(|s| s.decoded_text.push(chat_message.clone()));
+        }
+
+        let user_tokens = model.str_to_token(&chat_message, AddBos::Never)?;
         batch.clear();
 
         for (i, token) in user_tokens.iter().enumerate() {
             if batch.n_tokens() >= BATCH_SIZE {
-                ctx.decode(&mut batch).unwrap();
+                ctx.decode(&mut batch)?;
                 batch.clear();
             }
             let is_last = i == user_tokens.len() - 1;
-            batch.add(*token, n_past + i as i32, &[0], is_last).unwrap();
+            batch.add(*token, n_past + i as i32, &[0], is_last)?;
         }
 
         if batch.n_tokens() > 0 {
-            ctx.decode(&mut batch).unwrap();
+            ctx.decode(&mut batch)?;
         }
+        kv_tokens.truncate(n_past as usize);
+        kv_tokens.extend(&user_tokens);
         n_past += user_tokens.len() as i32;
+        reply_start = n_past;
 
-        let mut rng = rand::rng();
-
-        let mut reply = String::new();
-        let mut last_message_chunk_index = 0;
-        let mut creative_sampler = LlamaSampler::chain_simple([
-            LlamaSampler::min_p(0.05, 1),
-            LlamaSampler::temp(0.80),
-            LlamaSampler::dist(rng.next_u32()),
-        ]);
-
-        let mut deterministic_sampler = LlamaSampler::chain_simple([
-            LlamaSampler::greedy()
-        ]);
-
-        let mut decoder = encoding_rs::UTF_8.new_decoder();
-
-        let mut interrupted = false;
-        let mut is_thinking = false;
-        let mut is_calling_tools = false;
-        let mut tool_call_start_index = 0;
-
-        loop {
-            // Check for interrupt event
-            if state.read().llm_command == Some(LlmCommand::CancelInference) {
-                interrupted = true;
-                break;
-            }
-
-            if is_start_of_tool_call(reply.clone().split(" ").last().unwrap_or("")) {
-                tool_call_start_index = reply.len() -1;
-                is_calling_tools = true;
-
-            } else if is_calling_tools && is_tool_call_complete(&reply.clone().split_off(tool_call_start_index)) {
-                is_calling_tools = false;
-                tool_call_start_index = 0;
-            }
-
-            let token = if is_calling_tools {
-                let token = deterministic_sampler.sample(&ctx, batch.n_tokens() - 1);
-                deterministic_sampler.accept(token);
-                token
-            } else {
-                let token = creative_sampler.sample(&ctx, batch.n_tokens() - 1);
-                creative_sampler.accept(token);
-                token
-            };
-
-            if model.is_eog_token(token) {
-                is_calling_tools = false;
-                if let Some(ref tools) = tools
-                    && let Some((_format, tool_command)) = try_parse_tool_call(&reply)
-                {
-                    let tool_calls = split_tool_calls(&tool_command);
-
-                    // Add tool result as a message and continue inference
-                    let mut tool_response_messages = vec![];
-
-                    for call in &tool_calls {
-                        match run_tool(tools, call) {
-                            Ok(result) => {
-                                tool_response_messages.push(
-                                    LlamaChatMessage::new(tool_result_role.into(), result.clone())
-                                        .unwrap(),
-                                );
-
-                                state.update(|s| {
-                                    if let Some(snippet) = s.conversation.pop() {
-                                        s.conversation.push(ConversationSnippet {
-                                            is_tool_call: true,
-                                            ..snippet
-                                        });
-                                    }
-
-                                    s.conversation.push(ConversationSnippet {
-                                        message: result,
-                                        role: crate::state::LlmRole::Tool,
-                                        is_tool_call: false,
-                                    });
-                                });
-                            }
-                            Err(e) => {
-                                tool_response_messages.push(
-                                    LlamaChatMessage::new(
-                                        tool_result_role.into(),
-                                        format!("Error: {:?}", e),
-                                    )
-                                    .unwrap(),
-                                );
-
-                                state.update(|s| {
-                                    if let Some(snippet) = s.conversation.pop() {
-                                        s.conversation.push(ConversationSnippet {
-                                            is_tool_call: true,
-                                            ..snippet
-                                        });
-                                    }
-
-                                    s.conversation.push(ConversationSnippet {
-                                        message: format!("Error: {:?}", e),
-                                        role: crate::state::LlmRole::Tool,
-                                        is_tool_call: false,
-                                    });
-                                });
-                            }
-                        }
-                    }
-
-                    let tool_chat = model
-                        .apply_chat_template(
-                            &_chat_template,
-                            tool_response_messages.as_slice(),
-                            true,
-                        )
-                        .unwrap();
-                    let tool_tokens = model.str_to_token(&tool_chat, AddBos::Never).unwrap();
-                    batch.clear();
-
-                    for (i, token) in tool_tokens.iter().enumerate() {
-                        if batch.n_tokens() >= BATCH_SIZE {
-                            ctx.decode(&mut batch).unwrap();
-                            batch.clear();
-                        }
-                        let is_last = i == tool_tokens.len() - 1;
-                        batch.add(*token, n_past + i as i32, &[0], is_last).unwrap();
-                    }
-
-                    if batch.n_tokens() > 0 {
-                        ctx.decode(&mut batch).unwrap();
-                    }
-                    n_past += tool_tokens.len() as i32;
-
-                    reply.clear();
-
-                    continue;
-                } else {
-                    break;
-                }
-            }
-
-            if let Ok(t) = model.token_to_piece(token, &mut decoder, true, None) {
-                reply.push_str(&t);
-                if enable_word_by_word_response {
-                    if t.contains("</") {
-                        is_thinking = false;
-                        last_message_chunk_index = 0;
-                    } else if t.contains("<") {
-                        is_thinking = true;
-                    }
-                }
-
-                state.update(|s| {
-                    if let Some(snippet) = s.conversation.last()
-                        && snippet.role == LlmRole::Assistant
-                    {
-                        s.conversation.pop();
-                    }
-
-                    s.conversation.push(ConversationSnippet {
-                        role: crate::state::LlmRole::Assistant,
-                        message: reply.clone(),
-                        is_tool_call: false,
-                    });
-
-                    if enable_word_by_word_response && end_sentence.is_match(&t) && !is_thinking {
-                        let sentence = &reply[last_message_chunk_index..];
-                        last_message_chunk_index = reply.len();
-                        s.tts_commands.push(sentence.into());
-                    }
-                });
+        state.update(|s| {
+            if let Some(timings) = s.turn_timings.as_mut() {
+                timings.prompt_decoded = Some(Instant::now());
             }
+        });
 
-            batch.clear();
-            batch.add(token, n_past, &[0], true).unwrap();
-            ctx.decode(&mut batch).unwrap();
-            n_past += 1;
+        if !is_regenerating {
+            attempt = 0;
         }
 
-        if interrupted {
+        let mut tokens = LlamaTokens {
+            model: &model,
+            ctx: &mut ctx,
+            batch: &mut batch,
+            kv_tokens: &mut kv_tokens,
+            n_past,
+            round_start: n_past,
+            draft: draft.as_mut(),
+            chat_template: &_chat_template,
+            tool_result_role,
+            tool_grammar: &tool_grammar,
+            creative_sampler: reply_sampler(&model, &sampling, attempt),
+            deterministic_sampler: tool_call_sampler(&model, None),
+            decoder: encoding_rs::UTF_8.new_decoder(),
+            is_calling_tools: false,
+            speculated: VecDeque::new(),
+        };
+        let reply = reply::generate_reply(
+            &state,
+            &mut tokens,
+            &ReplyOptions {
+                tools: tools.as_ref(),
+                tool_limits: &tool_limits,
+                sampling: &sampling,
+                template_format,
+                enable_word_by_word_response,
+                record_decoded_text,
+            },
+        )?;
+        n_past = tokens.finish();
+        let mut first_sentence_queued_at = reply.first_sentence_queued_at;
+
+        if reply.interrupted {
+            debug!("inference interrupted, rolling back to {}", n_past_before);
             // Roll back KV cache to state before this inference
-            let _ = ctx.clear_kv_cache_seq(
-                None,
-                Some((n_past_before - 1) as u32),
-                Some(ctx.kv_cache_seq_pos_max(0) as u32),
-            );
+            let _ = ctx.clear_kv_cache_seq(None, Some(n_past_before as u32), None);
+            n_past = n_past_before;
             exchange_checkpoints.pop();
+            exchange_indices.pop();
 
             state.update(|s| {
                 loop {
@@ -434,12 +733,23 @@ fn run_llm_loop(
 
                 s.system_mute = false;
                 s.llm_state = LlmState::AwaitingInput;
+                s.turn_timings = None;
             });
 
+            kv_path = state.read().conversation.to_vec();
             continue;
         }
 
         state.update(|s| {
+            if record_decoded_text {
+                s.decoded_text.push(reply.text.clone());
+            }
+
+            if is_regenerating && keep_alternative_replies {
+                s.reply_alternatives.push(last_exchange(&s.conversation));
+                s.selected_alternative = s.reply_alternatives.len() - 1;
+            }
+
             if s.is_only_responding_after_name {
                 s.time_since_name_was_said = Some(Instant::now());
             }
@@ -447,7 +757,8 @@ fn run_llm_loop(
             if s.life_cycle_state != LifeCycleState::ShuttingDown {
                 if !enable_word_by_word_response {
                     s.llm_state = LlmState::InitializingTts;
-                    s.tts_commands.push(reply);
+                    s.tts_commands.push(reply.text);
+                    first_sentence_queued_at.get_or_insert_with(Instant::now);
                 } else {
                     s.llm_state = LlmState::AwaitingInput;
                     s.system_mute = false
@@ -455,7 +766,526 @@ fn run_llm_loop(
             } else {
                 s.llm_state = LlmState::AwaitingInput;
             }
+
+            if let Some(timings) = s.turn_timings.as_mut() {
+                timings.first_token = reply.first_token_at;
+                timings.first_sentence_queued = first_sentence_queued_at;
+                timings.generated_tokens = reply.tokens;
+                timings.generation_done = Some(Instant::now());
+            }
+
+            // Nothing to speak, otherwise the tts thread finishes the turn once it's all been said
+            if first_sentence_queued_at.is_none() {
+                metrics::complete_turn(s);
+            }
         });
+
+        kv_path = state.read().conversation.to_vec();
+    }
+    Ok(())
+}
+
+/// Tokens sampled from the model for a reply, with the draft model guessing ahead when there
+/// is one
+struct LlamaTokens<'a, 'm> {
+    model: &'a LlamaModel,
+    ctx: &'a mut LlamaContext<'m>,
+    batch: &'a mut LlamaBatch<'m>,
+    kv_tokens: &'a mut Vec<LlamaToken>,
+    n_past: i32,
+    /// Where the reply of this tool round starts in the KV cache
+    round_start: i32,
+    draft: Option<&'a mut Draft<'m>>,
+    chat_template: &'a LlamaChatTemplate,
+    tool_result_role: &'a str,
+    tool_grammar: &'a dyn Fn(ToolFormat) -> Option<String>,
+    creative_sampler: LlamaSampler,
+    deterministic_sampler: LlamaSampler,
+    decoder: encoding_rs::Decoder,
+    is_calling_tools: bool,
+    /// Tokens the draft model guessed right, and whether they're already in the KV cache
+    speculated: VecDeque<(LlamaToken, bool)>,
+}
+
+impl LlamaTokens<'_, '_> {
+    /// Where the KV cache ends once the reply is done
+    fn finish(self) -> i32 {
+        // Stopped before using every guess, so drop the rest from the KV cache
+        if !self.speculated.is_empty() {
+            let _ = self
+                .ctx
+                .clear_kv_cache_seq(Some(0), Some(self.n_past as u32), None);
+        }
+
+        self.n_past
+    }
+}
+
+impl TokenSource for LlamaTokens<'_, '_> {
+    fn next_piece(&mut self) -> anyhow::Result<Option<String>> {
+        let (token, is_decoded) = if let Some(next) = self.speculated.pop_front() {
+            next
+        } else if self.is_calling_tools {
+            let token = self
+                .deterministic_sampler
+                .sample(self.ctx, self.batch.n_tokens() - 1);
+            self.deterministic_sampler.accept(token);
+            (token, false)
+        } else if let Some(draft) = self.draft.as_deref_mut() {
+            self.speculated = speculative::speculate(
+                draft,
+                self.model,
+                self.ctx,
+                self.batch,
+                &mut self.creative_sampler,
+                self.kv_tokens,
+                self.n_past,
+            )?;
+            self.speculated.pop_front().unwrap()
+        } else {
+            let token = self
+                .creative_sampler
+                .sample(self.ctx, self.batch.n_tokens() - 1);
+            self.creative_sampler.accept(token);
+            (token, false)
+        };
+
+        if self.model.is_eog_token(token) {
+            return Ok(None);
+        }
+
+        if !is_decoded {
+            self.batch.clear();
+            self.batch.add(token, self.n_past, &[0], true)?;
+            self.ctx.decode(self.batch)?;
+        }
+        self.kv_tokens.truncate(self.n_past as usize);
+        self.kv_tokens.push(token);
+        self.n_past += 1;
+
+        // Part of a character comes out empty, the rest of it comes with the next token
+        Ok(Some(
+            self.model
+                .token_to_piece(token, &mut self.decoder, true, None)
+                .unwrap_or_default(),
+        ))
+    }
+
+    fn start_tool_call(&mut self, format: ToolFormat, restart_round: bool) -> anyhow::Result<()> {
+        self.is_calling_tools = true;
+        // A fresh grammar for every call, it keeps track of how far into the call it is
+        self.deterministic_sampler =
+            tool_call_sampler(self.model, (self.tool_grammar)(format).as_deref());
+
+        if restart_round {
+            self.speculated.clear();
+            speculative::discard(self.ctx, self.batch, self.kv_tokens, self.round_start)?;
+            self.kv_tokens.truncate(self.round_start as usize);
+            self.n_past = self.round_start;
+        } else if !self.speculated.is_empty() {
+            // Guesses after the start of the call weren't held to the grammar
+            self.speculated.clear();
+            speculative::discard(self.ctx, self.batch, self.kv_tokens, self.n_past)?;
+        }
+
+        Ok(())
+    }
+
+    fn end_tool_call(&mut self) {
+        self.is_calling_tools = false;
+    }
+
+    fn read_tool_results(&mut self, results: &[String]) -> anyhow::Result<String> {
+        // Guesses past the end of the round go, the results take their place
+        self.speculated.clear();
+        let _ = self
+            .ctx
+            .clear_kv_cache_seq(Some(0), Some(self.n_past as u32), None);
+
+        let messages = results
+            .iter()
+            .map(|result| LlamaChatMessage::new(self.tool_result_role.into(), result.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let tool_chat = self
+            .model
+            .apply_chat_template(self.chat_template, &messages, true)?;
+
+        decode_text(
+            self.model,
+            self.ctx,
+            self.batch,
+            &tool_chat,
+            &mut self.n_past,
+            self.kv_tokens,
+        )?;
+        self.round_start = self.n_past;
+
+        Ok(tool_chat)
+    }
+
+    fn cut_off(&mut self, reply: &str) -> anyhow::Result<()> {
+        // The KV cache still has the stop string in it and no end to the turn, so roll the round
+        // back to what was kept of the reply and end it there
+        self.speculated.clear();
+        let _ = self
+            .ctx
+            .clear_kv_cache_seq(Some(0), Some(self.round_start as u32), None);
+        self.n_past = self.round_start;
+        decode_text(
+            self.model,
+            self.ctx,
+            self.batch,
+            reply,
+            &mut self.n_past,
+            self.kv_tokens,
+        )?;
+
+        let end_of_turn = self.model.token_eos();
+        self.batch.clear();
+        self.batch.add(end_of_turn, self.n_past, &[0], true)?;
+        self.ctx.decode(self.batch)?;
+        self.kv_tokens.truncate(self.n_past as usize);
+        self.kv_tokens.push(end_of_turn);
+        self.n_past += 1;
+
+        Ok(())
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model
+            .str_to_token(text, AddBos::Never)
+            .map(|tokens| tokens.len())
+            .unwrap_or(text.len())
+    }
+}
+
+/// Room left in the context for the summary of a recovered session
+const SUMMARY_TOKENS: usize = 512;
+
+/// Summarises a conversation recovered from the journal into the memory file. Only as much of
+/// the end of it as fits in the context is used.
+fn summarise_recovered(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    chat_template: &LlamaChatTemplate,
+    conversation: &[ConversationSnippet],
+    conversation_file: &str,
+) -> anyhow::Result<()> {
+    let messages = |transcript: &str| -> anyhow::Result<Vec<LlamaChatMessage>> {
+        Ok(vec![
+            LlamaChatMessage::new(
+                "system".into(),
+                "You summarize conversation transcripts".into(),
+            )?,
+            LlamaChatMessage::new(
+                "user".into(),
+                format!("{}\n\n{}", transcript, SUMMARY_PROMPT),
+            )?,
+        ])
+    };
+    let count_tokens = |text: &str| {
+        model
+            .str_to_token(text, AddBos::Never)
+            .map(|tokens| tokens.len())
+            .unwrap_or(text.len())
+    };
+
+    let scaffolding =
+        count_tokens(&model.apply_chat_template(chat_template, &messages("")?, true)?);
+    let budget = (ctx.n_ctx() as usize).saturating_sub(scaffolding + SUMMARY_TOKENS);
+    let transcript = journal::transcript(conversation, budget, count_tokens);
+
+    if transcript.is_empty() {
+        anyhow::bail!("none of the conversation fits in the context");
+    }
+
+    let summary = generate_once(model, ctx, chat_template, &messages(&transcript)?)?;
+
+    let think_tags = Regex::new(r"(<think>[\s\S]*?<\/think>)*")?;
+    memory::append_summary(
+        conversation_file,
+        think_tags.replace_all(&summary, "").trim(),
+    )
+}
+
+/// Runs a one off prompt greedily from an empty context and returns the whole reply
+fn generate_once(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    chat_template: &LlamaChatTemplate,
+    messages: &[LlamaChatMessage],
+) -> anyhow::Result<String> {
+    ctx.clear_kv_cache();
+
+    let prompt = model.apply_chat_template(chat_template, messages, true)?;
+    let tokens = model.str_to_token(&prompt, AddBos::Always)?;
+    if tokens.len() >= ctx.n_ctx() as usize {
+        anyhow::bail!(
+            "the prompt is {} tokens, which doesn't fit in the context",
+            tokens.len()
+        );
+    }
+
+    let mut batch = LlamaBatch::new(BATCH_SIZE as usize, 1);
+    let mut n_past = 0;
+
+    for chunk in tokens.chunks(BATCH_SIZE as usize) {
+        batch.clear();
+        for token in chunk {
+            batch.add(*token, n_past, &[0], n_past as usize == tokens.len() - 1)?;
+            n_past += 1;
+        }
+        ctx.decode(&mut batch)?;
+    }
+
+    let mut sampler = LlamaSampler::greedy();
+    let mut decoder = encoding_rs::UTF_8.new_decoder();
+    let mut reply = String::new();
+
+    while (n_past as u32) < ctx.n_ctx() {
+        let token = sampler.sample(ctx, batch.n_tokens() - 1);
+        sampler.accept(token);
+
+        if model.is_eog_token(token) {
+            break;
+        }
+
+        if let Ok(piece) = model.token_to_piece(token, &mut decoder, true, None) {
+            reply.push_str(&piece);
+        }
+
+        batch.clear();
+        batch.add(token, n_past, &[0], true)?;
+        ctx.decode(&mut batch)?;
+        n_past += 1;
+    }
+
+    ctx.clear_kv_cache();
+    Ok(reply)
+}
+
+/// The configured seed for the first reply to a message and a different one made from it for
+/// every regeneration, so regenerating doesn't give the same reply again. Random without one.
+fn reply_seed(configured: Option<u32>, attempt: u32) -> u32 {
+    match configured {
+        Some(seed) => seed ^ attempt,
+        None => rand::rng().next_u32(),
+    }
+}
+
+/// Sampler chain for ordinary replies, built from the sampling config. `attempt` is how many
+/// times the reply has been regenerated.
+fn reply_sampler(model: &LlamaModel, config: &SamplingConfig, attempt: u32) -> LlamaSampler {
+    let seed = reply_seed(config.seed, attempt);
+    let mut samplers = Vec::new();
+
+    if config.repeat_penalty.is_some()
+        || config.presence_penalty.is_some()
+        || config.frequency_penalty.is_some()
+    {
+        samplers.push(LlamaSampler::penalties(
+            config.penalty_last_n,
+            config.repeat_penalty.unwrap_or(1.0),
+            config.frequency_penalty.unwrap_or(0.0),
+            config.presence_penalty.unwrap_or(0.0),
+        ));
+    }
+
+    if let Some(multiplier) = config.dry_multiplier {
+        samplers.push(LlamaSampler::dry(
+            model,
+            multiplier,
+            config.dry_base,
+            config.dry_allowed_length,
+            config.dry_penalty_last_n,
+            ["\n", ":", "\"", "*"],
+        ));
+    }
+
+    match config.mirostat {
+        Some(1) => {
+            samplers.push(LlamaSampler::temp(config.temperature));
+            samplers.push(LlamaSampler::mirostat(
+                model.n_vocab(),
+                seed,
+                config.mirostat_tau,
+                config.mirostat_eta,
+                100,
+            ));
+        }
+        Some(2) => {
+            samplers.push(LlamaSampler::temp(config.temperature));
+            samplers.push(LlamaSampler::mirostat_v2(
+                seed,
+                config.mirostat_tau,
+                config.mirostat_eta,
+            ));
+        }
+        _ => {
+            if let Some(top_k) = config.top_k {
+                samplers.push(LlamaSampler::top_k(top_k));
+            }
+            if let Some(typical_p) = config.typical_p {
+                samplers.push(LlamaSampler::typical(typical_p, 1));
+            }
+            if let Some(top_p) = config.top_p {
+                samplers.push(LlamaSampler::top_p(top_p, 1));
+            }
+            if let Some(min_p) = config.min_p {
+                samplers.push(LlamaSampler::min_p(min_p, 1));
+            }
+
+            if config.temperature <= 0.0 {
+                samplers.push(LlamaSampler::greedy());
+            } else {
+                samplers.push(LlamaSampler::temp(config.temperature));
+                samplers.push(LlamaSampler::dist(seed));
+            }
+        }
     }
+
+    LlamaSampler::chain_simple(samplers)
+}
+
+/// Greedy sampling for tool calls, held to the tool grammar when there is one
+fn tool_call_sampler(model: &LlamaModel, grammar: Option<&str>) -> LlamaSampler {
+    if let Some(grammar) = grammar
+        && let Ok(grammar_sampler) = LlamaSampler::grammar(model, grammar, "root")
+    {
+        LlamaSampler::chain_simple([grammar_sampler, LlamaSampler::greedy()])
+    } else {
+        LlamaSampler::chain_simple([LlamaSampler::greedy()])
+    }
+}
+
+/// Everything after the last user message on the active branch, the tool calls, their results
+/// and the reply
+fn last_exchange(conversation: &ConversationTree) -> Vec<ConversationSnippet> {
+    let start = (0..conversation.len())
+        .rev()
+        .find(|&index| conversation[index].role == LlmRole::User)
+        .map_or(0, |index| index + 1);
+
+    conversation.iter().skip(start).cloned().collect()
+}
+
+/// Takes the last exchange off the active branch so it can be generated again. Returns what was
+/// taken off and the user message it answered, if the branch ends on one once it's gone.
+fn take_last_exchange(
+    conversation: &mut ConversationTree,
+) -> (Vec<ConversationSnippet>, Option<String>) {
+    let previous = last_exchange(conversation);
+    for _ in 0..previous.len() {
+        conversation.pop();
+    }
+
+    let message = conversation
+        .last()
+        .filter(|snippet| snippet.role == LlmRole::User)
+        .map(|snippet| snippet.message.clone());
+
+    (previous, message)
+}
+
+/// A snippet as it goes in the KV cache. Replies are decoded as they were generated,
+/// everything else goes through the chat template.
+fn replay_text(
+    model: &LlamaModel,
+    chat_template: &LlamaChatTemplate,
+    tool_result_role: &str,
+    snippet: &ConversationSnippet,
+) -> anyhow::Result<String> {
+    let role = match snippet.role {
+        LlmRole::User => "user",
+        LlmRole::Tool => tool_result_role,
+        LlmRole::Assistant => return Ok(snippet.message.clone()),
+    };
+
+    Ok(model.apply_chat_template(
+        chat_template,
+        &[LlamaChatMessage::new(role.into(), snippet.message.clone())?],
+        true,
+    )?)
+}
+
+/// Decodes raw text onto the end of the KV cache
+fn decode_text(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    text: &str,
+    n_past: &mut i32,
+    kv_tokens: &mut Vec<LlamaToken>,
+) -> anyhow::Result<()> {
+    let tokens = model.str_to_token(text, AddBos::Never)?;
+    debug!(
+        tokens = tokens.len(),
+        n_past = *n_past,
+        "decoding {:?}",
+        text
+    );
+    batch.clear();
+
+    for (i, token) in tokens.iter().enumerate() {
+        if batch.n_tokens() >= BATCH_SIZE {
+            ctx.decode(batch)?;
+            batch.clear();
+        }
+        let is_last = i == tokens.len() - 1;
+        batch.add(*token, *n_past + i as i32, &[0], is_last)?;
+    }
+
+    if batch.n_tokens() > 0 {
+        ctx.decode(batch)?;
+    }
+    kv_tokens.truncate(*n_past as usize);
+    kv_tokens.extend(&tokens);
+    *n_past += tokens.len() as i32;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::snippet;
+
+    #[test]
+    fn regenerating_changes_a_fixed_seed() {
+        assert_eq!(reply_seed(Some(1234), 0), 1234);
+        assert_eq!(reply_seed(Some(1234), 0), reply_seed(Some(1234), 0));
+        assert_ne!(reply_seed(Some(1234), 1), 1234);
+        assert_ne!(reply_seed(Some(1234), 1), reply_seed(Some(1234), 2));
+    }
+
+    #[test]
+    fn regenerating_after_a_branch_switch_asks_the_branch_switched_to() {
+        let mut conversation = ConversationTree::default();
+        conversation.push(snippet(LlmRole::User, "What's the weather like?"));
+        conversation.push(snippet(LlmRole::Assistant, "Sunny."));
+        conversation.fork(0, snippet(LlmRole::User, "Tell me a joke."));
+        conversation.push(snippet(LlmRole::Assistant, "Knock knock."));
+        assert!(conversation.switch_branch(0, false));
+
+        let (previous, message) = take_last_exchange(&mut conversation);
+        assert_eq!(previous, [snippet(LlmRole::Assistant, "Sunny.")]);
+        assert_eq!(message.as_deref(), Some("What's the weather like?"));
+        assert_eq!(conversation.len(), 1);
+    }
+
+    #[test]
+    fn regenerating_after_an_interrupted_turn_asks_the_last_answered_message() {
+        let mut conversation = ConversationTree::default();
+        conversation.push(snippet(LlmRole::User, "Hi"));
+        conversation.push(snippet(LlmRole::Assistant, "Hello."));
+        conversation.push(snippet(LlmRole::User, "Tell me a long story."));
+        conversation.push(snippet(LlmRole::Assistant, "Once upon"));
+        // As the interrupted turn leaves it
+        conversation.pop();
+        conversation.pop();
+
+        let (previous, message) = take_last_exchange(&mut conversation);
+        assert_eq!(previous, [snippet(LlmRole::Assistant, "Hello.")]);
+        assert_eq!(message.as_deref(), Some("Hi"));
+    }
+}
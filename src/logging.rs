@@ -0,0 +1,76 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+
+use tracing::{debug, error};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+
+use crate::state::StateHandle;
+
+const LOG_FILE_PREFIX: &str = "local-ml";
+const MAX_LOG_FILES: usize = 7;
+
+/// Sends logs from every thread (and from llama.cpp and whisper) to a log file that rotates daily.
+/// Nothing is written to the terminal so the raw mode UI stays intact.
+/// Logs are only flushed while the returned guard is alive.
+pub fn init(directory: &str, filter: &str) -> anyhow::Result<WorkerGuard> {
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(directory)?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_thread_names(true)
+        .with_env_filter(EnvFilter::try_new(filter)?)
+        .init();
+
+    llama_cpp_2::send_logs_to_tracing(llama_cpp_2::LogOptions::default());
+
+    // Panics would otherwise print over the UI, or nowhere at all once it's cleared
+    panic::set_hook(Box::new(|info| {
+        let thread = thread::current();
+        error!(
+            thread = thread.name().unwrap_or("unnamed"),
+            "panicked: {}", info
+        );
+    }));
+
+    Ok(guard)
+}
+
+/// A worker's thread, which joins to whether the worker failed
+pub type WorkerHandle = JoinHandle<bool>;
+
+/// Spawns a named thread for `worker`. If it returns an error or panics, that gets logged
+/// and shown to the user rather than the thread silently disappearing.
+pub fn spawn_worker<F>(name: &'static str, state: StateHandle, worker: F) -> WorkerHandle
+where
+    F: FnOnce() -> anyhow::Result<()> + Send + 'static,
+{
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(move || {
+            let message = match panic::catch_unwind(AssertUnwindSafe(worker)) {
+                Ok(Ok(())) => {
+                    debug!("{} thread finished", name);
+                    return false;
+                }
+                Ok(Err(e)) => {
+                    error!("{} thread stopped: {:?}", name, e);
+                    format!("{} stopped: {}", name, e)
+                }
+                // Already logged by the panic hook
+                Err(_) => format!("{} crashed, see the log for details", name),
+            };
+
+            state.update(|s| s.worker_errors.push(message));
+            true
+        })
+        .expect("failed to spawn thread")
+}
@@ -1,24 +1,54 @@
 mod audio;
+mod builtins;
+mod calculator;
 mod config;
+mod confirmation;
+mod conversation;
+mod export;
+mod grammar;
+#[cfg(test)]
+mod harness;
 mod input;
+mod journal;
+mod logging;
+mod manifest;
+mod mcp;
 mod orb;
+mod pipeline;
+mod reply;
+mod sandbox;
+mod scheduler;
 mod shutdown;
+mod speculative;
 mod state;
 mod stt;
+mod supervisor;
+#[cfg(test)]
+mod test_support;
 mod tts;
 // llm needs to be below stt
 mod llm;
+mod memory;
+mod metrics;
+mod tool_worker;
 mod tools;
 mod ui;
 mod vad;
 
-use std::{fs, time::Instant};
+use std::fs;
+use std::path::Path;
 
+use export::Exporter;
+use llm::{Llama, LlmOptions};
 use stt::Stt;
+use tts::Piper;
 
 use crate::{
+    conversation::ConversationTree,
+    memory::RetrievalConfig,
     shutdown::save_conversation,
-    state::{ConversationSnippet, LlmCommand, LlmState, StateHandle},
+    state::{LlmCommand, StateHandle},
+    supervisor::{Policy, Supervisor},
 };
 
 fn load_previous_summary(conversation_file: &str) -> Option<String> {
@@ -30,20 +60,79 @@ fn load_previous_summary(conversation_file: &str) -> Option<String> {
     None
 }
 
+/// `--resume` picks up the last session, `--resume <file>` a particular one
+fn resume_argument() -> Option<Option<String>> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|arg| arg == "--resume")?;
+
+    Some(
+        args.get(position + 1)
+            .filter(|arg| !arg.starts_with("--"))
+            .cloned(),
+    )
+}
+
+/// The newest session saved for an assistant. Sessions are named by when they started,
+/// so that's the last one alphabetically.
+fn latest_session(session_directory: &str, prefix: &str) -> Option<String> {
+    let prefix = format!("{}_", prefix);
+
+    fs::read_dir(session_directory)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+                && path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+        })
+        .max()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
 fn main() -> anyhow::Result<()> {
     // Load assistant config and select
     let config = config::load_config()?;
+    let _log_guard = logging::init(&config.global.log_directory, &config.global.log_filter)?;
     let selected = config::select_assistant(&config)?;
+    tracing::info!(assistant = %selected.name, "starting");
     let conversation_file = selected.conversation_file();
+    let journal_file = selected.journal_file();
+
+    // A journal left behind means the last session never got to save its memories
+    let recovered = journal::offer_recovery(&journal_file)?;
+
+    let retrieval = config
+        .global
+        .embedding_model_path
+        .clone()
+        .map(|embedding_model_path| RetrievalConfig {
+            embedding_model_path,
+            conversation_file: conversation_file.clone(),
+            index_file: selected.memory_index_file(),
+            top_k: config.global.memory_top_k,
+            token_budget: config.global.memory_token_budget,
+        });
 
     let mut system_prompt = format!(
-        "Your name is {}. {}. Here is a summary of previous interactions with the user:\n",
+        "Your name is {}. {}.",
         selected.name.clone(),
         selected.system_prompt.clone()
     );
 
-    if let Some(summary) = load_previous_summary(&conversation_file) {
-        system_prompt.push_str(&summary);
+    if retrieval.is_some() {
+        // Memories are retrieved per turn instead of living in the system prompt
+        system_prompt.push_str(
+            " Relevant memories of previous interactions with the user will be provided as the conversation goes on.",
+        );
+    } else {
+        system_prompt.push_str(" Here is a summary of previous interactions with the user:\n");
+
+        if let Some(summary) = load_previous_summary(&conversation_file) {
+            system_prompt.push_str(&summary);
+        }
     }
 
     let whisper_model_path = config.global.whisper_model_path;
@@ -62,8 +151,23 @@ fn main() -> anyhow::Result<()> {
     let llm_threads: i32 = config.global.llm_threads;
     let llm_context_size: u32 = config.global.llm_context_size;
 
+    // Compare generation speed with and without the draft model, then quit
+    if std::env::args().any(|arg| arg == "--benchmark") {
+        return speculative::run_benchmark(
+            &selected.with_defaults(Some(llm_model_path), None, None, &[]),
+            llm_threads,
+            llm_context_size,
+        );
+    }
+
     let stt = Stt::new(&whisper_model_path)?;
-    let selected = selected.with_defaults(Some(llm_model_path), None, config.global.tool_path);
+    let mut selected = selected.with_defaults(
+        Some(llm_model_path),
+        None,
+        config.global.tool_path,
+        &config.global.tool_manifests,
+    );
+    selected.system_prompt = system_prompt;
 
     ui::status_stt_online();
 
@@ -76,102 +180,164 @@ fn main() -> anyhow::Result<()> {
     let state_for_llm = state.clone();
     let state_for_tts = state.clone();
     let state_for_vad = state.clone();
+    let state_for_journal = state.clone();
+    let state_for_metrics = state.clone();
+    let state_for_scheduler = state.clone();
+    let state_for_supervisor = state.clone();
+    state.update(|s| s.memories_in_system_prompt = retrieval.is_none());
+
+    let session_prefix = selected.name.to_lowercase().replace(' ', "_");
+    let resumed_file = match resume_argument() {
+        Some(Some(file)) => Some(file),
+        Some(None) => Some(
+            latest_session(&config.global.session_directory, &session_prefix)
+                .ok_or_else(|| anyhow::anyhow!("There's no session to resume"))?,
+        ),
+        None => None,
+    };
+
+    // Resuming carries on in the same session file
+    let (session, session_file) = match &resumed_file {
+        Some(file) => (
+            Path::new(file)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy())
+                .unwrap_or_default()
+                .trim_start_matches(&format!("{}_", session_prefix))
+                .to_string(),
+            file.clone(),
+        ),
+        None => {
+            let session = chrono::offset::Local::now()
+                .format("%Y%m%d-%H%M%S")
+                .to_string();
+            let session_file = format!(
+                "{}/{}_{}.json",
+                config.global.session_directory, session_prefix, session
+            );
+            (session, session_file)
+        }
+    };
+
+    if let Some(file) = &resumed_file {
+        let conversation = ConversationTree::load(file)?;
+        tracing::info!(file = %file, snippets = conversation.len(), "resuming session");
+
+        // The llm decodes it all once it's loaded
+        state.update(|s| {
+            s.resumed_snippets = conversation.len();
+            s.conversation = conversation;
+            s.llm_command = Some(LlmCommand::RebuildBranch);
+        });
+    }
+
+    let exporter = Exporter {
+        directory: config.global.export_directory.clone(),
+        formats: config.global.export_formats.clone(),
+        include_debug: config.global.export_include_debug,
+        assistant_name: selected.name.clone(),
+        system_prompt: selected.system_prompt.clone(),
+        session,
+    };
 
-    let _ = input::spawn_input_thread(state_for_input);
+    let mut supervisor = Supervisor::new(state_for_supervisor);
 
-    let _ = if config.global.orb_mode {
-        (
-            Some(orb::spawn_orb_thread(
-                state_for_ui,
-                selected.orb_colour.unwrap(),
-            )),
-            None,
+    let input_conversation_file = conversation_file.clone();
+    supervisor.restartable("input", move || {
+        input::spawn_input_thread(
+            state_for_input.clone(),
+            input_conversation_file.clone(),
+            exporter.clone(),
         )
+        .handle
+    });
+
+    if config.global.orb_mode {
+        let orb_colour = selected.orb_colour.unwrap();
+        supervisor.restartable("orb", move || {
+            orb::spawn_orb_thread(state_for_ui.clone(), orb_colour).handle
+        });
     } else {
-        (
-            None,
-            Some(ui::spawn_ui_thread(
-                state_for_ui,
-                selected.name.clone(),
-                config.global.enable_word_by_word_response,
-            )),
-        )
-    };
+        let model_name = selected.name.clone();
+        let enable_word_by_word_response = config.global.enable_word_by_word_response;
+        supervisor.restartable("ui", move || {
+            ui::spawn_ui_thread(
+                state_for_ui.clone(),
+                model_name.clone(),
+                enable_word_by_word_response,
+            )
+            .handle
+        });
+    }
 
-    let _ = llm::spawn_llm_thread(
+    let llm = llm::spawn_llm_thread(
         state_for_llm,
-        selected.clone(),
-        llm_threads,
-        llm_context_size,
-        config.global.enable_word_by_word_response,
+        Llama {
+            assistant: selected.clone(),
+            options: LlmOptions {
+                threads: llm_threads,
+                context_size: llm_context_size,
+                enable_word_by_word_response: config.global.enable_word_by_word_response,
+                retrieval: retrieval.clone(),
+                recovered,
+                record_decoded_text: config.global.export_include_debug,
+                keep_alternative_replies: config.global.keep_alternative_replies,
+                sampling: selected
+                    .sampling
+                    .clone()
+                    .unwrap_or_else(|| config.global.sampling.clone()),
+                tool_limits: config.global.tool_limits.clone(),
+                tool_sandbox: config.global.tool_sandbox.clone(),
+                tool_permissions: config.global.tool_permissions.clone(),
+            },
+        },
     );
-    let _ = tts::spawn_tts_thread(state_for_tts, piper_model_path);
+    supervisor.watch("llm", llm.handle, Policy::Critical);
+
+    let journal = journal::spawn_journal_thread(
+        state_for_journal,
+        journal_file.clone(),
+        session_file.clone(),
+    );
+    supervisor.watch("journal", journal.handle, Policy::Report);
+
+    let metrics =
+        metrics::spawn_metrics_thread(state_for_metrics, config.global.metrics_file.clone());
+    supervisor.watch("metrics", metrics.handle, Policy::Report);
+
+    supervisor.restartable("tts", move || {
+        tts::spawn_tts_thread(state_for_tts.clone(), Piper::new(piper_model_path.clone())).handle
+    });
+
+    let timers_file = selected.timers_file();
+    supervisor.restartable("scheduler", move || {
+        scheduler::spawn_scheduler_thread(state_for_scheduler.clone(), timers_file.clone()).handle
+    });
+
+    let _ = supervisor.spawn();
 
     let (audio, stream, source_rate) = audio::start_mic(state_for_audio);
 
     vad::run_vad(state_for_vad, audio, source_rate, |utterance| {
-        if let Ok(text) = stt.transcribe(&utterance) {
-            if text.trim().is_empty() || text.trim() == "[BLANK_AUDIO]" {
-                return;
-            }
-
-            let current_state = state.read();
-
-            if current_state.is_only_responding_after_name
-                && match current_state.time_since_name_was_said {
-                    None => true,
-                    Some(instant) => instant.elapsed().as_secs() > 5,
-                }
-            {
-                let words = text.split(" ");
-
-                if words.clone().count() <= 3
-                    && words.clone().any(|word| {
-                        selected.name.eq_ignore_ascii_case(
-                            &word
-                                .chars()
-                                .filter(|c| c.is_alphabetic())
-                                .collect::<String>(),
-                        )
-                    })
-                {
-                    state.update(|s| {
-                        s.time_since_name_was_said = Some(Instant::now());
-                        s.tts_commands.push("Yes?".into());
-                    });
-
-                    return;
-                }
-
-                if !words.take(5).any(|word| {
-                    selected.name.eq_ignore_ascii_case(
-                        &word
-                            .chars()
-                            .filter(|c| c.is_alphabetic())
-                            .collect::<String>(),
-                    )
-                }) {
-                    return;
-                }
-            }
-
-            state.update(|s| {
-                s.time_since_name_was_said = None;
-                s.system_mute = true;
-                s.conversation.push(ConversationSnippet {
-                    role: state::LlmRole::User,
-                    message: text.clone(),
-                    is_tool_call: false,
-                });
-                s.llm_state = LlmState::RunningInference;
-                s.llm_command = Some(LlmCommand::ContinueConversation(text.trim().into()));
-            });
-        }
+        pipeline::handle_utterance(&state, &stt, &selected.name, &conversation_file, &utterance);
     });
 
-    save_conversation(state, &conversation_file)?;
+    // Keep every branch of the conversation before the summary gets added to it
+    let conversation = state.read().conversation;
+    if !conversation.is_empty() {
+        conversation.save(&session_file)?;
+    }
+
+    // Summarising needs the llm, so if it died leave the journal to be recovered next time
+    if state.read().stopped_workers.contains(&"llm") {
+        ui::status_memories_kept_in_journal();
+    } else {
+        save_conversation(state, &conversation_file, retrieval.is_none())?;
+        journal::clear(&journal_file);
+    }
 
     ui::restore_cursor();
+    tracing::info!("shut down");
 
     #[allow(unused_must_use)]
     std::mem::ManuallyDrop::into_inner(stream);
@@ -0,0 +1,476 @@
+//! Tools that are programs rather than python functions, declared in a TOML or JSON manifest:
+//!
+//! ```toml
+//! [[tools]]
+//! name = "disk_usage"
+//! description = "How much space a directory takes up"
+//! command = ["du", "-sh", "{path}"]
+//! arguments = "argv"
+//! parameters = { type = "object", properties = { path = { type = "string" } }, required = ["path"] }
+//! ```
+//!
+//! Each call starts the program fresh, in the same sandbox the python tools get.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, anyhow, bail};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::config::SandboxPolicy;
+use crate::sandbox;
+use crate::tools::{Tool, ToolResult};
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    tools: Vec<ManifestTool>,
+}
+
+#[derive(Deserialize)]
+struct ManifestTool {
+    name: String,
+    #[serde(default)]
+    description: String,
+    /// The program and its arguments. With `argv`, `{name}` is swapped for that argument
+    command: Vec<String>,
+    #[serde(default)]
+    arguments: ArgumentPassing,
+    /// A JSON schema for an object, one property per argument
+    #[serde(default)]
+    parameters: Option<Value>,
+}
+
+/// How the llm's arguments reach the program
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum ArgumentPassing {
+    /// As one JSON object on stdin
+    #[default]
+    Stdin,
+    /// On the command line, in place of `{name}`, or as `--name value` when `command` doesn't say where
+    Argv,
+}
+
+/// How to run one tool from a manifest
+#[derive(Debug, Clone)]
+pub struct ExecutableTool {
+    name: String,
+    command: Vec<String>,
+    arguments: ArgumentPassing,
+    /// Schemas for each argument, for checking what the llm passes in
+    properties: HashMap<String, Value>,
+    /// Where the manifest is, which relative programs and the working directory go by
+    directory: PathBuf,
+}
+
+/// Reads the tools in a manifest, TOML unless it ends in `.json`
+pub fn load(path: &Path) -> anyhow::Result<Vec<(Tool, ExecutableTool)>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    let manifest: Manifest = if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        serde_json::from_str(&text)?
+    } else {
+        toml::from_str(&text)?
+    };
+
+    let directory = path
+        .canonicalize()?
+        .parent()
+        .unwrap_or(Path::new("/"))
+        .to_path_buf();
+
+    manifest
+        .tools
+        .into_iter()
+        .map(|tool| describe(tool, &directory))
+        .collect()
+}
+
+fn describe(tool: ManifestTool, directory: &Path) -> anyhow::Result<(Tool, ExecutableTool)> {
+    if tool.command.is_empty() {
+        bail!("{} has an empty command", tool.name);
+    }
+
+    let parameters = tool.parameters.unwrap_or(Value::Null);
+    let schemas: HashMap<String, Value> = parameters
+        .get("properties")
+        .and_then(Value::as_object)
+        .map(|properties| {
+            properties
+                .iter()
+                .map(|(name, schema)| (name.clone(), schema.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok((
+        Tool::from_schema(&tool.name, &tool.description, &parameters),
+        ExecutableTool {
+            name: tool.name,
+            command: tool.command,
+            arguments: tool.arguments,
+            properties: schemas,
+            directory: directory.to_path_buf(),
+        },
+    ))
+}
+
+impl ExecutableTool {
+    /// Runs the program once with `arguments` and waits for it, up to the policy's timeout or
+    /// `deadline`, whichever comes first
+    pub fn run(
+        &self,
+        arguments: &serde_json::Map<String, Value>,
+        policy: &SandboxPolicy,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<ToolResult> {
+        for (name, value) in arguments {
+            if let Some(schema) = self.properties.get(name) {
+                check(value, schema, name).map_err(|e| anyhow!("Invalid arguments: {}", e))?;
+            }
+        }
+
+        let argv = match self.arguments {
+            ArgumentPassing::Stdin => self.command.clone(),
+            ArgumentPassing::Argv => fill_in(&self.command, arguments, &self.properties),
+        };
+
+        // `./script.sh` is next to the manifest, `ls` is on the PATH
+        let program = match Path::new(&argv[0]) {
+            path if path.is_relative() && argv[0].contains('/') => self.directory.join(path),
+            path => path.to_path_buf(),
+        };
+        let working_directory = match &policy.working_directory {
+            Some(directory) => PathBuf::from(directory),
+            None => self.directory.clone(),
+        };
+
+        let program_name = program.to_string_lossy();
+        let mut command = sandbox::one_off_command(&program_name, policy, &working_directory)?;
+        command
+            .args(&argv[1..])
+            .stdin(match self.arguments {
+                ArgumentPassing::Stdin => Stdio::piped(),
+                ArgumentPassing::Argv => Stdio::null(),
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow!(sandbox::describe_spawn_error(&argv[0], policy, &e)))?;
+        info!(tool = %self.name, ?argv, "running tool");
+
+        // Read and written as it goes, since a full pipe would stall the program or us
+        let read_all = |mut pipe: Box<dyn Read + Send>| {
+            std::thread::spawn(move || {
+                let mut bytes = Vec::new();
+                let _ = pipe.read_to_end(&mut bytes);
+                String::from_utf8_lossy(&bytes).into_owned()
+            })
+        };
+        let stdout = read_all(Box::new(child.stdout.take().context("tool has no stdout")?));
+        let stderr = read_all(Box::new(child.stderr.take().context("tool has no stderr")?));
+        if let Some(mut stdin) = child.stdin.take() {
+            let input = Value::Object(arguments.clone()).to_string();
+            // A program that doesn't read its input closes the pipe early, which is fine
+            std::thread::spawn(move || writeln!(stdin, "{}", input));
+        }
+
+        let timeout = policy.timeout(deadline);
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!(
+                    "{} took longer than {}s and was stopped",
+                    self.name,
+                    timeout.as_secs_f32().round()
+                );
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        let error = match status.code() {
+            Some(_) => None,
+            None => Some(
+                sandbox::describe_signal(policy, &self.name, Some(status))
+                    .unwrap_or_else(|| format!("{} was stopped ({})", self.name, status)),
+            ),
+        };
+        if let Some(error) = &error {
+            warn!(tool = %self.name, "{}", error);
+        }
+
+        Ok(ToolResult {
+            stdout,
+            stderr,
+            exit_code: Some(exit_code(status)),
+            value: None,
+            error,
+            truncated: false,
+        })
+    }
+}
+
+/// Swaps `{name}` in the command for the arguments. One that's a whole entry can be a list, which
+/// becomes one entry per item, and entries for arguments that weren't given are left out.
+/// Arguments the command doesn't mention go on the end as `--name value`.
+fn fill_in(
+    command: &[String],
+    arguments: &serde_json::Map<String, Value>,
+    parameters: &HashMap<String, Value>,
+) -> Vec<String> {
+    let names: HashSet<&String> = parameters.keys().chain(arguments.keys()).collect();
+    let placeholder = |name: &str| format!("{{{}}}", name);
+
+    let mut argv = vec![command[0].clone()];
+    let mut mentioned = HashSet::new();
+
+    for entry in &command[1..] {
+        let here: Vec<&String> = names
+            .iter()
+            .copied()
+            .filter(|name| entry.contains(&placeholder(name)))
+            .collect();
+        mentioned.extend(here.iter().copied());
+
+        if here.is_empty() {
+            argv.push(entry.clone());
+            continue;
+        }
+        if here
+            .iter()
+            .any(|name| arguments.get(*name).is_none_or(Value::is_null))
+        {
+            continue;
+        }
+
+        if let [name] = here[..]
+            && *entry == placeholder(name)
+            && let Some(Value::Array(items)) = arguments.get(name)
+        {
+            argv.extend(items.iter().map(plain));
+            continue;
+        }
+
+        let mut filled = entry.clone();
+        for name in here {
+            filled = filled.replace(&placeholder(name), &plain(&arguments[name.as_str()]));
+        }
+        argv.push(filled);
+    }
+
+    for (name, value) in arguments {
+        if mentioned.contains(name) {
+            continue;
+        }
+
+        match value {
+            Value::Bool(true) => argv.push(format!("--{}", name)),
+            Value::Bool(false) | Value::Null => {}
+            Value::Array(items) => {
+                for item in items {
+                    argv.push(format!("--{}", name));
+                    argv.push(plain(item));
+                }
+            }
+            value => {
+                argv.push(format!("--{}", name));
+                argv.push(plain(value));
+            }
+        }
+    }
+
+    argv
+}
+
+/// Strings as they are, anything else as JSON
+fn plain(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Checks a value against the parts of a JSON schema an llm is likely to get wrong
+fn check(value: &Value, schema: &Value, name: &str) -> Result<(), String> {
+    if let Some(options) = schema.get("enum").and_then(Value::as_array)
+        && !options.contains(value)
+    {
+        let options: Vec<String> = options.iter().map(Value::to_string).collect();
+        return Err(format!(
+            "{} should be one of {}, not {}",
+            name,
+            options.join(", "),
+            value
+        ));
+    }
+
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(kind)) => vec![kind.as_str()],
+        Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !types.is_empty() && !types.iter().any(|kind| is_type(value, kind)) {
+        return Err(format!(
+            "{} should be {}, not {}",
+            name,
+            types.join(" or "),
+            value
+        ));
+    }
+
+    match value {
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(item, item_schema, &format!("{}[{}]", name, i))?;
+                }
+            }
+        }
+        Value::Object(members) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (key, member) in members {
+                if let Some(member_schema) = properties.and_then(|properties| properties.get(key)) {
+                    check(member, member_schema, &format!("{}.{}", name, key))?;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn is_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+/// The exit code, or 128 plus the signal like a shell would say for a program that was killed
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    status.code().unwrap_or(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ToolSandbox;
+    use crate::test_support::temp_file;
+    use crate::tools::{load_tools, parse_call, run_tool};
+
+    fn write_manifest(extension: &str, source: &str) -> PathBuf {
+        temp_file("manifest", extension, source)
+    }
+
+    #[test]
+    fn fills_in_placeholders_and_flags() {
+        let command: Vec<String> = ["grep", "{pattern}", "--max-count={limit}", "{files}"]
+            .map(String::from)
+            .to_vec();
+        let parameters = HashMap::from(
+            ["pattern", "limit", "files", "ignore_case"]
+                .map(|name| (name.to_string(), Value::Null)),
+        );
+        let arguments = serde_json::json!({
+            "pattern": "a b",
+            "files": ["x.txt", "y.txt"],
+            "ignore_case": true,
+        });
+
+        assert_eq!(
+            fill_in(&command, arguments.as_object().unwrap(), &parameters),
+            ["grep", "a b", "x.txt", "y.txt", "--ignore_case"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_programs_from_toml_and_json_manifests() {
+        let toml = write_manifest(
+            "toml",
+            r#"
+[[tools]]
+name = "echo_twice"
+description = "Says it twice"
+command = ["sh", "-c", "echo \"$1 $1\"; echo done >&2; exit 3", "sh", "{text}"]
+arguments = "argv"
+parameters = { type = "object", properties = { text = { type = "string" } }, required = ["text"] }
+"#,
+        );
+        let json = write_manifest(
+            "json",
+            r#"{"tools": [{
+                "name": "read_input",
+                "command": ["cat"],
+                "parameters": {
+                    "type": "object",
+                    "properties": {"size": {"type": "string", "enum": ["small", "large"]}},
+                    "required": ["size"]
+                }
+            }]}"#,
+        );
+        let manifests = [&toml, &json].map(|path| path.to_string_lossy().into_owned());
+        let tools = load_tools(None, &manifests, &[], None, &ToolSandbox::default()).unwrap();
+
+        let run = |call: &str| run_tool(&tools, &parse_call(call).unwrap(), None);
+        let echoed = run(r#"echo_twice(text="hi there")"#).unwrap();
+        let read = run(r#"read_input(size="small")"#).unwrap();
+        let wrong = run(r#"read_input(size="medium")"#);
+        let _ = fs::remove_file(&toml);
+        let _ = fs::remove_file(&json);
+
+        assert_eq!(tools.tools.len(), 2);
+        assert_eq!(tools.tools[0].required, vec!["text"]);
+
+        assert_eq!(echoed.stdout, "hi there hi there\n");
+        assert_eq!(echoed.stderr, "done\n");
+        assert_eq!(echoed.exit_code, Some(3));
+
+        let input: Value = serde_json::from_str(&read.stdout).unwrap();
+        assert_eq!(input, serde_json::json!({"size": "small"}));
+        assert_eq!(read.exit_code, Some(0));
+
+        assert!(
+            wrong
+                .unwrap_err()
+                .to_string()
+                .contains("size should be one of \"small\", \"large\"")
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//! A Model Context Protocol client, so tools on MCP servers can be used like the python ones.
+//! Servers are started as a process of their own and talked to over stdio, one JSON-RPC message
+//! per line like the tool worker.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Instant;
+
+use anyhow::{Context, anyhow, bail};
+use serde_json::{Value, json};
+use tracing::{debug, info, warn};
+
+use crate::config::{McpServer, SandboxPolicy};
+use crate::sandbox;
+use crate::tools::{Tool, ToolResult};
+
+const PROTOCOL_VERSION: &str = "2025-06-18";
+const METHOD_NOT_FOUND: i64 = -32601;
+
+pub struct McpClient {
+    server: McpServer,
+    policy: SandboxPolicy,
+    child: Child,
+    stdin: ChildStdin,
+    messages: mpsc::Receiver<String>,
+    next_id: u64,
+}
+
+impl McpClient {
+    /// Starts the server and goes through the initialize handshake with it
+    pub fn start(server: &McpServer, policy: &SandboxPolicy) -> anyhow::Result<Self> {
+        let (program, arguments) = server
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow!("the MCP server {} has an empty command", server.name))?;
+        let working_directory = match &policy.working_directory {
+            Some(directory) => PathBuf::from(directory),
+            None => std::env::current_dir()?,
+        };
+
+        let mut command = sandbox::command(program, policy, &working_directory)?;
+        command
+            .args(arguments)
+            .envs(&server.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow!(sandbox::describe_spawn_error(program, policy, &e)))?;
+
+        let stdin = child.stdin.take().context("MCP server has no stdin")?;
+        let stdout = child.stdout.take().context("MCP server has no stdout")?;
+        let stderr = child.stderr.take().context("MCP server has no stderr")?;
+
+        let (messages_tx, messages) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if messages_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Servers log to stderr, which would otherwise fill up the pipe
+        let name = server.name.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                debug!("{}: {}", name, line);
+            }
+        });
+
+        let mut client = McpClient {
+            server: server.clone(),
+            policy: policy.clone(),
+            child,
+            stdin,
+            messages,
+            next_id: 0,
+        };
+
+        let initialized = client.request(
+            "initialize",
+            json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            }),
+            None,
+        )?;
+        client.send(json!({"jsonrpc": "2.0", "method": "notifications/initialized"}))?;
+
+        info!(
+            server = %server.name,
+            protocol_version = ?initialized.get("protocolVersion"),
+            server_info = ?initialized.get("serverInfo"),
+            "connected to MCP server"
+        );
+
+        Ok(client)
+    }
+
+    /// Every tool the server has, going through all its pages
+    pub fn list_tools(&mut self) -> anyhow::Result<Vec<Tool>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let page = self.request("tools/list", params, None)?;
+
+            for tool in page
+                .get("tools")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+            {
+                let Some(name) = tool.get("name").and_then(Value::as_str) else {
+                    continue;
+                };
+                let description = tool
+                    .get("description")
+                    .and_then(Value::as_str)
+                    .unwrap_or("");
+
+                tools.push(Tool::from_schema(
+                    name,
+                    description,
+                    tool.get("inputSchema").unwrap_or(&Value::Null),
+                ));
+            }
+
+            cursor = page
+                .get("nextCursor")
+                .and_then(Value::as_str)
+                .map(String::from);
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+
+    /// Calls a tool on the server, starting it again first if it stopped since the last call.
+    /// It's cancelled if it isn't done by `deadline`.
+    pub fn call(
+        &mut self,
+        tool: &str,
+        arguments: &serde_json::Map<String, Value>,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<ToolResult> {
+        if let Ok(Some(status)) = self.child.try_wait() {
+            warn!(server = %self.server.name, %status, "MCP server stopped, starting it again");
+            *self = McpClient::start(&self.server, &self.policy)?;
+        }
+
+        let result = self.request(
+            "tools/call",
+            json!({ "name": tool, "arguments": arguments }),
+            deadline,
+        )?;
+        Ok(to_tool_result(&result))
+    }
+
+    /// Sends a request and waits for its response. Anything the server asks of us in the meantime
+    /// gets answered, and a request that takes too long is cancelled rather than the server stopped.
+    fn request(
+        &mut self,
+        method: &str,
+        params: Value,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+
+        let timeout = self.policy.timeout(deadline);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = match self
+                .messages
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = self.send(json!({
+                        "jsonrpc": "2.0",
+                        "method": "notifications/cancelled",
+                        "params": { "requestId": id, "reason": "timed out" },
+                    }));
+                    bail!(
+                        "{} didn't answer {} within {}s",
+                        self.server.name,
+                        method,
+                        timeout.as_secs_f32().round()
+                    );
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let status = self.stop();
+                    bail!(self.describe_stop(method, status));
+                }
+            };
+
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                debug!(%line, "ignoring MCP server output that isn't JSON");
+                continue;
+            };
+
+            // Requests and notifications from the server have a method, responses don't
+            if let Some(server_method) = message.get("method").and_then(Value::as_str) {
+                if let Some(request_id) = message.get("id") {
+                    self.answer(request_id, server_method)?;
+                }
+                continue;
+            }
+
+            if message.get("id").and_then(Value::as_u64) != Some(id) {
+                continue;
+            }
+
+            if let Some(error) = message.get("error") {
+                bail!(
+                    "{} couldn't do {}: {}",
+                    self.server.name,
+                    method,
+                    error
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or("unknown error")
+                );
+            }
+
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    /// Pings get a pong, nothing else a server might ask for is supported
+    fn answer(&mut self, id: &Value, method: &str) -> anyhow::Result<()> {
+        if method == "ping" {
+            return self.send(json!({ "jsonrpc": "2.0", "id": id, "result": {} }));
+        }
+
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": METHOD_NOT_FOUND, "message": format!("{} isn't supported", method) },
+        }))
+    }
+
+    fn send(&mut self, message: Value) -> anyhow::Result<()> {
+        if let Err(e) = writeln!(self.stdin, "{}", message).and_then(|_| self.stdin.flush()) {
+            self.stop();
+            bail!("Couldn't reach the MCP server {}: {}", self.server.name, e);
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Option<ExitStatus> {
+        let _ = self.child.kill();
+        self.child.wait().ok()
+    }
+
+    fn describe_stop(&self, method: &str, status: Option<ExitStatus>) -> String {
+        if let Some(limit) = sandbox::describe_signal(&self.policy, &self.server.name, status) {
+            return limit;
+        }
+
+        match status {
+            Some(status) => format!(
+                "The MCP server {} stopped during {} ({})",
+                self.server.name, method, status
+            ),
+            None => format!(
+                "The MCP server {} stopped during {}",
+                self.server.name, method
+            ),
+        }
+    }
+}
+
+impl Drop for McpClient {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Text content becomes the output, or the error when the server says the tool failed. Structured
+/// content is supposed to be in the text too, so it's only kept as the value when there's no text.
+fn to_tool_result(result: &Value) -> ToolResult {
+    let text = result
+        .get("content")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(content_text)
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let is_error = result
+        .get("isError")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    let value = result
+        .get("structuredContent")
+        .filter(|_| text.is_empty())
+        .cloned();
+
+    if is_error {
+        ToolResult {
+            exit_code: Some(1),
+            value,
+            error: Some(text),
+            ..ToolResult::default()
+        }
+    } else {
+        ToolResult {
+            stdout: text,
+            exit_code: Some(0),
+            value,
+            ..ToolResult::default()
+        }
+    }
+}
+
+/// What the llm gets to read of one piece of content
+fn content_text(content: &Value) -> String {
+    let text = match content.get("type").and_then(Value::as_str) {
+        Some("text") => content.get("text"),
+        Some("resource") => content
+            .pointer("/resource/text")
+            .or_else(|| content.pointer("/resource/uri")),
+        Some("resource_link") => content.get("uri"),
+        // The llm can't look at images or listen to audio
+        Some(kind) => return format!("[{} left out]", kind),
+        None => None,
+    };
+
+    text.and_then(Value::as_str).unwrap_or("").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const SERVER_FIXTURE: &str = "tests/fixtures/mcp_server.py";
+
+    fn fixture_server() -> McpServer {
+        McpServer {
+            name: "fixture".into(),
+            command: vec!["python".into(), SERVER_FIXTURE.into()],
+            env: HashMap::from([("FIXTURE_GREETING".into(), "hello".into())]),
+        }
+    }
+
+    #[test]
+    fn lists_and_calls_tools_on_a_server() {
+        let mut client = McpClient::start(&fixture_server(), &SandboxPolicy::default()).unwrap();
+
+        let tools = client.list_tools().unwrap();
+        let names: Vec<&str> = tools.iter().map(|tool| tool.name.as_str()).collect();
+        assert_eq!(names, ["add", "greet", "fail"]);
+        assert_eq!(tools[0].required, vec!["a", "b"]);
+        assert_eq!(
+            tools[0].properties["a"],
+            ("number".into(), "The first number".into())
+        );
+
+        let mut arguments = serde_json::Map::new();
+        arguments.insert("a".into(), 2.into());
+        arguments.insert("b".into(), 3.into());
+        let added = client.call("add", &arguments, None).unwrap();
+        assert_eq!(added.stdout, "5");
+        assert_eq!(added.exit_code, Some(0));
+
+        let no_arguments = serde_json::Map::new();
+        let greeted = client.call("greet", &no_arguments, None).unwrap();
+        assert_eq!(greeted.value, Some(json!({ "greeting": "hello" })));
+
+        let failed = client.call("fail", &no_arguments, None).unwrap();
+        assert_eq!(failed.error.as_deref(), Some("it broke"));
+        assert_eq!(failed.exit_code, Some(1));
+
+        let unknown = client.call("missing", &no_arguments, None);
+        assert!(unknown.unwrap_err().to_string().contains("no tool called"));
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::LazyLock;
+
+use llama_cpp_2::{
+    context::LlamaContext,
+    llama_batch::LlamaBatch,
+    model::{AddBos, LlamaModel},
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::confirmation;
+use crate::state::StateHandle;
+
+pub const EMBEDDING_CONTEXT_SIZE: u32 = 512;
+
+#[derive(Debug, Clone)]
+pub struct RetrievalConfig {
+    pub embedding_model_path: String,
+    pub conversation_file: String,
+    pub index_file: String,
+    pub top_k: usize,
+    pub token_budget: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryItem {
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+/// Vector index over the lines of the conversation file.
+/// The conversation file stays the source of truth, this only caches an embedding per line.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MemoryIndex {
+    pub items: Vec<MemoryItem>,
+}
+
+impl MemoryIndex {
+    pub fn load(path: &str) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Brings the index in line with `lines`, embedding anything that isn't cached yet.
+    /// Returns true if the index changed and should be saved.
+    pub fn sync(
+        &mut self,
+        lines: &[String],
+        mut embed: impl FnMut(&str) -> anyhow::Result<Vec<f32>>,
+    ) -> anyhow::Result<bool> {
+        let previous_len = self.items.len();
+        let mut cached: HashMap<String, Vec<f32>> = self
+            .items
+            .drain(..)
+            .map(|item| (item.text, item.embedding))
+            .collect();
+
+        let mut changed = false;
+
+        for line in lines {
+            let embedding = match cached.remove(line) {
+                Some(embedding) => embedding,
+                None => {
+                    changed = true;
+                    embed(line)?
+                }
+            };
+
+            self.items.push(MemoryItem {
+                text: line.clone(),
+                embedding,
+            });
+        }
+
+        Ok(changed || self.items.len() != previous_len)
+    }
+
+    /// Returns the `top_k` items most similar to `query`, best first
+    pub fn search(&self, query: &[f32], top_k: usize) -> Vec<&MemoryItem> {
+        let mut scored: Vec<(f32, &MemoryItem)> = self
+            .items
+            .iter()
+            .map(|item| (dot(query, &item.embedding), item))
+            .collect();
+
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(top_k)
+            .map(|(_, item)| item)
+            .collect()
+    }
+}
+
+/// Splits a summary into individual memories, one per non-empty line
+pub fn memory_lines(summary: &str) -> Vec<String> {
+    summary
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.into())
+        .collect()
+}
+
+// Embeddings are normalised so this is the cosine similarity
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+pub fn embed(ctx: &mut LlamaContext, model: &LlamaModel, text: &str) -> anyhow::Result<Vec<f32>> {
+    let mut tokens = model.str_to_token(text, AddBos::Always)?;
+    tokens.truncate(EMBEDDING_CONTEXT_SIZE as usize);
+
+    let mut batch = LlamaBatch::new(tokens.len().max(1), 1);
+    batch.add_sequence(&tokens, 0, false)?;
+
+    ctx.clear_kv_cache();
+    ctx.decode(&mut batch)?;
+
+    let embedding = ctx.embeddings_seq_ith(0)?;
+    let norm = embedding
+        .iter()
+        .map(|v| v * v)
+        .sum::<f32>()
+        .sqrt()
+        .max(f32::EPSILON);
+
+    Ok(embedding.iter().map(|v| v / norm).collect())
+}
+
+/// Builds the memory block injected before a user turn, keeping to `token_budget` tokens
+pub fn retrieve_memories(
+    index: &MemoryIndex,
+    query_embedding: &[f32],
+    top_k: usize,
+    token_budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Option<String> {
+    let mut block = String::from("Relevant memories about the user:");
+    let mut used = count_tokens(&block);
+    let mut included = 0;
+
+    for item in index.search(query_embedding, top_k) {
+        let line = format!("\n{}", item.text);
+        let cost = count_tokens(&line);
+
+        if used + cost > token_budget {
+            break;
+        }
+
+        used += cost;
+        included += 1;
+        block.push_str(&line);
+    }
+
+    if included == 0 { None } else { Some(block) }
+}
+
+pub enum MemoryIntent {
+    Forget(String),
+    Recall,
+}
+
+static RECALL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bwhat do you (remember|know) about me\b").unwrap());
+// Only at the start, so "don't forget to buy milk" or "I always forget my keys" aren't commands
+static FORGET: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(?:please )?forget (?:that |about |what I (?:said|told you) about )(.+)")
+        .unwrap()
+});
+
+/// Picks out requests about memory that should be handled here rather than by the llm.
+/// `text` is what was said after the assistant's name, if it was said at all.
+pub fn parse_memory_intent(text: &str) -> Option<MemoryIntent> {
+    if RECALL.is_match(text) {
+        return Some(MemoryIntent::Recall);
+    }
+
+    FORGET.captures(text.trim()).map(|cap| {
+        MemoryIntent::Forget(
+            cap[1]
+                .trim()
+                .trim_end_matches(|c: char| !c.is_alphanumeric())
+                .into(),
+        )
+    })
+}
+
+pub fn load_memories(conversation_file: &str) -> Vec<String> {
+    memory_lines(&fs::read_to_string(conversation_file).unwrap_or_default())
+}
+
+pub fn save_memories(conversation_file: &str, memories: &[String]) -> anyhow::Result<()> {
+    fs::write(conversation_file, memories.join("\n"))?;
+    Ok(())
+}
+
+/// Adds a freshly generated summary to the end of the conversation file
+pub fn append_summary(conversation_file: &str, summary: &str) -> anyhow::Result<()> {
+    let existing = fs::read_to_string(conversation_file).unwrap_or_default();
+
+    let memories = if existing.trim().is_empty() {
+        summary.to_string()
+    } else {
+        format!("{}\n{}", existing.trim(), summary)
+    };
+
+    fs::write(conversation_file, memories)?;
+    Ok(())
+}
+
+/// Strips list markers so a memory reads naturally when spoken
+pub fn strip_bullet(memory: &str) -> &str {
+    memory
+        .trim_start_matches(|c: char| c == '-' || c == '*' || c == '•' || c.is_whitespace())
+        .trim()
+}
+
+/// Every memory that has at least half of the subject's keywords as whole words
+pub fn matching_memories(memories: &[String], subject: &str) -> Vec<String> {
+    const STOP_WORDS: [&str; 16] = [
+        "i", "me", "my", "mine", "the", "a", "an", "that", "about", "is", "am", "was", "and", "to",
+        "of", "it",
+    ];
+
+    let keywords: HashSet<String> = words(subject)
+        .into_iter()
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect();
+
+    if keywords.is_empty() {
+        return Vec::new();
+    }
+
+    memories
+        .iter()
+        .filter(|memory| {
+            let words = words(memory);
+            let matches = keywords
+                .iter()
+                .filter(|keyword| words.contains(*keyword))
+                .count();
+
+            matches * 2 >= keywords.len()
+        })
+        .cloned()
+        .collect()
+}
+
+/// Lowercased words with the punctuation taken out
+fn words(text: &str) -> HashSet<String> {
+    text.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Answers a memory request directly from the conversation file and speaks the result.
+/// Forgetting reads back what would go and waits for a yes before deleting anything.
+pub fn handle_memory_intent(state: &StateHandle, intent: MemoryIntent, conversation_file: &str) {
+    let memories = load_memories(conversation_file);
+
+    let replies: Vec<String> = match intent {
+        MemoryIntent::Recall => {
+            if memories.is_empty() {
+                vec!["I don't remember anything about you yet.".into()]
+            } else {
+                std::iter::once("Here's what I remember.")
+                    .chain(memories.iter().map(|memory| strip_bullet(memory)))
+                    .map(|line| line.into())
+                    .collect()
+            }
+        }
+        MemoryIntent::Forget(subject) => {
+            let matching = matching_memories(&memories, &subject);
+
+            if matching.is_empty() {
+                vec!["I don't remember anything like that.".into()]
+            } else {
+                let (intro, question) = if matching.len() == 1 {
+                    ("I remember this.", "Should I forget it?")
+                } else {
+                    ("I remember these.", "Should I forget all of them?")
+                };
+
+                let replies = std::iter::once(intro)
+                    .chain(matching.iter().map(|memory| strip_bullet(memory)))
+                    .chain(std::iter::once(question))
+                    .map(|line| line.into())
+                    .collect();
+
+                state.update(|s| s.pending_forget = Some(matching));
+                replies
+            }
+        }
+    };
+
+    state.update(|s| {
+        s.system_mute = true;
+        s.tts_commands.extend(replies);
+    });
+}
+
+/// Deletes the memories waiting to be forgotten, or keeps them if the user said no
+pub fn answer_forget(state: &StateHandle, approved: bool, conversation_file: &str) {
+    let mut pending = None;
+    state.update(|s| pending = s.pending_forget.take());
+    let Some(pending) = pending else {
+        return;
+    };
+
+    let reply = if approved {
+        let mut memories = load_memories(conversation_file);
+        memories.retain(|memory| !pending.contains(memory));
+        match save_memories(conversation_file, &memories) {
+            Ok(()) if state.read_with(|s| s.memories_in_system_prompt) => {
+                "Okay, I'll have forgotten that from the next time I'm started."
+            }
+            Ok(()) => "Okay, I've forgotten that.",
+            Err(e) => {
+                tracing::warn!("couldn't forget memories: {:?}", e);
+                "Sorry, I couldn't forget that."
+            }
+        }
+    } else {
+        "Okay, I'll keep remembering it."
+    };
+
+    state.update(|s| {
+        s.system_mute = true;
+        s.tts_commands.push(reply.into());
+    });
+}
+
+/// Answers the question about forgetting with what the user said, asking again if it was neither
+pub fn answer_forget_by_voice(state: &StateHandle, text: &str, conversation_file: &str) {
+    match confirmation::parse_answer(text) {
+        Some(approved) => answer_forget(state, approved, conversation_file),
+        None => state.update(|s| {
+            s.system_mute = true;
+            s.tts_commands.push("Sorry, was that a yes or a no?".into());
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memories() -> Vec<String> {
+        vec![
+            "- Likes tea".into(),
+            "- Has a cat called Miso".into(),
+            "- Went to a party on Friday".into(),
+            "- Keeps monkeys".into(),
+        ]
+    }
+
+    #[test]
+    fn forgetting_has_to_be_asked_for() {
+        assert!(matches!(
+            parse_memory_intent("Forget about my cat Miso."),
+            Some(MemoryIntent::Forget(subject)) if subject == "my cat Miso"
+        ));
+        assert!(matches!(
+            parse_memory_intent("forget that I like tea"),
+            Some(MemoryIntent::Forget(subject)) if subject == "I like tea"
+        ));
+        assert!(parse_memory_intent("Don't forget to buy milk").is_none());
+        assert!(parse_memory_intent("I always forget my keys").is_none());
+        assert!(parse_memory_intent("Forget it").is_none());
+    }
+
+    #[test]
+    fn memories_match_on_whole_words() {
+        assert_eq!(
+            matching_memories(&memories(), "my cat Miso"),
+            vec!["- Has a cat called Miso".to_string()]
+        );
+        assert!(matching_memories(&memories(), "art").is_empty());
+        assert!(matching_memories(&memories(), "my keys").is_empty());
+        assert_eq!(
+            matching_memories(&memories(), "the party"),
+            vec!["- Went to a party on Friday".to_string()]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::logging::{self, WorkerHandle};
+use crate::state::{LifeCycleState, State, StateHandle, unix_timestamp};
+
+/// When each stage of the current turn finished, from the end of speech to the last audio played
+#[derive(Clone, Debug, PartialEq)]
+pub struct TurnTimings {
+    /// The end of speech for voice turns, or when the llm picked up a typed message
+    pub started: Instant,
+    pub stt_done: Option<Instant>,
+    /// Length of the utterance, for the STT real-time factor
+    pub audio_seconds: f32,
+    pub prompt_decoded: Option<Instant>,
+    pub first_token: Option<Instant>,
+    pub first_sentence_queued: Option<Instant>,
+    pub first_audio: Option<Instant>,
+    pub generation_done: Option<Instant>,
+    pub generated_tokens: usize,
+    pub turn_complete: Option<Instant>,
+}
+
+impl TurnTimings {
+    pub fn new(started: Instant) -> Self {
+        TurnTimings {
+            started,
+            stt_done: None,
+            audio_seconds: 0.0,
+            prompt_decoded: None,
+            first_token: None,
+            first_sentence_queued: None,
+            first_audio: None,
+            generation_done: None,
+            generated_tokens: 0,
+            turn_complete: None,
+        }
+    }
+
+    /// Timings for a voice turn whose speech ended at `vad_endpoint`
+    pub fn from_speech(vad_endpoint: Instant, stt_done: Instant, audio_seconds: f32) -> Self {
+        TurnTimings {
+            stt_done: Some(stt_done),
+            audio_seconds,
+            ..TurnTimings::new(vad_endpoint)
+        }
+    }
+
+    fn millis_since_start(&self, instant: Option<Instant>) -> Option<u64> {
+        instant.map(|instant| instant.duration_since(self.started).as_millis() as u64)
+    }
+
+    pub fn metrics(&self) -> TurnMetrics {
+        let stt_seconds = self
+            .stt_done
+            .map(|stt_done| stt_done.duration_since(self.started).as_secs_f32());
+
+        let tokens_per_second = match (self.first_token, self.generation_done) {
+            (Some(first_token), Some(generation_done)) if self.generated_tokens > 1 => {
+                let seconds = generation_done.duration_since(first_token).as_secs_f32();
+                Some((self.generated_tokens - 1) as f32 / seconds.max(f32::EPSILON))
+            }
+            _ => None,
+        };
+
+        TurnMetrics {
+            timestamp: unix_timestamp(),
+            stt_ms: self.millis_since_start(self.stt_done),
+            stt_real_time_factor: stt_seconds
+                .filter(|_| self.audio_seconds > 0.0)
+                .map(|seconds| seconds / self.audio_seconds),
+            prompt_decoded_ms: self.millis_since_start(self.prompt_decoded),
+            first_token_ms: self.millis_since_start(self.first_token),
+            first_sentence_queued_ms: self.millis_since_start(self.first_sentence_queued),
+            time_to_first_audio_ms: self.millis_since_start(self.first_audio),
+            turn_complete_ms: self.millis_since_start(self.turn_complete),
+            generated_tokens: self.generated_tokens,
+            tokens_per_second,
+        }
+    }
+}
+
+/// Per turn numbers for the status line and the metrics log. Every `_ms` is since `started`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TurnMetrics {
+    pub timestamp: u64,
+    pub stt_ms: Option<u64>,
+    pub stt_real_time_factor: Option<f32>,
+    pub prompt_decoded_ms: Option<u64>,
+    pub first_token_ms: Option<u64>,
+    pub first_sentence_queued_ms: Option<u64>,
+    pub time_to_first_audio_ms: Option<u64>,
+    pub turn_complete_ms: Option<u64>,
+    pub generated_tokens: usize,
+    pub tokens_per_second: Option<f32>,
+}
+
+/// Marks the current turn as finished once the reply has been generated and spoken
+pub fn complete_turn(s: &mut State) {
+    let Some(timings) = s.turn_timings.as_mut() else {
+        return;
+    };
+
+    if timings.turn_complete.is_some() || timings.generation_done.is_none() {
+        return;
+    }
+
+    timings.turn_complete = Some(Instant::now());
+    s.last_turn_metrics = Some(timings.metrics());
+}
+
+pub struct MetricsHandle {
+    pub handle: WorkerHandle,
+}
+
+/// Appends the metrics of every finished turn to `metrics_file` as a line of JSON
+pub fn spawn_metrics_thread(state: StateHandle, metrics_file: String) -> MetricsHandle {
+    let handle = logging::spawn_worker("metrics", state.clone(), move || {
+        run_metrics_loop(state, metrics_file)
+    });
+
+    MetricsHandle { handle }
+}
+
+fn run_metrics_loop(state: StateHandle, metrics_file: String) -> anyhow::Result<()> {
+    let mut written: Option<TurnMetrics> = None;
+
+    let events = state.subscribe();
+    while events.wait() {
+        let current_state = state.read();
+
+        if let Some(metrics) = current_state.last_turn_metrics
+            && written.as_ref() != Some(&metrics)
+        {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&metrics_file)?;
+            writeln!(file, "{}", serde_json::to_string(&metrics)?)?;
+
+            written = Some(metrics);
+        }
+
+        if current_state.life_cycle_state == LifeCycleState::ShuttingDown {
+            break;
+        }
+    }
+
+    Ok(())
+}
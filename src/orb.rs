@@ -4,12 +4,13 @@ use crossterm::{
     terminal,
 };
 use rand::Rng;
+use std::f32::consts::PI;
 use std::io::{Write, stdout};
 use std::thread;
 use std::time::Duration;
-use std::{f32::consts::PI, thread::JoinHandle};
 
-use crate::state::{LifeCycleState, LlmState, StateHandle};
+use crate::logging::{self, WorkerHandle};
+use crate::state::{Event, LifeCycleState, LlmState, StateHandle};
 
 const FPS: u64 = 30;
 
@@ -20,15 +21,14 @@ struct Point3D {
 }
 
 pub struct OrbHandle {
-    _handle: JoinHandle<()>,
+    pub handle: WorkerHandle,
 }
 
 pub fn spawn_orb_thread(state: StateHandle, base_colour: u32) -> OrbHandle {
-    let handle = thread::spawn(move || {
-        let _ = summon_orb(state, base_colour);
-    });
+    let handle =
+        logging::spawn_worker("orb", state.clone(), move || summon_orb(state, base_colour));
 
-    OrbHandle { _handle: handle }
+    OrbHandle { handle }
 }
 
 fn create_particles() -> Vec<Point3D> {
@@ -130,8 +130,22 @@ fn summon_orb(state: StateHandle, base_colour: u32) -> anyhow::Result<()> {
     let mut speaking_modifier = 1.0_f32;
     let mut spin_multiplier = 1.0_f32;
     let mut rng = rand::rng();
+    let mut is_speaking = false;
+    let events = state.subscribe();
 
     loop {
+        for event in events.try_iter() {
+            match event {
+                Event::TtsStarted => is_speaking = true,
+                Event::TtsFinished => is_speaking = false,
+                // Jolt awake when spoken to
+                Event::UserUtterance => spin_multiplier = spin_multiplier.max(5.0),
+                // Spin up a little with every piece of the reply
+                Event::Token(piece) => spin_multiplier += piece.len() as f32 * 0.05,
+                Event::StateChanged => {}
+            }
+        }
+
         let current_state = state.read();
 
         if current_state.life_cycle_state == LifeCycleState::ShuttingDown {
@@ -147,7 +161,7 @@ fn summon_orb(state: StateHandle, base_colour: u32) -> anyhow::Result<()> {
         let mut z_buffer = vec![f32::NEG_INFINITY; width * height];
 
         // Morph factor
-        let pulse = if current_state.llm_state == LlmState::RunningTts {
+        let pulse = if is_speaking {
             speaking_modifier += rng.random_range(-0.3..0.3);
 
             if speaking_modifier > 1.5 {
@@ -224,6 +238,17 @@ fn summon_orb(state: StateHandle, base_colour: u32) -> anyhow::Result<()> {
             }
         }
 
+        if let Some(pending) = &current_state.pending_tool_call {
+            let prompt = format!("Run {}? (y/n)", pending.call);
+            let prompt_x = width.saturating_sub(prompt.chars().count()) / 2;
+            execute!(
+                stdout,
+                cursor::MoveTo(prompt_x as u16, height.saturating_sub(3) as u16),
+                SetForegroundColor(Color::Yellow),
+                Print(prompt),
+            )?;
+        }
+
         if let Some((text, pos)) = current_state.text_input {
             let text_x = (width.saturating_sub(text.len())) / 2;
             execute!(
@@ -0,0 +1,131 @@
+use std::time::Instant;
+
+use crate::confirmation;
+use crate::memory;
+use crate::metrics::TurnTimings;
+use crate::scheduler;
+use crate::state::{
+    ConversationSnippet, Event, LlmCommand, LlmRole, LlmState, StateHandle, unix_timestamp,
+};
+use crate::stt::Transcriber;
+
+/// Transcribes an utterance from the VAD and hands it to the llm,
+/// unless it's a memory command or wasn't meant for the assistant
+pub fn handle_utterance(
+    state: &StateHandle,
+    stt: &impl Transcriber,
+    assistant_name: &str,
+    conversation_file: &str,
+    utterance: &[f32],
+) {
+    let vad_endpoint = Instant::now();
+    // Utterances are resampled to 16kHz for whisper
+    let audio_seconds = utterance.len() as f32 / 16_000.0;
+
+    let Ok(text) = stt.transcribe(utterance) else {
+        return;
+    };
+    let stt_done = Instant::now();
+
+    if text.trim().is_empty() || text.trim() == "[BLANK_AUDIO]" {
+        return;
+    }
+
+    // A yes or no for a tool call doesn't need the assistant's name
+    if state.read_with(|s| s.pending_tool_call.is_some()) {
+        confirmation::answer_by_voice(state, &text);
+        return;
+    }
+
+    if state.read_with(|s| s.pending_forget.is_some()) {
+        memory::answer_forget_by_voice(state, &text, conversation_file);
+        return;
+    }
+
+    let current_state = state.read();
+
+    if current_state.is_waiting_for_name
+        || current_state.is_only_responding_after_name
+            && match current_state.time_since_name_was_said {
+                None => true,
+                Some(instant) => instant.elapsed().as_secs() > 5,
+            }
+    {
+        let is_name = |word: &str| {
+            assistant_name.eq_ignore_ascii_case(
+                &word
+                    .chars()
+                    .filter(|c| c.is_alphabetic())
+                    .collect::<String>(),
+            )
+        };
+        let words = text.split(" ");
+
+        if words.clone().count() <= 3 && words.clone().any(is_name) {
+            state.update(|s| {
+                s.time_since_name_was_said = Some(Instant::now());
+                s.is_waiting_for_name = false;
+                s.tts_commands.push("Yes?".into());
+            });
+
+            return;
+        }
+
+        if !words.take(5).any(is_name) {
+            return;
+        }
+    }
+
+    let command = after_name(&text, assistant_name);
+
+    if let Some(intent) = memory::parse_memory_intent(command) {
+        memory::handle_memory_intent(state, intent, conversation_file);
+        return;
+    }
+
+    if let Some(intent) = scheduler::parse_schedule_intent(command) {
+        scheduler::handle_schedule_intent(state, intent);
+        return;
+    }
+
+    // Published first so listeners hear about it before the llm gets going
+    state.publish(Event::UserUtterance);
+    state.update(|s| {
+        s.time_since_name_was_said = None;
+        s.is_waiting_for_name = false;
+        s.system_mute = true;
+        s.conversation.push(ConversationSnippet {
+            role: LlmRole::User,
+            message: text.clone(),
+            is_tool_call: false,
+            timestamp: unix_timestamp(),
+            tool_result: None,
+        });
+        s.llm_state = LlmState::RunningInference;
+        s.llm_command = Some(LlmCommand::ContinueConversation(text.trim().into()));
+        s.turn_timings = Some(TurnTimings::from_speech(
+            vad_endpoint,
+            stt_done,
+            audio_seconds,
+        ));
+    });
+}
+
+/// What was said after the assistant's name when it starts with it, like "Jarvis, forget that..."
+/// or "Hey Jarvis forget that...", otherwise all of it
+fn after_name<'a>(text: &'a str, assistant_name: &str) -> &'a str {
+    let text = text.trim();
+    let mut rest = text;
+
+    for _ in 0..2 {
+        let (word, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let word: String = word.chars().filter(|c| c.is_alphabetic()).collect();
+
+        if word.eq_ignore_ascii_case(assistant_name) {
+            return after.trim_start_matches(|c: char| !c.is_alphanumeric());
+        }
+        rest = after.trim_start();
+    }
+
+    text
+}
@@ -0,0 +1,559 @@
+//! One reply, tool rounds and all, whatever its tokens come from. The llm thread gets them from
+//! the model and the harness from a script, so both go through the same turn.
+
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use tracing::{info, warn};
+
+use crate::config::{SamplingConfig, ToolLimits};
+use crate::confirmation;
+use crate::grammar;
+use crate::state::{
+    ConversationSnippet, Event, LlmCommand, LlmRole, State, StateHandle, unix_timestamp,
+};
+use crate::tools::{
+    ToolCall, ToolFormat, Tools, is_tool_call_complete, tool_call_marker, try_parse_tool_call,
+};
+
+/// Where a reply's text comes from. A piece is part of what the llm has read as soon as it's
+/// handed out.
+pub trait TokenSource {
+    /// The next piece of the reply, or `None` once the llm has ended its turn
+    fn next_piece(&mut self) -> anyhow::Result<Option<String>>;
+
+    /// Holds what comes next to the grammar for calls in `format`. With `restart_round` the
+    /// round's reply so far is thrown away first, for calls with no marker to switch on after.
+    fn start_tool_call(&mut self, format: ToolFormat, restart_round: bool) -> anyhow::Result<()>;
+
+    /// Goes back to sampling freely once a call has been written
+    fn end_tool_call(&mut self);
+
+    /// Puts the results of a round of tool calls after the reply for the llm to carry on from,
+    /// and returns the text that went in
+    fn read_tool_results(&mut self, results: &[String]) -> anyhow::Result<String>;
+
+    /// Ends a reply the llm didn't get to end itself, keeping only `reply` of the last round
+    fn cut_off(&mut self, reply: &str) -> anyhow::Result<()>;
+
+    /// How many tokens `text` takes, for cutting tool results down to size
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// What a reply is held to
+pub struct ReplyOptions<'a> {
+    pub tools: Option<&'a Tools>,
+    pub tool_limits: &'a ToolLimits,
+    pub sampling: &'a SamplingConfig,
+    /// Settles which kind of XML call `<tool_call>` starts, and whether a reply starting with a
+    /// brace is a JSON call
+    pub template_format: ToolFormat,
+    pub enable_word_by_word_response: bool,
+    pub record_decoded_text: bool,
+}
+
+/// How a reply went
+pub struct Reply {
+    /// What the llm said after its last round of tool calls
+    pub text: String,
+    pub interrupted: bool,
+    /// Across every tool round
+    pub tokens: usize,
+    pub first_token_at: Option<Instant>,
+    pub first_sentence_queued_at: Option<Instant>,
+}
+
+/// Generates the reply to the conversation so far, running the tools it calls and keeping the
+/// conversation up to date as it goes. Sentences are queued for speaking as they're finished
+/// when replying word by word, otherwise the whole reply is left for the caller.
+pub fn generate_reply(
+    state: &StateHandle,
+    source: &mut impl TokenSource,
+    options: &ReplyOptions,
+) -> anyhow::Result<Reply> {
+    let &ReplyOptions {
+        tools,
+        tool_limits,
+        sampling,
+        template_format,
+        enable_word_by_word_response,
+        record_decoded_text,
+    } = options;
+    let end_sentence = Regex::new(r"[.?;:]")?;
+
+    let mut reply = String::new();
+    let mut last_message_chunk_index = 0;
+    let mut turn_tokens = 0;
+    let mut first_token_at: Option<Instant> = None;
+    let mut first_sentence_queued_at: Option<Instant> = None;
+
+    let mut interrupted = false;
+    // Stopped by a stop string, max_tokens or the turn's time running out rather than by the
+    // llm ending its turn
+    let mut cut_off = false;
+    let mut is_thinking = false;
+    let mut is_calling_tools = false;
+    let mut tool_call_start_index = 0;
+    let mut tool_call_end_index = 0;
+
+    // Pushed back while a tool call waits for the user to say yes or no
+    let mut turn_deadline = Instant::now() + Duration::from_secs(tool_limits.turn_timeout_secs);
+    let mut tool_rounds = 0;
+    let mut tool_calls_made: Vec<String> = Vec::new();
+    let mut tools_exhausted = false;
+
+    loop {
+        // Check for interrupt event
+        if state.read_with(|s| s.llm_command == Some(LlmCommand::CancelInference)) {
+            interrupted = true;
+            break;
+        }
+
+        if Instant::now() >= turn_deadline {
+            warn!(
+                "turn took over {} seconds, cutting the reply off",
+                tool_limits.turn_timeout_secs
+            );
+            cut_off = true;
+            break;
+        }
+
+        if sampling
+            .max_tokens
+            .is_some_and(|max_tokens| turn_tokens >= max_tokens)
+        {
+            cut_off = true;
+            break;
+        }
+
+        let last_word_start = reply.rfind(' ').map(|i| i + 1).unwrap_or(0);
+
+        let marker_format = if last_word_start >= tool_call_end_index {
+            tool_call_marker(&reply[last_word_start..])
+        } else {
+            None
+        };
+
+        if !is_calling_tools
+            && tools.is_some()
+            && template_format == ToolFormat::JsonStandard
+            && tool_call_end_index == 0
+            && reply.trim_start().starts_with('{')
+        {
+            // Plain JSON calls have no marker to switch the grammar on after, so the round
+            // starts over with it held to from the opening brace
+            tool_call_start_index = 0;
+            is_calling_tools = true;
+            source.start_tool_call(ToolFormat::JsonStandard, true)?;
+            reply.clear();
+        } else if !is_calling_tools && let Some(marker_format) = marker_format {
+            tool_call_start_index = last_word_start;
+            is_calling_tools = true;
+            let format = grammar::format_after_marker(marker_format, template_format);
+            source.start_tool_call(format, false)?;
+        } else if is_calling_tools && is_tool_call_complete(&reply[tool_call_start_index..]) {
+            is_calling_tools = false;
+            tool_call_end_index = reply.len();
+            source.end_tool_call();
+        }
+
+        let Some(piece) = source.next_piece()? else {
+            is_calling_tools = false;
+            source.end_tool_call();
+
+            if let Some(tools) = tools
+                && !tools_exhausted
+                && let Some((_format, tool_calls)) = try_parse_tool_call(&reply)
+            {
+                tools_exhausted = run_tool_round(
+                    state,
+                    source,
+                    tools,
+                    tool_limits,
+                    tool_calls,
+                    &mut tool_rounds,
+                    &mut tool_calls_made,
+                    &mut turn_deadline,
+                    record_decoded_text.then_some(reply.as_str()),
+                )?;
+
+                reply.clear();
+                tool_call_start_index = 0;
+                tool_call_end_index = 0;
+
+                continue;
+            }
+
+            break;
+        };
+
+        turn_tokens += 1;
+        first_token_at.get_or_insert_with(Instant::now);
+
+        if piece.is_empty() {
+            continue;
+        }
+        reply.push_str(&piece);
+
+        let stop_at = if is_calling_tools {
+            None
+        } else {
+            stop_position(&reply, &sampling.stop)
+        };
+
+        if let Some(stop_at) = stop_at {
+            reply.truncate(stop_at);
+            cut_off = true;
+            state.update(|s| {
+                if let Some(snippet) = s.conversation.last()
+                    && snippet.role == LlmRole::Assistant
+                {
+                    s.conversation.pop();
+                }
+
+                s.conversation.push(ConversationSnippet {
+                    role: LlmRole::Assistant,
+                    message: reply.clone(),
+                    is_tool_call: false,
+                    timestamp: unix_timestamp(),
+                    tool_result: None,
+                });
+            });
+            break;
+        }
+
+        if enable_word_by_word_response {
+            if piece.contains("</") {
+                is_thinking = false;
+                last_message_chunk_index = 0;
+            } else if piece.contains("<") {
+                is_thinking = true;
+            }
+        }
+
+        state.update(|s| {
+            if let Some(snippet) = s.conversation.last()
+                && snippet.role == LlmRole::Assistant
+            {
+                s.conversation.pop();
+            }
+
+            s.conversation.push(ConversationSnippet {
+                role: LlmRole::Assistant,
+                message: reply.clone(),
+                is_tool_call: false,
+                timestamp: unix_timestamp(),
+                tool_result: None,
+            });
+
+            if enable_word_by_word_response && end_sentence.is_match(&piece) && !is_thinking {
+                let sentence = &reply[last_message_chunk_index..];
+                last_message_chunk_index = reply.len();
+                s.tts_commands.push(sentence.into());
+                first_sentence_queued_at.get_or_insert_with(Instant::now);
+            }
+        });
+        state.publish(Event::Token(piece));
+    }
+
+    if cut_off {
+        source.cut_off(&reply)?;
+    }
+
+    Ok(Reply {
+        text: reply,
+        interrupted,
+        tokens: turn_tokens,
+        first_token_at,
+        first_sentence_queued_at,
+    })
+}
+
+/// Runs a round of calls, adds their results to the conversation and has the llm read them.
+/// Returns whether a limit was hit, after which no more tools are run this turn.
+#[allow(clippy::too_many_arguments)]
+fn run_tool_round(
+    state: &StateHandle,
+    source: &mut impl TokenSource,
+    tools: &Tools,
+    tool_limits: &ToolLimits,
+    mut tool_calls: Vec<ToolCall>,
+    tool_rounds: &mut usize,
+    tool_calls_made: &mut Vec<String>,
+    turn_deadline: &mut Instant,
+    recorded_reply: Option<&str>,
+) -> anyhow::Result<bool> {
+    let mut tool_response_messages = vec![];
+    state.update(mark_tool_call);
+
+    let signatures: Vec<String> = tool_calls.iter().map(call_signature).collect();
+    *tool_rounds += 1;
+
+    let limit = tool_limit_reached(
+        tool_limits,
+        *tool_rounds,
+        tool_calls_made,
+        &signatures,
+        *turn_deadline,
+    );
+    tool_calls_made.extend(signatures);
+    let exhausted = limit.is_some();
+
+    // Stop running tools for the rest of this turn and have the llm answer with what it has
+    if let Some(limit) = limit {
+        warn!("{}", limit);
+        tool_calls.clear();
+        tool_response_messages.push(format!(
+            "{} No more tools can be used this turn, answer the user with what you have so far.",
+            limit
+        ));
+
+        state.update(|s| {
+            s.conversation.push(ConversationSnippet {
+                message: limit,
+                role: LlmRole::Tool,
+                is_tool_call: false,
+                timestamp: unix_timestamp(),
+                tool_result: None,
+            });
+        });
+    }
+
+    for call in &tool_calls {
+        match confirmation::run_if_allowed(state, tools, call, Some(&mut *turn_deadline)) {
+            Ok(mut result) => {
+                result.truncate_to(tool_limits.max_result_tokens, |text| {
+                    source.count_tokens(text)
+                });
+                let message = result.for_llm();
+
+                info!(
+                    call = %call,
+                    exit_code = ?result.exit_code,
+                    truncated = result.truncated,
+                    result = %message.trim(),
+                    "tool call finished"
+                );
+                tool_response_messages.push(message.clone());
+
+                state.update(|s| {
+                    s.conversation.push(ConversationSnippet {
+                        message,
+                        role: LlmRole::Tool,
+                        is_tool_call: false,
+                        timestamp: unix_timestamp(),
+                        tool_result: Some(result),
+                    });
+                });
+            }
+            Err(e) => {
+                warn!(call = %call, "tool call failed: {:?}", e);
+                tool_response_messages.push(format!("Error: {:?}", e));
+
+                state.update(|s| {
+                    s.conversation.push(ConversationSnippet {
+                        message: format!("Error: {:?}", e),
+                        role: LlmRole::Tool,
+                        is_tool_call: false,
+                        timestamp: unix_timestamp(),
+                        tool_result: None,
+                    });
+                });
+            }
+        }
+    }
+
+    let tool_chat = source.read_tool_results(&tool_response_messages)?;
+    if let Some(reply) = recorded_reply {
+        state.update(|s| {
+            s.decoded_text.push(reply.into());
+            s.decoded_text.push(tool_chat);
+        });
+    }
+
+    Ok(exhausted)
+}
+
+/// Marks the reply that made the calls as a tool call, before any of their results are added
+fn mark_tool_call(s: &mut State) {
+    if let Some(snippet) = s.conversation.pop() {
+        s.conversation.push(ConversationSnippet {
+            is_tool_call: true,
+            ..snippet
+        });
+    }
+}
+
+/// Why no more tools should be run this turn, if any limit has been hit
+fn tool_limit_reached(
+    limits: &ToolLimits,
+    rounds: usize,
+    previous_calls: &[String],
+    calls: &[String],
+    turn_deadline: Instant,
+) -> Option<String> {
+    if rounds > limits.max_rounds {
+        return Some(format!(
+            "Tool limit reached: tools were already used {} times this turn.",
+            limits.max_rounds
+        ));
+    }
+
+    if previous_calls.len() + calls.len() > limits.max_calls {
+        return Some(format!(
+            "Tool limit reached: only {} tool calls are allowed per turn.",
+            limits.max_calls
+        ));
+    }
+
+    if let Some(repeated) = calls.iter().find(|call| previous_calls.contains(call)) {
+        return Some(format!(
+            "Tool limit reached: {} was already called with the same arguments this turn.",
+            repeated
+        ));
+    }
+
+    if Instant::now() >= turn_deadline {
+        return Some(format!(
+            "Tool limit reached: this turn has taken over {} seconds.",
+            limits.turn_timeout_secs
+        ));
+    }
+
+    None
+}
+
+/// Identifies a call by its name and arguments, ignoring formatting and argument order
+fn call_signature(call: &ToolCall) -> String {
+    format!(
+        "{}({})",
+        call.name,
+        serde_json::Value::Object(call.arguments.clone())
+    )
+}
+
+/// Byte offset of the earliest stop string in `reply`
+fn stop_position(reply: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| reply.find(stop.as_str()))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ScriptedTokens;
+    use crate::tools;
+
+    const SHOUT: &str = r#"{"name": "shout", "parameters": {"text": "hello"}}"#;
+
+    fn reply(
+        replies: &[&str],
+        tools: Option<&Tools>,
+        sampling: SamplingConfig,
+    ) -> (Reply, ScriptedTokens, StateHandle) {
+        let state = StateHandle::new();
+        let mut tokens = ScriptedTokens::new(replies);
+        tokens.next_reply().unwrap();
+
+        let reply = generate_reply(
+            &state,
+            &mut tokens,
+            &ReplyOptions {
+                tools,
+                tool_limits: &ToolLimits::default(),
+                sampling: &sampling,
+                template_format: ToolFormat::JsonStandard,
+                enable_word_by_word_response: false,
+                record_decoded_text: false,
+            },
+        )
+        .unwrap();
+
+        (reply, tokens, state)
+    }
+
+    #[test]
+    fn replies_are_cut_off_at_stop_strings_and_max_tokens() {
+        let sampling = SamplingConfig {
+            stop: vec!["User:".into()],
+            ..SamplingConfig::default()
+        };
+        let (stopped, tokens, state) = reply(&["Hello there. User: hi"], None, sampling);
+
+        assert_eq!(stopped.text, "Hello there. ");
+        assert_eq!(tokens.cut_off_reply.as_deref(), Some("Hello there. "));
+        assert_eq!(
+            state.read().conversation.last().unwrap().message,
+            "Hello there. "
+        );
+
+        let sampling = SamplingConfig {
+            max_tokens: Some(1),
+            ..SamplingConfig::default()
+        };
+        let (limited, tokens, _) = reply(&["Hello there."], None, sampling);
+
+        assert_eq!(limited.text, "Hello ");
+        assert_eq!(tokens.cut_off_reply.as_deref(), Some("Hello "));
+
+        let (finished, tokens, _) = reply(&["Hello there."], None, SamplingConfig::default());
+
+        assert_eq!(finished.text, "Hello there.");
+        assert_eq!(tokens.cut_off_reply, None);
+    }
+
+    #[test]
+    fn max_tokens_counts_every_tool_round() {
+        let tools = tools::parse_python_functions("tests/fixtures/tools.py".into());
+        // The call is restarted once it's seen to start with a brace, so its first word counts twice
+        let sampling = SamplingConfig {
+            max_tokens: Some(8),
+            ..SamplingConfig::default()
+        };
+        let (reply, _, state) = reply(&[SHOUT, "It said HELLO."], Some(&tools), sampling);
+
+        assert_eq!(reply.text, "It said ");
+        assert_eq!(reply.tokens, 8);
+        assert_eq!(state.read().conversation[1].message.trim(), "HELLO");
+    }
+
+    #[test]
+    fn repeated_calls_stop_the_tools_for_the_turn() {
+        let tools = tools::parse_python_functions("tests/fixtures/tools.py".into());
+        let (reply, _, state) = reply(
+            &[SHOUT, SHOUT, "It said HELLO."],
+            Some(&tools),
+            SamplingConfig::default(),
+        );
+
+        let conversation = state.read().conversation;
+        assert_eq!(reply.text, "It said HELLO.");
+        assert!(conversation[0].is_tool_call);
+        assert!(conversation[2].is_tool_call);
+        assert!(
+            conversation[3]
+                .message
+                .contains("was already called with the same arguments")
+        );
+        assert_eq!(conversation.len(), 5);
+    }
+
+    #[test]
+    fn only_the_reply_is_marked_when_it_makes_several_calls() {
+        let tools = tools::parse_python_functions("tests/fixtures/tools.py".into());
+        let calls = r#"<tool_call>{"name": "shout", "arguments": {"text": "hello"}}</tool_call> <tool_call>{"name": "shout", "arguments": {"text": "bye"}}</tool_call>"#;
+        let (_, _, state) = reply(
+            &[calls, "It said HELLO and BYE."],
+            Some(&tools),
+            SamplingConfig::default(),
+        );
+
+        let conversation = state.read().conversation;
+        let marked: Vec<bool> = conversation.iter().map(|s| s.is_tool_call).collect();
+        assert_eq!(marked, [true, false, false, false]);
+        assert_eq!(conversation[1].message.trim(), "HELLO");
+        assert_eq!(conversation[2].message.trim(), "BYE");
+    }
+}
@@ -0,0 +1,420 @@
+//! Boxes in the processes that run tool calls, following a `SandboxPolicy`. The wall clock
+//! timeout is up to whoever waits on them. The python worker lives through many calls, so it sets
+//! its own CPU limit for each one, everything else is set up here before the process starts.
+//!
+//! It's resource limits, an empty network namespace and Landlock rules for writing, nothing more.
+//! Tools can still read anything the user running the assistant can, and there's no seccomp
+//! filter, so every syscall is open to them.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+use crate::config::SandboxPolicy;
+
+/// A command for `program` that runs in `working_directory` and is held to `policy`, apart from
+/// its CPU limit
+pub fn command(
+    program: &str,
+    policy: &SandboxPolicy,
+    working_directory: &Path,
+) -> anyhow::Result<Command> {
+    build(program, policy, working_directory, None)
+}
+
+/// Like `command`, for a process that only lives for one call, so the CPU limit can be set up front
+pub fn one_off_command(
+    program: &str,
+    policy: &SandboxPolicy,
+    working_directory: &Path,
+) -> anyhow::Result<Command> {
+    build(program, policy, working_directory, policy.cpu_secs)
+}
+
+fn build(
+    program: &str,
+    policy: &SandboxPolicy,
+    working_directory: &Path,
+    cpu_secs: Option<u64>,
+) -> anyhow::Result<Command> {
+    let mut command = Command::new(find_program(program));
+    command.current_dir(working_directory);
+
+    if let Some(allowed) = &policy.allowed_env {
+        command.env_clear();
+        for name in allowed {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+
+    platform::restrict(&mut command, policy, working_directory, cpu_secs)?;
+    Ok(command)
+}
+
+/// Looks for `program` on our own PATH, since the tools' environment might not have one
+fn find_program(program: &str) -> PathBuf {
+    std::env::var_os("PATH")
+        .iter()
+        .flat_map(std::env::split_paths)
+        .map(|directory| directory.join(program))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(program))
+}
+
+/// Says which part of the sandbox couldn't be set up when a tool's process fails to start
+pub fn describe_spawn_error(
+    program: &str,
+    policy: &SandboxPolicy,
+    error: &std::io::Error,
+) -> String {
+    let mut needs = Vec::new();
+    if !policy.network {
+        needs.push("turning off the network needs unprivileged user namespaces");
+    }
+    if policy.confine_to_working_directory {
+        needs.push("confining to the working directory needs Landlock");
+    }
+    if policy.memory_mb.is_some() {
+        needs.push("the memory limit needs setrlimit");
+    }
+
+    if needs.is_empty() {
+        format!("couldn't start {} for the tools: {}", program, error)
+    } else {
+        format!(
+            "couldn't start {} in the tool sandbox: {} ({})",
+            program,
+            error,
+            needs.join(", ")
+        )
+    }
+}
+
+/// Which limit a tool's process most likely went over, going by the signal that stopped it
+pub fn describe_signal(
+    policy: &SandboxPolicy,
+    function: &str,
+    status: Option<ExitStatus>,
+) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        let signal = status.and_then(|status| status.signal());
+
+        if let Some(cpu_secs) = policy.cpu_secs
+            && signal == Some(libc::SIGXCPU)
+        {
+            return Some(format!(
+                "{} used more than {}s of CPU time and was stopped",
+                function, cpu_secs
+            ));
+        }
+
+        // Python usually raises a MemoryError, but running out inside a C extension can crash it
+        if let Some(memory_mb) = policy.memory_mb
+            && matches!(signal, Some(libc::SIGSEGV | libc::SIGABRT | libc::SIGKILL))
+        {
+            return Some(format!(
+                "{} probably went over its {}MB memory limit and was stopped",
+                function, memory_mb
+            ));
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (policy, function, status);
+
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::Command;
+
+    use anyhow::bail;
+
+    use crate::config::SandboxPolicy;
+
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    /// Landlock ABI 2
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    /// Landlock ABI 3
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    /// Everything in the first Landlock ABI that changes the filesystem. Reading isn't handled,
+    /// so it stays allowed everywhere, not just in the working directory, and python can still
+    /// load its standard library.
+    const ACCESS_FS_CHANGES: u64 = ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_CHAR
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_MAKE_FIFO
+        | ACCESS_FS_MAKE_BLOCK
+        | ACCESS_FS_MAKE_SYM;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: libc::c_int,
+    }
+
+    /// Everything worked out before forking, since the child can't safely allocate
+    struct Restrictions {
+        memory_bytes: Option<u64>,
+        cpu_secs: Option<u64>,
+        /// What goes in /proc/self/uid_map and gid_map once in a user namespace of its own
+        id_maps: Option<(CString, CString)>,
+        /// Filesystem changes Landlock looks after, and the paths where they're still allowed
+        landlock: Option<(u64, Vec<(CString, u64)>)>,
+    }
+
+    pub fn restrict(
+        command: &mut Command,
+        policy: &SandboxPolicy,
+        working_directory: &Path,
+        cpu_secs: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let memory_bytes = policy.memory_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+
+        let id_maps = (!policy.network).then(|| {
+            // SAFETY: getuid and getgid can't fail
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            (
+                CString::new(format!("{} {} 1", uid, uid)).unwrap(),
+                CString::new(format!("{} {} 1", gid, gid)).unwrap(),
+            )
+        });
+
+        let landlock = if policy.confine_to_working_directory {
+            let abi = landlock_abi();
+            if abi < 1 {
+                bail!(
+                    "confine_to_working_directory needs Landlock, which this kernel doesn't have or has turned off"
+                );
+            }
+
+            let mut handled = ACCESS_FS_CHANGES;
+            let mut file_access = ACCESS_FS_WRITE_FILE;
+            if abi >= 2 {
+                handled |= ACCESS_FS_REFER;
+            }
+            if abi >= 3 {
+                handled |= ACCESS_FS_TRUNCATE;
+                file_access |= ACCESS_FS_TRUNCATE;
+            }
+
+            let directory = working_directory.canonicalize()?;
+            let writable = vec![
+                (CString::new(directory.as_os_str().as_bytes())?, handled),
+                // Subprocesses and DEVNULL need somewhere to throw output away
+                (CString::new("/dev/null")?, file_access),
+            ];
+
+            Some((handled, writable))
+        } else {
+            None
+        };
+
+        if memory_bytes.is_none() && cpu_secs.is_none() && id_maps.is_none() && landlock.is_none() {
+            return Ok(());
+        }
+
+        let restrictions = Restrictions {
+            memory_bytes,
+            cpu_secs,
+            id_maps,
+            landlock,
+        };
+
+        // SAFETY: `apply` only makes syscalls and doesn't allocate, so it's fine between fork and exec
+        unsafe {
+            command.pre_exec(move || restrictions.apply());
+        }
+
+        Ok(())
+    }
+
+    impl Restrictions {
+        fn apply(&self) -> io::Result<()> {
+            if let Some(bytes) = self.memory_bytes {
+                let limit = libc::rlimit {
+                    rlim_cur: bytes,
+                    rlim_max: bytes,
+                };
+                // SAFETY: `limit` is a valid rlimit
+                check(unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) })?;
+            }
+
+            if let Some(secs) = self.cpu_secs {
+                // Going over the soft limit sends SIGXCPU, the hard one a second later SIGKILL
+                let limit = libc::rlimit {
+                    rlim_cur: secs,
+                    rlim_max: secs + 1,
+                };
+                // SAFETY: `limit` is a valid rlimit
+                check(unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) })?;
+            }
+
+            // Before Landlock, which would stop the id maps from being written
+            if let Some((uid_map, gid_map)) = &self.id_maps {
+                // A network namespace of its own has nothing in it but a loopback that's down
+                // SAFETY: unshare only affects this process
+                check(unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) })?;
+
+                write_file(c"/proc/self/setgroups", c"deny")?;
+                write_file(c"/proc/self/uid_map", uid_map)?;
+                write_file(c"/proc/self/gid_map", gid_map)?;
+            }
+
+            if let Some((handled, writable)) = &self.landlock {
+                restrict_filesystem(*handled, writable)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn restrict_filesystem(handled: u64, writable: &[(CString, u64)]) -> io::Result<()> {
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+
+        // SAFETY: `attr` is a valid ruleset attribute of the size given
+        let ruleset = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        check(ruleset as libc::c_int)?;
+        let ruleset = ruleset as libc::c_int;
+
+        let result = (|| {
+            for (path, allowed_access) in writable {
+                // SAFETY: `path` is a valid C string
+                let parent_fd =
+                    check(unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) })?;
+
+                let rule = PathBeneathAttr {
+                    allowed_access: *allowed_access,
+                    parent_fd,
+                };
+                // SAFETY: `rule` is a valid path beneath rule and `ruleset` is open
+                let added = unsafe {
+                    libc::syscall(
+                        libc::SYS_landlock_add_rule,
+                        ruleset,
+                        LANDLOCK_RULE_PATH_BENEATH,
+                        &rule as *const PathBeneathAttr,
+                        0,
+                    )
+                };
+                let added = check(added as libc::c_int);
+                // SAFETY: `parent_fd` was opened above
+                unsafe { libc::close(parent_fd) };
+                added?;
+            }
+
+            // SAFETY: only affects this process, and is required by landlock_restrict_self
+            check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+            // SAFETY: `ruleset` is open
+            check(
+                unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) }
+                    as libc::c_int,
+            )
+        })();
+
+        // SAFETY: `ruleset` was opened above
+        unsafe { libc::close(ruleset) };
+        result.map(|_| ())
+    }
+
+    /// The Landlock ABI version the kernel supports, or less than 1 if it has none
+    fn landlock_abi() -> libc::c_long {
+        // SAFETY: asking for the version takes no attribute
+        unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        }
+    }
+
+    fn write_file(path: &std::ffi::CStr, contents: &std::ffi::CStr) -> io::Result<()> {
+        // SAFETY: `path` is a valid C string
+        let fd = check(unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) })?;
+        let bytes = contents.to_bytes();
+        // SAFETY: `bytes` is valid for its length and `fd` is open
+        let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+        let written = check(written as libc::c_int);
+        // SAFETY: `fd` was opened above
+        unsafe { libc::close(fd) };
+
+        written.map(|_| ())
+    }
+
+    fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::path::Path;
+    use std::process::Command;
+
+    use anyhow::bail;
+
+    use crate::config::SandboxPolicy;
+
+    pub fn restrict(
+        _command: &mut Command,
+        policy: &SandboxPolicy,
+        _working_directory: &Path,
+        cpu_secs: Option<u64>,
+    ) -> anyhow::Result<()> {
+        if !policy.network || policy.confine_to_working_directory || policy.memory_mb.is_some() {
+            bail!("network, confine_to_working_directory and memory_mb only work on Linux");
+        }
+        if cpu_secs.is_some() {
+            bail!("cpu_secs only works on Linux for tools that aren't python");
+        }
+
+        Ok(())
+    }
+}
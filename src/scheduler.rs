@@ -0,0 +1,474 @@
+//! Timers, alarms and reminders, set by the llm through the built-in tools or by saying
+//! something like "remind me in 10 minutes to check the oven". They're kept in a file so they
+//! last across restarts, and when one is due it's added to the conversation and said out loud,
+//! whether or not anyone spoke first.
+
+use std::fs;
+use std::sync::LazyLock;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Local, NaiveTime};
+use regex::Regex;
+
+use crate::logging::{self, WorkerHandle};
+use crate::state::{
+    ConversationSnippet, LifeCycleState, LlmRole, LlmState, State, StateHandle, Timer, TimerKind,
+    unix_timestamp,
+};
+
+/// How often due timers are looked for, so they go off at most this late
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Timers this overdue went off while the program wasn't running
+const MISSED_AFTER_SECS: u64 = 60;
+
+pub struct SchedulerHandle {
+    pub handle: WorkerHandle,
+}
+
+pub fn spawn_scheduler_thread(state: StateHandle, timers_file: String) -> SchedulerHandle {
+    let handle = logging::spawn_worker("scheduler", state.clone(), move || {
+        run_scheduler_loop(state, timers_file)
+    });
+
+    SchedulerHandle { handle }
+}
+
+fn run_scheduler_loop(state: StateHandle, timers_file: String) -> anyhow::Result<()> {
+    let mut saved = load(&timers_file);
+    // Started again after a crash, the state has the newest copy of anything in both
+    state.update(|s| {
+        for timer in &saved {
+            if !s.timers.iter().any(|existing| existing.id == timer.id) {
+                s.timers.push(timer.clone());
+            }
+        }
+    });
+
+    while !state.is_shutting_down() {
+        let timers = state.read_with(|s| s.timers.clone());
+        if timers != saved {
+            save(&timers_file, &timers)?;
+            saved = timers;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+
+        let now = unix_timestamp();
+        if !state.read_with(|s| is_idle(s) && s.timers.iter().any(|timer| timer.due <= now)) {
+            continue;
+        }
+
+        state.update(|s| {
+            // Checked again, something might have started in between
+            if !is_idle(s) {
+                return;
+            }
+
+            let (due, waiting): (Vec<_>, Vec<_>) =
+                s.timers.drain(..).partition(|timer| timer.due <= now);
+            s.timers = waiting;
+
+            for timer in due {
+                tracing::info!(id = timer.id, label = %timer.label, "timer went off");
+                announce(s, announcement(&timer, now));
+            }
+        });
+    }
+
+    // Anything set or gone off since the last poll would otherwise be lost or go off again
+    save(&timers_file, &state.read_with(|s| s.timers.clone()))
+}
+
+/// Speaking in the middle of a turn would talk over the reply and confuse `llm_state`,
+/// so timers wait until nothing else is going on, including the llm loading
+fn is_idle(state: &State) -> bool {
+    state.life_cycle_state == LifeCycleState::Running
+        && state.llm_state == LlmState::AwaitingInput
+        && state.llm_command.is_none()
+        && state.pending_tool_call.is_none()
+        && state.tts_commands.is_empty()
+}
+
+/// Goes in the conversation as something the assistant said, so the llm knows about it next
+/// turn. It's only said out loud if the mic isn't muted, since that means the user doesn't want
+/// to be disturbed, and in name-only mode it gives the user the same few seconds to answer
+/// as a reply does. A conversation that was ended stays ended.
+fn announce(state: &mut State, text: String) {
+    state.conversation.push(ConversationSnippet {
+        role: LlmRole::Assistant,
+        message: text.clone(),
+        is_tool_call: false,
+        timestamp: unix_timestamp(),
+        tool_result: None,
+    });
+
+    if state.user_mute {
+        return;
+    }
+
+    if state.is_only_responding_after_name && !state.is_waiting_for_name {
+        state.time_since_name_was_said = Some(std::time::Instant::now());
+    }
+
+    // Same as for a reply, so the mic doesn't hear it. TTS unmutes once it's been said.
+    state.system_mute = true;
+    state.tts_commands.push(text);
+}
+
+fn announcement(timer: &Timer, now: u64) -> String {
+    let text = match (timer.kind, timer.label.is_empty()) {
+        (TimerKind::Timer, true) => "Your timer is done.".into(),
+        (TimerKind::Timer, false) => format!("Your {} timer is done.", timer.label),
+        (TimerKind::Alarm, true) => "This is your alarm.".into(),
+        (TimerKind::Alarm, false) => format!("This is your alarm for {}.", timer.label),
+        (TimerKind::Reminder, true) => "This is your reminder.".into(),
+        (TimerKind::Reminder, false) => format!("Reminder: {}.", timer.label),
+    };
+
+    if now.saturating_sub(timer.due) > MISSED_AFTER_SECS {
+        format!("This went off while I was away. {}", text)
+    } else {
+        text
+    }
+}
+
+/// Adds a timer to the state, where the scheduler picks it up, and returns its id
+pub fn add(state: &StateHandle, kind: TimerKind, label: &str, due: u64) -> u64 {
+    let mut id = 0;
+    state.update(|s| {
+        id = s.timers.iter().map(|timer| timer.id).max().unwrap_or(0) + 1;
+        s.timers.push(Timer {
+            id,
+            kind,
+            label: label.into(),
+            due,
+        });
+    });
+    id
+}
+
+fn load(timers_file: &str) -> Vec<Timer> {
+    let Ok(contents) = fs::read_to_string(timers_file) else {
+        return Vec::new();
+    };
+
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        tracing::warn!("couldn't read the timers in {}: {:?}", timers_file, e);
+        Vec::new()
+    })
+}
+
+fn save(timers_file: &str, timers: &[Timer]) -> anyhow::Result<()> {
+    fs::write(timers_file, serde_json::to_string_pretty(timers)?)?;
+    Ok(())
+}
+
+/// The next time it's `time` o'clock locally, as a unix timestamp. Today if it hasn't been yet,
+/// otherwise tomorrow.
+pub fn next_occurrence(time: NaiveTime) -> Result<u64, String> {
+    let today = Local::today()
+        .and_time(time)
+        .ok_or_else(|| "That time doesn't exist today".to_string())?;
+    let due = if today > Local::now() {
+        today
+    } else {
+        today + chrono::Duration::days(1)
+    };
+
+    Ok(due.timestamp() as u64)
+}
+
+/// Like `1 hour 5 minutes`, which is how it'd be said
+pub fn describe_duration(seconds: u64) -> String {
+    let parts = [
+        (seconds / 86400, "day"),
+        (seconds % 86400 / 3600, "hour"),
+        (seconds % 3600 / 60, "minute"),
+        (seconds % 60, "second"),
+    ];
+
+    let described: Vec<String> = parts
+        .iter()
+        .filter(|(amount, _)| *amount > 0)
+        .map(|(amount, unit)| format!("{} {}{}", amount, unit, if *amount == 1 { "" } else { "s" }))
+        .collect();
+
+    if described.is_empty() {
+        "0 seconds".into()
+    } else {
+        described.join(" ")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScheduleIntent {
+    Timer { seconds: u64, label: String },
+    Reminder { due: u64, label: String },
+}
+
+static TIMER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bset an? (?:(.+?) )?timer for (.+)").unwrap());
+static REMIND_WHEN_FIRST: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bremind me (in|at) (.+?) to (.+)").unwrap());
+static REMIND_WHEN_LAST: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bremind me to (.+) (in|at) (.+)").unwrap());
+
+/// Picks out timers and reminders that can be set here rather than by the llm, like
+/// "set a tea timer for 5 minutes", "remind me in an hour to call mum" or
+/// "remind me to take the bins out at 7:30 pm"
+pub fn parse_schedule_intent(text: &str) -> Option<ScheduleIntent> {
+    if let Some(cap) = TIMER.captures(text) {
+        return Some(ScheduleIntent::Timer {
+            seconds: parse_duration(&cap[2])?,
+            label: cap.get(1).map_or("", |label| label.as_str()).trim().into(),
+        });
+    }
+
+    let (preposition, when, label) = if let Some(cap) = REMIND_WHEN_FIRST.captures(text) {
+        (
+            cap[1].to_lowercase(),
+            cap[2].to_string(),
+            cap[3].to_string(),
+        )
+    } else {
+        let cap = REMIND_WHEN_LAST.captures(text)?;
+        (
+            cap[2].to_lowercase(),
+            cap[3].to_string(),
+            cap[1].to_string(),
+        )
+    };
+
+    let due = if preposition == "in" {
+        unix_timestamp() + parse_duration(&when)?
+    } else {
+        next_occurrence(parse_clock_time(&when)?).ok()?
+    };
+
+    Some(ScheduleIntent::Reminder {
+        due,
+        label: label
+            .trim()
+            .trim_end_matches(|c: char| !c.is_alphanumeric())
+            .into(),
+    })
+}
+
+/// Sets the timer or reminder and says so
+pub fn handle_schedule_intent(state: &StateHandle, intent: ScheduleIntent) {
+    let reply = match intent {
+        ScheduleIntent::Timer { seconds, label } => {
+            add(state, TimerKind::Timer, &label, unix_timestamp() + seconds);
+            format!("Okay, timer set for {}.", describe_duration(seconds))
+        }
+        ScheduleIntent::Reminder { due, label } => {
+            add(state, TimerKind::Reminder, &label, due);
+            format!(
+                "Okay, I'll remind you in {}.",
+                describe_duration(due.saturating_sub(unix_timestamp()))
+            )
+        }
+    };
+
+    state.update(|s| {
+        s.system_mute = true;
+        s.tts_commands.push(reply);
+    });
+}
+
+static AMOUNT_AND_UNIT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"\b(\d+(?:\.\d+)?|an?|one|two|three|four|five|six|seven|eight|nine|ten|fifteen|twenty|thirty|forty|fifty)\s*-?\s*(seconds?|secs?|minutes?|mins?|hours?|hrs?)\b",
+    )
+    .unwrap()
+});
+
+/// Adds up every `<amount> <unit>` in something like "an hour and 30 minutes". Whisper
+/// writes most numbers as digits, but small ones often come out as words.
+fn parse_duration(text: &str) -> Option<u64> {
+    let text = text.to_lowercase();
+    if text.contains("half an hour") {
+        return Some(1800);
+    }
+
+    let mut seconds: f64 = 0.0;
+    for cap in AMOUNT_AND_UNIT.captures_iter(&text) {
+        let amount = match &cap[1] {
+            "a" | "an" | "one" => 1.0,
+            "two" => 2.0,
+            "three" => 3.0,
+            "four" => 4.0,
+            "five" => 5.0,
+            "six" => 6.0,
+            "seven" => 7.0,
+            "eight" => 8.0,
+            "nine" => 9.0,
+            "ten" => 10.0,
+            "fifteen" => 15.0,
+            "twenty" => 20.0,
+            "thirty" => 30.0,
+            "forty" => 40.0,
+            "fifty" => 50.0,
+            digits => digits.parse().ok()?,
+        };
+        let unit = match &cap[2][..1] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            _ => 1.0,
+        };
+        seconds += amount * unit;
+    }
+
+    (seconds >= 1.0).then_some(seconds.round() as u64)
+}
+
+static CLOCK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(\d{1,2})(?:[:.](\d{2}))?\s*([ap])?\.?\s*(?:m\.?)?$").unwrap()
+});
+
+/// `17:30`, `5:30 pm`, `7 a.m.` and the like
+fn parse_clock_time(text: &str) -> Option<NaiveTime> {
+    let cap = CLOCK.captures(text.trim().trim_end_matches([',', '?']))?;
+
+    let mut hours: u32 = cap[1].parse().ok()?;
+    let minutes: u32 = cap.get(2).map_or(Some(0), |m| m.as_str().parse().ok())?;
+    match cap
+        .get(3)
+        .map(|half| half.as_str().to_lowercase())
+        .as_deref()
+    {
+        Some(_) if hours == 0 || hours > 12 => return None,
+        Some("p") if hours != 12 => hours += 12,
+        Some("a") if hours == 12 => hours = 0,
+        _ => {}
+    }
+
+    NaiveTime::from_hms_opt(hours, minutes, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_path;
+
+    #[test]
+    fn picks_out_timers_and_reminders() {
+        assert_eq!(
+            parse_schedule_intent("Set a tea timer for five minutes."),
+            Some(ScheduleIntent::Timer {
+                seconds: 300,
+                label: "tea".into()
+            })
+        );
+        assert_eq!(
+            parse_schedule_intent("Could you set a timer for 1 hour and 30 minutes?"),
+            Some(ScheduleIntent::Timer {
+                seconds: 5400,
+                label: String::new()
+            })
+        );
+
+        let now = unix_timestamp();
+        let Some(ScheduleIntent::Reminder { due, label }) =
+            parse_schedule_intent("Remind me in 10 minutes to check the oven.")
+        else {
+            panic!("not a reminder");
+        };
+        assert!((now + 600..=now + 601).contains(&due));
+        assert_eq!(label, "check the oven");
+
+        let Some(ScheduleIntent::Reminder { due, label }) =
+            parse_schedule_intent("Remind me to take the bins out at 7:30 pm")
+        else {
+            panic!("not a reminder");
+        };
+        assert!(due > now && due <= now + 86400);
+        assert_eq!(label, "take the bins out");
+
+        assert_eq!(parse_schedule_intent("What timers are set?"), None);
+        assert_eq!(parse_schedule_intent("Set a timer for later"), None);
+        assert_eq!(parse_schedule_intent("Remind me at teatime to eat"), None);
+    }
+
+    #[test]
+    fn reads_clock_times() {
+        assert_eq!(
+            parse_clock_time("17:30"),
+            NaiveTime::from_hms_opt(17, 30, 0)
+        );
+        assert_eq!(
+            parse_clock_time("5:30 pm"),
+            NaiveTime::from_hms_opt(17, 30, 0)
+        );
+        assert_eq!(parse_clock_time("7 a.m."), NaiveTime::from_hms_opt(7, 0, 0));
+        assert_eq!(parse_clock_time("12 am"), NaiveTime::from_hms_opt(0, 0, 0));
+        assert_eq!(parse_clock_time("13 pm"), None);
+        assert_eq!(parse_clock_time("25:00"), None);
+    }
+
+    #[test]
+    fn timers_last_across_restarts() {
+        let timers_file = temp_path("timers", "json").to_string_lossy().into_owned();
+        let _ = fs::remove_file(&timers_file);
+
+        let state = StateHandle::new();
+        let scheduler = spawn_scheduler_thread(state.clone(), timers_file.clone());
+        add(
+            &state,
+            TimerKind::Reminder,
+            "stretch",
+            unix_timestamp() + 3600,
+        );
+        // Long enough for it to be saved
+        thread::sleep(POLL_INTERVAL * 3);
+        state.update(|s| s.life_cycle_state = LifeCycleState::ShuttingDown);
+        scheduler.handle.join().unwrap();
+
+        let restarted = StateHandle::new();
+        let scheduler = spawn_scheduler_thread(restarted.clone(), timers_file.clone());
+        thread::sleep(POLL_INTERVAL);
+        let timers = restarted.read_with(|s| s.timers.clone());
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers[0].label, "stretch");
+        assert_eq!(timers[0].kind, TimerKind::Reminder);
+
+        restarted.update(|s| s.life_cycle_state = LifeCycleState::ShuttingDown);
+        scheduler.handle.join().unwrap();
+        fs::remove_file(&timers_file).unwrap();
+    }
+
+    #[test]
+    fn timers_set_just_before_shutdown_are_saved() {
+        let timers_file = temp_path("timers", "json").to_string_lossy().into_owned();
+        let _ = fs::remove_file(&timers_file);
+
+        let state = StateHandle::new();
+        let scheduler = spawn_scheduler_thread(state.clone(), timers_file.clone());
+        add(&state, TimerKind::Timer, "tea", unix_timestamp() + 3600);
+        state.update(|s| s.life_cycle_state = LifeCycleState::ShuttingDown);
+        scheduler.handle.join().unwrap();
+
+        let saved = load(&timers_file);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].label, "tea");
+        fs::remove_file(&timers_file).unwrap();
+    }
+
+    #[test]
+    fn missed_timers_say_so() {
+        let timer = Timer {
+            id: 1,
+            kind: TimerKind::Alarm,
+            label: "work".into(),
+            due: 1000,
+        };
+
+        assert_eq!(announcement(&timer, 1000), "This is your alarm for work.");
+        assert_eq!(
+            announcement(&timer, 5000),
+            "This went off while I was away. This is your alarm for work."
+        );
+    }
+}
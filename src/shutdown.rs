@@ -3,15 +3,39 @@ use std::io::Write;
 
 use regex::Regex;
 
-use crate::state::{LlmCommand, LlmState, StateHandle};
+use crate::state::{LlmCommand, LlmState, State, StateHandle};
 use crate::ui;
 
-pub fn save_conversation(state: StateHandle, conversation_file: &str) -> Result<(), anyhow::Error> {
+pub const SUMMARY_PROMPT: &str = "Ignore all previous instructions and summarize this conversation into a brief context block for future sessions. Include:
+1. Key facts about the user (background, preferences)
+2. Ongoing discussions and topics of conversation
+
+Format as concise bullet points. Include nothing else in your response.";
+
+/// The summary prompt, told where a resumed session's new part starts since everything before
+/// that was summarised when the session was first left
+fn summary_prompt(state: &State) -> String {
+    match state.conversation.get(state.resumed_snippets) {
+        Some(first_new) if state.resumed_snippets > 0 => format!(
+            "{}\n\nEverything before the message \"{}\" was already summarized in an earlier session. Only summarize that message and what came after it.",
+            SUMMARY_PROMPT,
+            first_new.message.trim()
+        ),
+        _ => SUMMARY_PROMPT.into(),
+    }
+}
+
+pub fn save_conversation(
+    state: StateHandle,
+    conversation_file: &str,
+    prune_memories: bool,
+) -> Result<(), anyhow::Error> {
     let re = Regex::new(r"(<think>[\s\S]*?<\/think>)*")?;
 
     let current_state = state.read();
 
-    if current_state.conversation.is_empty() {
+    // Nothing was said, or nothing new since resuming a session that was remembered back then
+    if current_state.conversation.len() <= current_state.resumed_snippets {
         ui::status_goodbye();
         return Ok(());
     }
@@ -20,17 +44,15 @@ pub fn save_conversation(state: StateHandle, conversation_file: &str) -> Result<
 
     let mut existing_memories = fs::read_to_string(conversation_file).unwrap_or_default();
 
-    let summary_prompt = "Ignore all previous instructions and summarize this conversation into a brief context block for future sessions. Include:
-1. Key facts about the user (background, preferences)
-2. Ongoing discussions and topics of conversation
-
-Format as concise bullet points. Include nothing else in your response.";
+    // Subscribed first so a quick reply can't come and go before we start listening
+    let rx = state.subscribe();
 
     state.update(|s| {
-        s.llm_command = Some(LlmCommand::ContinueConversation(summary_prompt.into()));
+        s.llm_command = Some(LlmCommand::ContinueConversation(summary_prompt(
+            &current_state,
+        )));
     });
 
-    let rx = state.subscribe();
     let summary = loop {
         let _ = rx.recv();
         let s = state.read();
@@ -42,8 +64,9 @@ Format as concise bullet points. Include nothing else in your response.";
         }
     };
 
-    // If memories are too large, ask LLM to prune them
-    if existing_memories.len() > 2000 {
+    // If memories are too large for the system prompt, ask LLM to prune them.
+    // With retrieval only the relevant ones are injected so they can grow freely.
+    if prune_memories && existing_memories.len() > 2000 {
         ui::status_pruning();
 
         let prune_messages = vec![
@@ -90,3 +113,30 @@ Format as concise bullet points. Include nothing else in your response.";
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::LlmRole;
+    use crate::test_support::snippet;
+
+    #[test]
+    fn resumed_sessions_only_summarise_what_is_new() {
+        let mut state = State::default();
+        for snippet in [
+            snippet(LlmRole::User, "Hi, I'm Sam"),
+            snippet(LlmRole::Assistant, "Hello Sam"),
+        ] {
+            state.conversation.push(snippet);
+        }
+        assert_eq!(summary_prompt(&state), SUMMARY_PROMPT);
+
+        state.resumed_snippets = 2;
+        let first_new = snippet(LlmRole::User, "I got a cat");
+        state.conversation.push(first_new);
+        let prompt = summary_prompt(&state);
+
+        assert!(prompt.starts_with(SUMMARY_PROMPT));
+        assert!(prompt.contains("Everything before the message \"I got a cat\""));
+    }
+}
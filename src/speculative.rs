@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+use std::num::NonZeroU32;
+use std::time::Instant;
+
+use llama_cpp_2::{
+    context::{LlamaContext, params::LlamaContextParams},
+    llama_backend::LlamaBackend,
+    llama_batch::LlamaBatch,
+    model::{AddBos, LlamaChatMessage, LlamaModel, params},
+    sampling::LlamaSampler,
+    token::LlamaToken,
+};
+
+use crate::config::Assistant;
+use crate::ui;
+
+const BATCH_SIZE: usize = 512;
+const BENCHMARK_TOKENS: usize = 256;
+const BENCHMARK_PROMPT: &str = "Tell me a long story about a lighthouse keeper and her cat.";
+
+/// A small model that guesses the next few tokens so the main model can check them all in one batch.
+/// It has to share the main model's vocabulary, see `check_vocab`.
+pub struct Draft<'a> {
+    model: &'a LlamaModel,
+    ctx: LlamaContext<'a>,
+    batch: LlamaBatch<'a>,
+    sampler: LlamaSampler,
+    /// What is in the draft's KV cache, so only the part that changed has to be decoded again
+    tokens: Vec<LlamaToken>,
+    max_tokens: usize,
+    pub drafted: usize,
+    pub accepted: usize,
+}
+
+impl<'a> Draft<'a> {
+    pub fn new(
+        backend: &LlamaBackend,
+        model: &'a LlamaModel,
+        threads: i32,
+        context_size: u32,
+        max_tokens: usize,
+    ) -> anyhow::Result<Self> {
+        let ctx = model.new_context(
+            backend,
+            LlamaContextParams::default()
+                .with_n_threads(threads)
+                .with_n_threads_batch(threads)
+                .with_n_ctx(NonZeroU32::new(context_size)),
+        )?;
+
+        Ok(Draft {
+            model,
+            ctx,
+            batch: LlamaBatch::new(BATCH_SIZE, 1),
+            sampler: LlamaSampler::greedy(),
+            tokens: Vec::new(),
+            max_tokens,
+            drafted: 0,
+            accepted: 0,
+        })
+    }
+
+    /// Guesses up to `limit` tokens that follow `context`
+    fn propose(&mut self, context: &[LlamaToken], limit: usize) -> anyhow::Result<Vec<LlamaToken>> {
+        if context.is_empty() || limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Keep what the draft already has cached, but always decode the last token again for its logits
+        let common = self
+            .tokens
+            .iter()
+            .zip(context)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(context.len() - 1);
+
+        self.ctx
+            .clear_kv_cache_seq(Some(0), Some(common as u32), None)?;
+        self.tokens.truncate(common);
+
+        for (chunk_index, chunk) in context[common..].chunks(BATCH_SIZE).enumerate() {
+            self.batch.clear();
+
+            for (i, token) in chunk.iter().enumerate() {
+                let position = common + chunk_index * BATCH_SIZE + i;
+                self.batch
+                    .add(*token, position as i32, &[0], position == context.len() - 1)?;
+            }
+
+            self.ctx.decode(&mut self.batch)?;
+        }
+
+        self.tokens.extend_from_slice(&context[common..]);
+
+        let mut proposed = Vec::new();
+
+        while proposed.len() < limit {
+            let token = self.sampler.sample(&self.ctx, self.batch.n_tokens() - 1);
+
+            if self.model.is_eog_token(token) {
+                break;
+            }
+
+            proposed.push(token);
+
+            if proposed.len() == limit {
+                break;
+            }
+
+            self.batch.clear();
+            self.batch
+                .add(token, self.tokens.len() as i32, &[0], true)?;
+            self.ctx.decode(&mut self.batch)?;
+            self.tokens.push(token);
+        }
+
+        Ok(proposed)
+    }
+
+    /// Share of guessed tokens the main model agreed with
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted == 0 {
+            0.0
+        } else {
+            self.accepted as f32 / self.drafted as f32
+        }
+    }
+}
+
+/// Errors if `draft` doesn't share `target`'s vocabulary. Its guesses would be token ids that
+/// mean something else to the main model, or don't exist there at all and make llama.cpp abort.
+pub fn check_vocab(draft: &LlamaModel, target: &LlamaModel) -> anyhow::Result<()> {
+    if draft.n_vocab() != target.n_vocab() {
+        anyhow::bail!(
+            "the draft model has {} tokens but the main model has {}",
+            draft.n_vocab(),
+            target.n_vocab()
+        );
+    }
+
+    if draft.token_bos() != target.token_bos() || draft.token_eos() != target.token_eos() {
+        anyhow::bail!("the draft model's start and end tokens aren't the main model's");
+    }
+
+    Ok(())
+}
+
+/// Samples the next token like normal, then has the draft guess what comes after it and checks
+/// every guess with a single decode of the main model.
+///
+/// Each token is sampled from the main model exactly as it would be without a draft, a guess is
+/// only kept if it's what the main model picked anyway. Returns the tokens in order alongside
+/// whether they're already in the main KV cache. The last one never is, so it gets decoded as usual.
+pub fn speculate(
+    draft: &mut Draft,
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    sampler: &mut LlamaSampler,
+    kv_tokens: &[LlamaToken],
+    n_past: i32,
+) -> anyhow::Result<VecDeque<(LlamaToken, bool)>> {
+    let first = sampler.sample(ctx, batch.n_tokens() - 1);
+    sampler.accept(first);
+
+    let room = (ctx.n_ctx() as i32 - n_past - 2).max(0) as usize;
+    if model.is_eog_token(first) || room == 0 {
+        return Ok(VecDeque::from([(first, false)]));
+    }
+
+    let mut context = kv_tokens[..(n_past as usize).min(kv_tokens.len())].to_vec();
+    context.push(first);
+
+    let proposed = draft.propose(&context, draft.max_tokens.min(room))?;
+    if proposed.is_empty() {
+        return Ok(VecDeque::from([(first, false)]));
+    }
+
+    batch.clear();
+    batch.add(first, n_past, &[0], true)?;
+    for (i, token) in proposed.iter().enumerate() {
+        batch.add(*token, n_past + 1 + i as i32, &[0], true)?;
+    }
+    ctx.decode(batch)?;
+
+    draft.drafted += proposed.len();
+
+    let mut tokens = VecDeque::from([(first, true)]);
+
+    for (i, guess) in proposed.iter().enumerate() {
+        let token = sampler.sample(ctx, i as i32);
+        sampler.accept(token);
+
+        if token != *guess {
+            // Throw away the guesses after the first wrong one
+            ctx.clear_kv_cache_seq(Some(0), Some((n_past + 1 + i as i32) as u32), None)?;
+            tokens.push_back((token, false));
+            return Ok(tokens);
+        }
+
+        draft.accepted += 1;
+        tokens.push_back((token, true));
+    }
+
+    // Every guess was right, so the main model's next token comes for free
+    let token = sampler.sample(ctx, proposed.len() as i32);
+    sampler.accept(token);
+    tokens.push_back((token, false));
+
+    Ok(tokens)
+}
+
+/// Drops speculated tokens past `n_past` from the main KV cache and decodes the last kept one
+/// again, so the next token can be sampled from its logits
+pub fn discard(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    kv_tokens: &[LlamaToken],
+    n_past: i32,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        n_past > 0 && n_past as usize <= kv_tokens.len(),
+        "can't go back to token {} of {} in the KV cache",
+        n_past,
+        kv_tokens.len()
+    );
+    let last = n_past - 1;
+
+    ctx.clear_kv_cache_seq(Some(0), Some(last as u32), None)?;
+    batch.clear();
+    batch.add(kv_tokens[last as usize], last, &[0], true)?;
+    ctx.decode(batch)?;
+
+    Ok(())
+}
+
+/// Generates the same reply with and without the draft model and prints how fast each one was
+pub fn run_benchmark(assistant: &Assistant, threads: i32, context_size: u32) -> anyhow::Result<()> {
+    let Some(draft_model_path) = &assistant.draft_model_path else {
+        anyhow::bail!(
+            "Benchmark mode needs a draft_model_path set for {}",
+            assistant.name
+        );
+    };
+
+    let backend = LlamaBackend::init()?;
+
+    let params = params::LlamaModelParams::default().with_n_gpu_layers(99);
+    let model = LlamaModel::load_from_file(
+        &backend,
+        assistant
+            .llm_model_path
+            .clone()
+            .expect("llm_model_path must be set"),
+        &params,
+    )?;
+    let draft_model = LlamaModel::load_from_file(&backend, draft_model_path, &params)?;
+    check_vocab(&draft_model, &model)?;
+
+    let prompt = model.apply_chat_template(
+        &model.chat_template(None)?,
+        &[
+            LlamaChatMessage::new("system".into(), assistant.system_prompt.clone())?,
+            LlamaChatMessage::new("user".into(), BENCHMARK_PROMPT.into())?,
+        ],
+        true,
+    )?;
+    let prompt_tokens = model.str_to_token(&prompt, AddBos::Always)?;
+
+    ui::benchmark_header(assistant, prompt_tokens.len(), BENCHMARK_TOKENS);
+
+    let mut results = Vec::new();
+
+    for use_draft in [false, true] {
+        let mut ctx = model.new_context(
+            &backend,
+            LlamaContextParams::default()
+                .with_n_threads(threads)
+                .with_n_threads_batch(threads)
+                .with_n_ctx(NonZeroU32::new(context_size)),
+        )?;
+        let mut draft = if use_draft {
+            Some(Draft::new(
+                &backend,
+                &draft_model,
+                threads,
+                context_size,
+                assistant.draft_tokens,
+            )?)
+        } else {
+            None
+        };
+
+        let mut batch = LlamaBatch::new(prompt_tokens.len().max(BATCH_SIZE), 1);
+        for (i, token) in prompt_tokens.iter().enumerate() {
+            batch.add(*token, i as i32, &[0], i == prompt_tokens.len() - 1)?;
+        }
+        ctx.decode(&mut batch)?;
+
+        // Greedy so both runs should write exactly the same thing
+        let mut sampler = LlamaSampler::greedy();
+        let mut kv_tokens = prompt_tokens.clone();
+        let mut n_past = prompt_tokens.len() as i32;
+        let mut generated = Vec::new();
+        let mut speculated: VecDeque<(LlamaToken, bool)> = VecDeque::new();
+
+        let started = Instant::now();
+
+        while generated.len() < BENCHMARK_TOKENS {
+            let (token, is_decoded) = if let Some(next) = speculated.pop_front() {
+                next
+            } else if let Some(draft) = draft.as_mut() {
+                speculated = speculate(
+                    draft,
+                    &model,
+                    &mut ctx,
+                    &mut batch,
+                    &mut sampler,
+                    &kv_tokens,
+                    n_past,
+                )?;
+                speculated.pop_front().unwrap()
+            } else {
+                let token = sampler.sample(&ctx, batch.n_tokens() - 1);
+                sampler.accept(token);
+                (token, false)
+            };
+
+            if model.is_eog_token(token) {
+                break;
+            }
+
+            if !is_decoded {
+                batch.clear();
+                batch.add(token, n_past, &[0], true)?;
+                ctx.decode(&mut batch)?;
+            }
+
+            kv_tokens.push(token);
+            generated.push(token);
+            n_past += 1;
+        }
+
+        let seconds = started.elapsed().as_secs_f32();
+        let tokens_per_second = generated.len() as f32 / seconds.max(f32::EPSILON);
+
+        ui::benchmark_result(
+            if use_draft {
+                "speculative"
+            } else {
+                "main model only"
+            },
+            generated.len(),
+            seconds,
+            draft.as_ref().map(|draft| draft.acceptance_rate()),
+        );
+
+        results.push((tokens_per_second, generated));
+    }
+
+    ui::benchmark_summary(results[1].0 / results[0].0, results[0].1 == results[1].1);
+
+    Ok(())
+}
@@ -1,4 +1,12 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock, mpsc};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::conversation::ConversationTree;
+use crate::metrics::{TurnMetrics, TurnTimings};
+use crate::tools::ToolResult;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LlmState {
@@ -18,12 +26,15 @@ pub enum LifeCycleState {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LlmCommand {
     ContinueConversation(String),
-    EditLastMessage(String),
+    /// Bring the KV cache in line with the active branch, replying if it ends on a user message
+    RebuildBranch,
     CancelInference,
     DestroyContextAndRunFromNothing(Vec<(String, String)>),
+    Regenerate,
+    SelectAlternative(usize),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LlmRole {
     User,
     Assistant,
@@ -40,59 +51,209 @@ impl std::fmt::Display for LlmRole {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConversationSnippet {
     pub role: LlmRole,
     pub message: String,
     pub is_tool_call: bool,
+    #[serde(default)]
+    pub timestamp: u64,
+    /// Everything a tool call left behind, on `Tool` snippets for calls that ran or were refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_result: Option<ToolResult>,
+}
+
+/// Seconds since the unix epoch, used to timestamp conversation snippets
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryView {
+    pub memories: Vec<String>,
+    pub selected: usize,
+}
+
+/// A timer, alarm or reminder, said out loud once it's due. Kept on disk by the scheduler
+/// so they last across restarts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Timer {
+    pub id: u64,
+    pub kind: TimerKind,
+    pub label: String,
+    /// Unix timestamp
+    pub due: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimerKind {
+    Timer,
+    Alarm,
+    Reminder,
+}
+
+/// A tool call that's waiting for the user to allow or refuse it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingToolCall {
+    /// The call as the llm wrote it
+    pub call: String,
+    pub approved: Option<bool>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct State {
     pub life_cycle_state: LifeCycleState,
-    pub conversation: Vec<ConversationSnippet>,
+    pub conversation: ConversationTree,
+    /// How much of the active branch came from a resumed session, which was remembered back then
+    pub resumed_snippets: usize,
     pub text_input: Option<(String, usize)>,
     pub system_mute: bool,
     pub user_mute: bool,
     pub is_editing: bool,
+    /// Index of the user message being edited on the active branch
+    pub edit_target: Option<usize>,
     pub is_hiding_think_tags: bool,
     pub is_only_responding_after_name: bool,
     pub time_since_name_was_said: Option<std::time::Instant>,
+    /// The conversation was ended, so nothing is answered until the name is said again,
+    /// even when not only responding after it
+    pub is_waiting_for_name: bool,
     pub llm_command: Option<LlmCommand>,
     pub llm_state: LlmState,
     pub tts_commands: Vec<String>,
+    pub memory_view: Option<MemoryView>,
+    pub pending_tool_call: Option<PendingToolCall>,
+    /// Memories the user asked to forget, kept until they say yes or no
+    pub pending_forget: Option<Vec<String>>,
+    /// Memories were written into the system prompt at startup instead of being retrieved each
+    /// turn, so forgetting or editing one only reaches the llm next session
+    pub memories_in_system_prompt: bool,
+    pub timers: Vec<Timer>,
+    pub decoded_text: Vec<String>,
+    /// Every exchange generated for the last message, tool calls and all, to switch between
+    pub reply_alternatives: Vec<Vec<ConversationSnippet>>,
+    pub selected_alternative: usize,
+    pub turn_timings: Option<TurnTimings>,
+    pub last_turn_metrics: Option<TurnMetrics>,
+    /// Something to tell the user once, like where an export went. Cleared by the next key.
+    pub status_line: Option<String>,
+    /// Threads that stopped because of an error or a panic
+    pub worker_errors: Vec<String>,
+    /// Workers that stopped and weren't started again
+    pub stopped_workers: Vec<&'static str>,
 }
 
 impl Default for State {
     fn default() -> Self {
         Self {
             life_cycle_state: LifeCycleState::Initializing,
-            conversation: Vec::new(),
+            conversation: ConversationTree::default(),
+            resumed_snippets: 0,
             system_mute: true,
             user_mute: false,
             is_editing: false,
+            edit_target: None,
             is_hiding_think_tags: true,
             is_only_responding_after_name: false,
             time_since_name_was_said: None,
+            is_waiting_for_name: false,
             text_input: None,
             llm_command: None,
             llm_state: LlmState::AwaitingInput,
             tts_commands: Vec::new(),
+            memory_view: None,
+            pending_tool_call: None,
+            pending_forget: None,
+            memories_in_system_prompt: false,
+            timers: Vec::new(),
+            decoded_text: Vec::new(),
+            reply_alternatives: Vec::new(),
+            selected_alternative: 0,
+            turn_timings: None,
+            last_turn_metrics: None,
+            status_line: None,
+            worker_errors: Vec::new(),
+            stopped_workers: Vec::new(),
         }
     }
 }
 
+/// Sent to every subscriber. Most of them only look at the state again, the rest are for
+/// listeners that care about what happened rather than what things look like now.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    StateChanged,
+    /// Speech was transcribed and handed to the llm
+    UserUtterance,
+    /// A piece of the reply, as soon as the llm generates it
+    Token(String),
+    TtsStarted,
+    TtsFinished,
+}
+
+/// Copies of the fields checked for every audio buffer or key poll, so those
+/// don't have to lock and clone the state
+#[derive(Default)]
+struct Flags {
+    muted: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+impl Flags {
+    fn store(&self, state: &State) {
+        self.muted
+            .store(state.system_mute || state.user_mute, Ordering::Relaxed);
+        self.shutting_down.store(
+            state.life_cycle_state == LifeCycleState::ShuttingDown,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Events sent since subscribing. Subscribe once outside of a loop so nothing is missed in between.
+pub struct Subscription {
+    rx: mpsc::Receiver<Event>,
+}
+
+impl Subscription {
+    pub fn recv(&self) -> Option<Event> {
+        self.rx.recv().ok()
+    }
+
+    /// Blocks until something happens, skipping events that piled up in the meantime.
+    /// For loops that only look at the latest state.
+    pub fn wait(&self) -> bool {
+        let received = self.rx.recv().is_ok();
+        while self.rx.try_recv().is_ok() {}
+        received
+    }
+
+    pub fn try_iter(&self) -> impl Iterator<Item = Event> + '_ {
+        self.rx.try_iter()
+    }
+}
+
 #[derive(Clone)]
 pub struct StateHandle {
     state: Arc<RwLock<State>>,
-    subscribers: Arc<RwLock<Vec<mpsc::Sender<()>>>>,
+    subscribers: Arc<RwLock<Vec<mpsc::Sender<Event>>>>,
+    flags: Arc<Flags>,
 }
 
 impl StateHandle {
     pub fn new() -> Self {
+        let state = State::default();
+        let flags = Flags::default();
+        flags.store(&state);
+
         Self {
-            state: Arc::new(RwLock::new(State::default())),
+            state: Arc::new(RwLock::new(state)),
             subscribers: Arc::new(RwLock::new(Vec::new())),
+            flags: Arc::new(flags),
         }
     }
 
@@ -101,6 +262,23 @@ impl StateHandle {
         self.state.read().unwrap().clone()
     }
 
+    /// Read part of the current state without cloning all of it
+    pub fn read_with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&State) -> R,
+    {
+        f(&self.state.read().unwrap())
+    }
+
+    /// Either mute is on, so the mic should be ignored
+    pub fn is_muted(&self) -> bool {
+        self.flags.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.flags.shutting_down.load(Ordering::Relaxed)
+    }
+
     /// Mutate the state and notify all subscribers
     pub fn update<F>(&self, f: F)
     where
@@ -109,21 +287,23 @@ impl StateHandle {
         {
             let mut state = self.state.write().unwrap();
             f(&mut state);
+            self.flags.store(&state);
         }
-        self.notify();
+        self.publish(Event::StateChanged);
     }
 
-    /// Subscribe to state changes, returns a receiver that gets notified on updates
-    pub fn subscribe(&self) -> mpsc::Receiver<()> {
+    /// Subscribe to state changes and events
+    pub fn subscribe(&self) -> Subscription {
         let (tx, rx) = mpsc::channel();
         self.subscribers.write().unwrap().push(tx);
-        rx
+        Subscription { rx }
     }
 
-    fn notify(&self) {
+    /// Send an event to all subscribers
+    pub fn publish(&self, event: Event) {
         let mut subs = self.subscribers.write().unwrap();
         // Remove disconnected subscribers
-        subs.retain(|tx| tx.send(()).is_ok());
+        subs.retain(|tx| tx.send(event.clone()).is_ok());
     }
 }
 
@@ -2,6 +2,11 @@ use whisper_rs::{
     FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters, install_logging_hooks,
 };
 
+/// Turns 16kHz mono speech into text. Whisper in the app, scripted transcripts in tests.
+pub trait Transcriber {
+    fn transcribe(&self, audio: &[f32]) -> anyhow::Result<String>;
+}
+
 pub struct Stt {
     ctx: WhisperContext,
 }
@@ -18,8 +23,10 @@ impl Stt {
             )?,
         })
     }
+}
 
-    pub fn transcribe(&self, audio: &[f32]) -> anyhow::Result<String> {
+impl Transcriber for Stt {
+    fn transcribe(&self, audio: &[f32]) -> anyhow::Result<String> {
         // let spec = hound::WavSpec {
         //     channels: 1,
         //     sample_rate: 16000,
@@ -54,6 +61,8 @@ impl Stt {
             }
         }
 
+        tracing::debug!(seconds = audio.len() as f32 / 16000.0, text = %out, "transcribed");
+
         Ok(out)
     }
 }
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::logging::{self, WorkerHandle};
+use crate::state::{LifeCycleState, StateHandle};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Restarts allowed per worker, so one that dies straight away doesn't spin forever
+const MAX_RESTARTS: usize = 3;
+
+/// What happens when a worker stops before the app is shutting down
+pub enum Policy {
+    /// Spawned again whenever it stops, but only up to `MAX_RESTARTS` times after failing
+    Restart(Box<dyn FnMut() -> WorkerHandle + Send>),
+    /// Nothing works without it, so the app shuts down and saves what it can
+    Critical,
+    /// The failure is shown but everything else carries on
+    Report,
+}
+
+struct Worker {
+    name: &'static str,
+    handle: Option<WorkerHandle>,
+    policy: Policy,
+    restarts: usize,
+}
+
+/// Keeps an eye on the worker threads, since a thread that dies doesn't take the app down with it
+pub struct Supervisor {
+    state: StateHandle,
+    workers: Vec<Worker>,
+}
+
+pub struct SupervisorHandle {
+    _handle: WorkerHandle,
+}
+
+impl Supervisor {
+    pub fn new(state: StateHandle) -> Self {
+        Supervisor {
+            state,
+            workers: Vec::new(),
+        }
+    }
+
+    /// Spawns a worker with `spawn`, and again whenever it stops
+    pub fn restartable<F>(&mut self, name: &'static str, mut spawn: F)
+    where
+        F: FnMut() -> WorkerHandle + Send + 'static,
+    {
+        let handle = spawn();
+        self.watch(name, handle, Policy::Restart(Box::new(spawn)));
+    }
+
+    pub fn watch(&mut self, name: &'static str, handle: WorkerHandle, policy: Policy) {
+        self.workers.push(Worker {
+            name,
+            handle: Some(handle),
+            policy,
+            restarts: 0,
+        });
+    }
+
+    pub fn spawn(self) -> SupervisorHandle {
+        let Supervisor { state, workers } = self;
+
+        let handle = logging::spawn_worker("supervisor", state.clone(), move || {
+            run_supervisor_loop(state, workers);
+            Ok(())
+        });
+
+        SupervisorHandle { _handle: handle }
+    }
+}
+
+fn run_supervisor_loop(state: StateHandle, mut workers: Vec<Worker>) {
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+
+        for worker in workers.iter_mut() {
+            if state.is_shutting_down() {
+                return;
+            }
+
+            let Some(handle) = worker.handle.take_if(|handle| handle.is_finished()) else {
+                continue;
+            };
+            // Any error has already been logged and added to the state by the worker itself
+            let failed = handle.join().unwrap_or(true);
+
+            match &mut worker.policy {
+                // Only failures use up the restarts
+                Policy::Restart(spawn) if !failed => {
+                    info!("{} finished, starting it again", worker.name);
+                    worker.handle = Some(spawn());
+                }
+                Policy::Restart(spawn) if worker.restarts < MAX_RESTARTS => {
+                    worker.restarts += 1;
+                    warn!(
+                        "restarting {} ({}/{})",
+                        worker.name, worker.restarts, MAX_RESTARTS
+                    );
+
+                    worker.handle = Some(spawn());
+                    state.update(|s| {
+                        s.worker_errors.push(format!(
+                            "{} restarted ({}/{})",
+                            worker.name, worker.restarts, MAX_RESTARTS
+                        ))
+                    });
+                }
+                Policy::Critical => {
+                    error!("{} is required, shutting down", worker.name);
+
+                    state.update(|s| {
+                        s.stopped_workers.push(worker.name);
+                        s.life_cycle_state = LifeCycleState::ShuttingDown;
+                    });
+                    return;
+                }
+                Policy::Restart(_) | Policy::Report => {
+                    error!("{} stopped for good", worker.name);
+
+                    state.update(|s| s.stopped_workers.push(worker.name));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn workers_that_finish_cleanly_dont_use_up_restarts() {
+        let state = StateHandle::new();
+        let spawned = Arc::new(AtomicUsize::new(0));
+
+        let mut supervisor = Supervisor::new(state.clone());
+        let worker_state = state.clone();
+        let worker_spawned = spawned.clone();
+        supervisor.restartable("finisher", move || {
+            worker_spawned.fetch_add(1, Ordering::SeqCst);
+            logging::spawn_worker("finisher", worker_state.clone(), || Ok(()))
+        });
+        let _ = supervisor.spawn();
+
+        let started = Instant::now();
+        while spawned.load(Ordering::SeqCst) <= MAX_RESTARTS + 1 {
+            assert!(started.elapsed() < Duration::from_secs(5), "not restarted");
+            std::thread::sleep(POLL_INTERVAL / 5);
+        }
+        state.update(|s| s.life_cycle_state = LifeCycleState::ShuttingDown);
+
+        let state = state.read();
+        assert!(state.worker_errors.is_empty());
+        assert!(state.stopped_workers.is_empty());
+    }
+}
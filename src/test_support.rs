@@ -0,0 +1,112 @@
+//! Helpers shared by the tests of every module
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::reply::TokenSource;
+use crate::state::{ConversationSnippet, LlmRole, unix_timestamp};
+use crate::tools::ToolFormat;
+
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn snippet(role: LlmRole, message: &str) -> ConversationSnippet {
+    ConversationSnippet {
+        role,
+        message: message.into(),
+        is_tool_call: false,
+        timestamp: unix_timestamp(),
+        tool_result: None,
+    }
+}
+
+/// An assistant snippet that calls tools
+pub fn tool_call(message: &str) -> ConversationSnippet {
+    ConversationSnippet {
+        is_tool_call: true,
+        ..snippet(LlmRole::Assistant, message)
+    }
+}
+
+/// A path in the temp directory that no other test uses, in this run or one running alongside it
+pub fn temp_path(name: &str, extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "local-ml-{}-{}-{}.{}",
+        name,
+        std::process::id(),
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed),
+        extension
+    ))
+}
+
+/// Writes `content` to a fresh temp file, which the test removes when it's done with it
+pub fn temp_file(name: &str, extension: &str, content: &str) -> PathBuf {
+    let path = temp_path(name, extension);
+    fs::write(&path, content).unwrap();
+    path
+}
+
+/// Replies read from a script instead of sampled from a model, a word at a time. Every round of
+/// tool calls moves on to the next reply.
+pub struct ScriptedTokens {
+    replies: VecDeque<String>,
+    round: String,
+    pieces: VecDeque<String>,
+    /// What was kept of the last reply that got cut off
+    pub cut_off_reply: Option<String>,
+}
+
+impl ScriptedTokens {
+    pub fn new(replies: &[&str]) -> Self {
+        ScriptedTokens {
+            replies: replies.iter().map(|reply| reply.to_string()).collect(),
+            round: String::new(),
+            pieces: VecDeque::new(),
+            cut_off_reply: None,
+        }
+    }
+
+    /// Starts on the next reply in the script
+    pub fn next_reply(&mut self) -> anyhow::Result<()> {
+        self.round = self
+            .replies
+            .pop_front()
+            .ok_or_else(|| anyhow::anyhow!("ran out of scripted replies"))?;
+        self.restart_round();
+        Ok(())
+    }
+
+    fn restart_round(&mut self) {
+        self.pieces = self.round.split_inclusive(' ').map(String::from).collect();
+    }
+}
+
+impl TokenSource for ScriptedTokens {
+    fn next_piece(&mut self) -> anyhow::Result<Option<String>> {
+        Ok(self.pieces.pop_front())
+    }
+
+    fn start_tool_call(&mut self, _format: ToolFormat, restart_round: bool) -> anyhow::Result<()> {
+        if restart_round {
+            self.restart_round();
+        }
+        Ok(())
+    }
+
+    fn end_tool_call(&mut self) {}
+
+    fn read_tool_results(&mut self, results: &[String]) -> anyhow::Result<String> {
+        self.next_reply()?;
+        Ok(results.join("\n"))
+    }
+
+    fn cut_off(&mut self, reply: &str) -> anyhow::Result<()> {
+        self.cut_off_reply = Some(reply.into());
+        Ok(())
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+}
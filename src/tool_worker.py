@@ -0,0 +1,306 @@
+# Runs tool calls for tool_worker.rs. It imports the tool file given as its first argument once,
+# then answers line delimited JSON-RPC requests on stdin until stdin closes. The second argument,
+# when it isn't empty, is how many seconds of CPU time each call can use. The arguments only ever
+# arrive as JSON, so nothing the llm wrote is evaluated as code:
+#     {"jsonrpc": "2.0", "id": 1, "method": "call", "params": {"function": "get_weather", "arguments": {"city": "Paris"}}}
+# Each gets one line back on stdout. A call that ran gets what it printed, its exit code and what it
+# returned as JSON, along with a short traceback if it raised. Calls that couldn't run get an error:
+#     {"jsonrpc": "2.0", "id": 1, "result": {"stdout": "", "stderr": "", "exit_code": 0, "value": {"temp": 21}, "error": null}}
+#     {"jsonrpc": "2.0", "id": 1, "error": {"code": -32602, "message": "..."}}
+import asyncio
+import contextlib
+import dataclasses
+import enum
+import importlib.util
+import inspect
+import io
+import json
+import math
+import os
+import sys
+import traceback
+import types
+import typing
+
+
+class InvalidArguments(Exception):
+    pass
+
+
+def describe(annotation):
+    # list[str] has a __name__ of just "list"
+    if typing.get_args(annotation):
+        return str(annotation).replace("typing.", "")
+    return getattr(annotation, "__name__", None) or str(annotation).replace("typing.", "")
+
+
+def check(value, annotation, name):
+    """Checks a JSON value against a type hint, converting it where JSON can't say what python means"""
+    if annotation is inspect.Parameter.empty or annotation is typing.Any:
+        return value
+
+    origin = typing.get_origin(annotation)
+    arguments = typing.get_args(annotation)
+
+    def invalid(expected=None):
+        return InvalidArguments(
+            f"{name} should be {expected or describe(annotation)}, not {json.dumps(value)}"
+        )
+
+    if origin is typing.Union or origin is types.UnionType:
+        for option in arguments:
+            try:
+                return check(value, option, name)
+            except InvalidArguments:
+                pass
+        raise invalid(" or ".join(describe(option) for option in arguments))
+
+    if annotation is None or annotation is type(None):
+        if value is None:
+            return None
+        raise invalid("None")
+
+    if origin is typing.Literal:
+        if value in arguments:
+            return value
+        raise invalid("one of " + ", ".join(json.dumps(option) for option in arguments))
+
+    if inspect.isclass(annotation) and issubclass(annotation, enum.Enum):
+        for member in annotation:
+            if value == member.value or value == member.name:
+                return member
+        raise invalid("one of " + ", ".join(json.dumps(member.value) for member in annotation))
+
+    if annotation is bool:
+        if isinstance(value, bool):
+            return value
+        raise invalid()
+
+    if annotation is int:
+        if isinstance(value, int) and not isinstance(value, bool):
+            return value
+        raise invalid()
+
+    if annotation is float:
+        if isinstance(value, (int, float)) and not isinstance(value, bool):
+            return float(value)
+        raise invalid()
+
+    if annotation is str:
+        if isinstance(value, str):
+            return value
+        raise invalid()
+
+    container = origin or annotation
+    if container in (list, tuple, set):
+        if not isinstance(value, list):
+            raise invalid()
+
+        if container is tuple and arguments and arguments[-1] is not Ellipsis:
+            if len(arguments) != len(value):
+                raise invalid()
+            return tuple(
+                check(item, item_type, f"{name}[{i}]")
+                for i, (item, item_type) in enumerate(zip(value, arguments))
+            )
+
+        item_type = arguments[0] if arguments else inspect.Parameter.empty
+        return container(check(item, item_type, f"{name}[{i}]") for i, item in enumerate(value))
+
+    if container is dict:
+        if not isinstance(value, dict):
+            raise invalid()
+
+        value_type = arguments[1] if len(arguments) == 2 else inspect.Parameter.empty
+        return {key: check(item, value_type, f"{name}[{key!r}]") for key, item in value.items()}
+
+    # Anything fancier is up to the tool itself
+    return value
+
+
+def load(path):
+    directory = os.path.dirname(os.path.abspath(path))
+    # So tools can import files next to them, like they could before
+    sys.path.insert(0, directory)
+
+    spec = importlib.util.spec_from_file_location("tools", path)
+    module = importlib.util.module_from_spec(spec)
+    spec.loader.exec_module(module)
+    return module
+
+
+PARSE_ERROR = -32700
+METHOD_NOT_FOUND = -32601
+INVALID_PARAMS = -32602
+TOOL_ERROR = -32000
+
+
+def limit_cpu(seconds):
+    """RLIMIT_CPU counts the whole life of the process, so each call gets its allowance on top of
+    what's been used so far. Going over it gets the worker killed with SIGXCPU."""
+    import resource
+
+    usage = resource.getrusage(resource.RUSAGE_SELF)
+    _, hard = resource.getrlimit(resource.RLIMIT_CPU)
+    soft = math.ceil(usage.ru_utime + usage.ru_stime) + seconds
+    if hard != resource.RLIM_INFINITY:
+        soft = min(soft, hard)
+    resource.setrlimit(resource.RLIMIT_CPU, (soft, hard))
+
+
+def to_json(value):
+    """Anything JSON can't hold directly is turned into the closest thing it can, or its repr"""
+    if dataclasses.is_dataclass(value) and not isinstance(value, type):
+        return dataclasses.asdict(value)
+    if isinstance(value, enum.Enum):
+        return value.value
+    if isinstance(value, (set, frozenset)):
+        return list(value)
+    return repr(value)
+
+
+def as_json(value):
+    # NaN and circular references can't be JSON at all
+    try:
+        return json.loads(json.dumps(value, default=to_json, allow_nan=False))
+    except ValueError:
+        return repr(value)
+
+
+def summarise(error, path):
+    """The exception and the last few lines of the tool file it went through, leaving out python's
+    own frames and the worker's, which don't mean anything to the llm"""
+    frames = [
+        frame
+        for frame in traceback.extract_tb(error.__traceback__)
+        if os.path.abspath(frame.filename) == path
+    ]
+    summary = f"{type(error).__name__}: {error}"
+    for frame in frames[-3:]:
+        summary += f"\n  line {frame.lineno}, in {frame.name}: {frame.line}"
+    return summary
+
+
+def run(module, params, cpu_secs):
+    name = params["function"]
+    if name.startswith("_"):
+        raise InvalidArguments(f"{name} isn't a tool")
+
+    function = getattr(module, name, None)
+    # Only functions written in the tool file, not ones it imported
+    if not inspect.isfunction(function) or function.__module__ != module.__name__:
+        raise InvalidArguments(f"there's no tool called {name}")
+
+    try:
+        hints = typing.get_type_hints(function)
+    except Exception:
+        hints = {}
+
+    parameters = {
+        parameter.name: parameter
+        for parameter in inspect.signature(function).parameters.values()
+        if parameter.kind not in (parameter.VAR_POSITIONAL, parameter.VAR_KEYWORD)
+    }
+    given = params.get("arguments") or {}
+
+    unknown = [argument for argument in given if argument not in parameters]
+    if unknown:
+        raise InvalidArguments(f"{name} has no argument called {', '.join(unknown)}")
+
+    missing = [
+        parameter.name
+        for parameter in parameters.values()
+        if parameter.default is parameter.empty and parameter.name not in given
+    ]
+    if missing:
+        raise InvalidArguments(f"{name} is missing {', '.join(missing)}")
+
+    kwargs = {
+        argument: check(value, hints.get(argument, parameters[argument].annotation), argument)
+        for argument, value in given.items()
+    }
+
+    if cpu_secs is not None:
+        limit_cpu(cpu_secs)
+
+    stdout, stderr = io.StringIO(), io.StringIO()
+    value, error, exit_code = None, None, 0
+    with contextlib.redirect_stdout(stdout), contextlib.redirect_stderr(stderr):
+        try:
+            value = function(**kwargs)
+            if inspect.iscoroutine(value):
+                value = asyncio.run(value)
+        except SystemExit as e:
+            # sys.exit("message") prints the message and exits with 1, like it would on its own
+            if e.code is None or isinstance(e.code, int):
+                exit_code = e.code or 0
+            else:
+                print(e.code, file=sys.stderr)
+                exit_code = 1
+        except MemoryError:
+            error, exit_code = f"{name} ran out of memory", 1
+        except Exception as e:
+            error, exit_code = summarise(e, os.path.abspath(module.__file__)), 1
+
+    return {
+        "stdout": stdout.getvalue(),
+        "stderr": stderr.getvalue(),
+        "exit_code": exit_code,
+        "value": as_json(value),
+        "error": error,
+    }
+
+
+def respond(line, module, load_error, cpu_secs):
+    try:
+        request = json.loads(line)
+    except json.JSONDecodeError as e:
+        return {"jsonrpc": "2.0", "id": None, "error": {"code": PARSE_ERROR, "message": str(e)}}
+
+    request_id = request.get("id")
+
+    def error(code, message):
+        return {"jsonrpc": "2.0", "id": request_id, "error": {"code": code, "message": message}}
+
+    if request.get("method") != "call":
+        return error(METHOD_NOT_FOUND, f"unknown method {request.get('method')}")
+    if load_error is not None:
+        return error(TOOL_ERROR, load_error)
+
+    try:
+        params = request.get("params") or {}
+        return {"jsonrpc": "2.0", "id": request_id, "result": run(module, params, cpu_secs)}
+    except InvalidArguments as e:
+        return error(INVALID_PARAMS, f"Invalid arguments: {e}")
+    except Exception as e:
+        # Checking the arguments went wrong, or the value couldn't be made into JSON
+        return error(TOOL_ERROR, f"{type(e).__name__}: {e}")
+
+
+def main():
+    # The protocol gets its own copies of stdin and stdout. Tools that read input get nothing, and
+    # anything else written to stdout (by a subprocess, say) goes to stderr instead of into a response
+    requests = os.fdopen(os.dup(0), encoding="utf-8")
+    # Output decoded with surrogateescape can't be encoded again, so it's replaced instead
+    responses = os.fdopen(os.dup(1), "w", encoding="utf-8", errors="replace")
+    devnull = os.open(os.devnull, os.O_RDONLY)
+    os.dup2(devnull, 0)
+    os.dup2(2, 1)
+
+    cpu_secs = int(sys.argv[2]) if len(sys.argv) > 2 and sys.argv[2] else None
+
+    module, load_error = None, None
+    try:
+        module = load(sys.argv[1])
+    except Exception as e:
+        load_error = f"Couldn't load {sys.argv[1]}: {type(e).__name__}: {e}"
+
+    for line in requests:
+        if not line.strip():
+            continue
+
+        responses.write(json.dumps(respond(line, module, load_error, cpu_secs), ensure_ascii=False) + "\n")
+        responses.flush()
+
+
+main()
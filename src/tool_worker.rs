@@ -0,0 +1,401 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Instant, SystemTime};
+
+use anyhow::{Context, anyhow, bail};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::config::SandboxPolicy;
+use crate::sandbox;
+use crate::tools::ToolResult;
+
+const WORKER_SCRIPT: &str = include_str!("tool_worker.py");
+
+/// A python process that imports a tool file once and runs calls from it for as long as it's
+/// up, so a call doesn't have to wait for python to start and the file's imports to load.
+/// Calls go over its stdin and stdout as line delimited JSON-RPC.
+pub struct ToolWorker {
+    path: PathBuf,
+    policy: SandboxPolicy,
+    /// When the file had last been changed as of starting, so edits get picked up
+    modified: Option<SystemTime>,
+    child: Child,
+    stdin: ChildStdin,
+    responses: mpsc::Receiver<String>,
+    next_id: u64,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: Option<u64>,
+    result: Option<ToolResult>,
+    error: Option<ResponseError>,
+}
+
+#[derive(Deserialize)]
+struct ResponseError {
+    message: String,
+}
+
+impl ToolWorker {
+    pub fn spawn(path: &Path, policy: &SandboxPolicy) -> anyhow::Result<Self> {
+        // Absolute, since the tools don't have to run next to the file
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("couldn't find {}", path.display()))?;
+        let file = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} isn't a file", path.display()))?;
+        let working_directory = match &policy.working_directory {
+            Some(directory) => PathBuf::from(directory),
+            None => path.parent().unwrap_or(Path::new("/")).to_path_buf(),
+        };
+
+        let mut command = sandbox::command("python", policy, &working_directory)?;
+        command
+            .arg("-c")
+            .arg(WORKER_SCRIPT)
+            .arg(&path)
+            .arg(
+                policy
+                    .cpu_secs
+                    .map(|secs| secs.to_string())
+                    .unwrap_or_default(),
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow!(sandbox::describe_spawn_error("python", policy, &e)))?;
+
+        let stdin = child.stdin.take().context("tool worker has no stdin")?;
+        let stdout = child.stdout.take().context("tool worker has no stdout")?;
+        let stderr = child.stderr.take().context("tool worker has no stderr")?;
+
+        let (responses_tx, responses) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if responses_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Whatever the tools print to stderr would otherwise fill up the pipe and stall the worker
+        let name = file.to_string_lossy().into_owned();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                warn!("{}: {}", name, line);
+            }
+        });
+
+        info!(path = %path.display(), ?policy, "started tool worker");
+
+        Ok(ToolWorker {
+            modified: modified(&path),
+            path,
+            policy: policy.clone(),
+            child,
+            stdin,
+            responses,
+            next_id: 0,
+        })
+    }
+
+    /// Whether it has to be started again, because it stopped or the tool file changed
+    pub fn is_stale(&mut self) -> bool {
+        if let Ok(Some(status)) = self.child.try_wait() {
+            warn!(path = %self.path.display(), %status, "tool worker stopped");
+            return true;
+        }
+
+        if modified(&self.path) != self.modified {
+            info!(path = %self.path.display(), "tool file changed, restarting its worker");
+            return true;
+        }
+
+        false
+    }
+
+    /// Runs `function` and returns what it printed and returned, or what it raised. Errors are
+    /// for calls that couldn't run at all. A call that goes over the policy's timeout, or past
+    /// `deadline`, stops the worker, since there's no way to interrupt it otherwise.
+    pub fn call(
+        &mut self,
+        function: &str,
+        arguments: &serde_json::Map<String, serde_json::Value>,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<ToolResult> {
+        self.next_id += 1;
+        let id = self.next_id;
+
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "call",
+            "params": {
+                "function": function,
+                "arguments": arguments,
+            },
+        });
+
+        if let Err(e) = writeln!(self.stdin, "{}", request).and_then(|_| self.stdin.flush()) {
+            self.stop();
+            bail!("Couldn't reach the tool worker: {}", e);
+        }
+
+        let timeout = self.policy.timeout(deadline);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let line = match self
+                .responses
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.stop();
+                    bail!(
+                        "{} took longer than {}s and was stopped",
+                        function,
+                        timeout.as_secs_f32().round()
+                    );
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let status = self.stop();
+                    bail!(self.describe_stop(function, status));
+                }
+            };
+
+            let Ok(response) = serde_json::from_str::<Response>(&line) else {
+                debug!(%line, "ignoring tool worker output that isn't a response");
+                continue;
+            };
+            if response.id != Some(id) {
+                continue;
+            }
+
+            return match (response.error, response.result) {
+                (Some(error), _) => Err(anyhow!(error.message)),
+                (None, Some(result)) => Ok(result),
+                (None, None) => Err(anyhow!(
+                    "The tool worker sent back nothing for {}",
+                    function
+                )),
+            };
+        }
+    }
+
+    fn stop(&mut self) -> Option<ExitStatus> {
+        let _ = self.child.kill();
+        self.child.wait().ok()
+    }
+
+    /// Why the worker stopped in the middle of a call, in a way the llm can pass on
+    fn describe_stop(&self, function: &str, status: Option<ExitStatus>) -> String {
+        if let Some(limit) = sandbox::describe_signal(&self.policy, function, status) {
+            return limit;
+        }
+
+        match status {
+            Some(status) => format!(
+                "The tool worker stopped while running {} ({})",
+                function, status
+            ),
+            None => format!("The tool worker stopped while running {}", function),
+        }
+    }
+}
+
+impl Drop for ToolWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_file;
+    use std::time::Duration;
+
+    fn write_tool_file(source: &str) -> PathBuf {
+        temp_file("worker", "py", source)
+    }
+
+    #[test]
+    fn keeps_the_module_loaded_between_calls() {
+        let path = write_tool_file(
+            r#"
+calls = 0
+
+def count():
+    global calls
+    calls += 1
+    return calls
+"#,
+        );
+        let mut worker = ToolWorker::spawn(&path, &SandboxPolicy::default()).unwrap();
+        let no_arguments = serde_json::Map::new();
+
+        let first = worker.call("count", &no_arguments, None).unwrap();
+        let second = worker.call("count", &no_arguments, None).unwrap();
+        let stale = worker.is_stale();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            (first.value, second.value),
+            (Some(1.into()), Some(2.into()))
+        );
+        assert!(!stale);
+    }
+
+    #[test]
+    fn restarts_after_a_timeout_or_a_change_to_the_file() {
+        let path = write_tool_file(
+            r#"
+import time
+
+def nap(seconds: float):
+    time.sleep(seconds)
+    return "awake"
+"#,
+        );
+        let policy = SandboxPolicy {
+            timeout_secs: 1,
+            ..SandboxPolicy::default()
+        };
+        let mut worker = ToolWorker::spawn(&path, &policy).unwrap();
+        let mut arguments = serde_json::Map::new();
+        arguments.insert("seconds".into(), 5.into());
+
+        let timed_out = worker.call("nap", &arguments, None);
+        let stale_after_timeout = worker.is_stale();
+
+        let mut worker = ToolWorker::spawn(&path, &policy).unwrap();
+        arguments.insert("seconds".into(), 0.into());
+        let awake = worker.call("nap", &arguments, None).unwrap();
+
+        // A turn with less time left than the policy allows cuts the call short
+        let mut rushed = ToolWorker::spawn(&path, &SandboxPolicy::default()).unwrap();
+        arguments.insert("seconds".into(), 5.into());
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let cut_short = rushed.call("nap", &arguments, Some(deadline));
+
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let stale_after_change = worker.is_stale();
+        let _ = fs::remove_file(&path);
+
+        assert!(
+            timed_out
+                .unwrap_err()
+                .to_string()
+                .contains("took longer than 1s")
+        );
+        assert!(stale_after_timeout);
+        assert!(
+            cut_short
+                .unwrap_err()
+                .to_string()
+                .contains("took longer than 1s")
+        );
+        assert_eq!(awake.value, Some("awake".into()));
+        assert!(stale_after_change);
+    }
+
+    #[test]
+    fn keeps_output_exit_codes_and_tracebacks_apart() {
+        let path = write_tool_file(
+            r#"
+import sys
+
+def noisy():
+    print("to stdout")
+    print("to stderr", file=sys.stderr)
+    return {"ok": True, "items": {1, 2}}
+
+def quit():
+    sys.exit(3)
+
+def divide(n: int):
+    return halve(n)
+
+def halve(n):
+    return 1 / n
+"#,
+        );
+        let mut worker = ToolWorker::spawn(&path, &SandboxPolicy::default()).unwrap();
+        let no_arguments = serde_json::Map::new();
+        let mut zero = serde_json::Map::new();
+        zero.insert("n".into(), 0.into());
+
+        let noisy = worker.call("noisy", &no_arguments, None).unwrap();
+        let quit = worker.call("quit", &no_arguments, None).unwrap();
+        let divided = worker.call("divide", &zero, None).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(noisy.stdout, "to stdout\n");
+        assert_eq!(noisy.stderr, "to stderr\n");
+        assert_eq!(noisy.exit_code, Some(0));
+        assert_eq!(
+            noisy.value,
+            Some(serde_json::json!({"ok": true, "items": [1, 2]}))
+        );
+
+        assert_eq!(quit.exit_code, Some(3));
+        assert_eq!(quit.error, None);
+
+        let error = divided.error.unwrap();
+        assert_eq!(divided.exit_code, Some(1));
+        assert!(error.starts_with("ZeroDivisionError: division by zero"));
+        assert!(error.contains("in halve: return 1 / n"));
+        assert!(!error.contains("tool_worker"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stops_calls_that_go_over_their_cpu_time_and_hides_the_environment() {
+        let path = write_tool_file(
+            r#"
+import os
+
+def spin():
+    while True:
+        pass
+
+def home():
+    return os.environ.get("HOME", "nowhere")
+"#,
+        );
+        let policy = SandboxPolicy {
+            cpu_secs: Some(1),
+            allowed_env: Some(Vec::new()),
+            ..SandboxPolicy::default()
+        };
+        let mut worker = ToolWorker::spawn(&path, &policy).unwrap();
+        let no_arguments = serde_json::Map::new();
+
+        let home = worker.call("home", &no_arguments, None).unwrap();
+        let spun = worker.call("spin", &no_arguments, None);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(home.value, Some("nowhere".into()));
+        assert!(spun.unwrap_err().to_string().contains("1s of CPU time"));
+    }
+}
@@ -1,8 +1,21 @@
-use anyhow::Error;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::{collections::HashMap, process::Command};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
 
+use crate::builtins::Builtins;
+use crate::config::{McpServer, ToolPermissions, ToolSandbox};
+use crate::manifest::{self, ExecutableTool};
+use crate::mcp::McpClient;
+use crate::tool_worker::ToolWorker;
+
+const INTROSPECT_SCRIPT: &str = include_str!("introspect.py");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ToolFormat {
     JsonStandard,
     PythonCall,
@@ -21,64 +34,89 @@ pub struct Tool {
 }
 
 impl Tool {
-    pub fn verify_command(&self, command: &str) -> anyhow::Result<bool> {
-        let in_params = Regex::new(r"\(.*\)")?;
+    /// A tool described by a JSON schema for its arguments, like manifests and MCP servers have
+    pub fn from_schema(name: &str, description: &str, schema: &serde_json::Value) -> Self {
+        use serde_json::Value;
+
+        let properties = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(name, schema)| {
+                // A list of types can't be put in one word, so anything goes
+                let kind = schema.get("type").and_then(Value::as_str).unwrap_or("");
+                let description = schema
+                    .get("description")
+                    .and_then(Value::as_str)
+                    .unwrap_or("");
+                let description = match schema.get("default") {
+                    Some(default) if description.is_empty() => format!("Defaults to {}", default),
+                    Some(default) => format!("{} (defaults to {})", description, default),
+                    None => description.into(),
+                };
+
+                (name.clone(), (kind.into(), description))
+            })
+            .collect();
+
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(String::from)
+            .collect();
+
+        Tool {
+            name: name.into(),
+            description: description.into(),
+            properties,
+            required,
+        }
+    }
 
-        if let Some(command_name) = command.split("(").next()
-            && command_name != self.name
+    /// Catches made up and missing arguments before python gets started.
+    /// Their types are checked by the runner, which can see the type hints
+    pub fn check_arguments(
+        &self,
+        arguments: &serde_json::Map<String, serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        if let Some(unknown) = arguments
+            .keys()
+            .find(|name| !self.properties.contains_key(*name))
         {
-            return Ok(false);
+            anyhow::bail!(
+                "Invalid arguments: {} has no argument called {}",
+                self.name,
+                unknown
+            );
         }
 
-        if let Some(args) = in_params.find(command) {
-            let args = args.as_str();
-            let args = &args[1..args.len() - 1];
-
-            let mut vec_args: Vec<String> = Vec::new();
-
-            let mut double_quote_depth = 0;
-            let mut single_quote_depth = 0;
-            let mut that_lil_guy_depth = 0;
-            let mut last_match_index = 0;
-
-            for (i, ch) in args.char_indices() {
-                match ch {
-                    '"' => double_quote_depth += 1,
-                    '\'' => single_quote_depth += 1,
-                    '`' => that_lil_guy_depth += 1,
-                    ',' if double_quote_depth % 2 == 0
-                        && single_quote_depth % 2 == 0
-                        && that_lil_guy_depth % 2 == 0 => {
-                            vec_args.push(args[last_match_index..i].into());
-                            last_match_index = i;
-                        }
-
-                    _ => {}
-                }
-            }
-
-            for arg in vec_args {
-                let eq: Vec<&str> = arg.trim().split("=").collect();
-
-                let name = eq[0];
-                let param = &eq[1..eq.len()];
-
-                if !self.properties.contains_key(name)
-                    || !verify_arg_type_and_value(param.join("="))?
-                {
-                    return Ok(false);
-                }
-            }
+        let missing: Vec<&str> = self
+            .required
+            .iter()
+            .filter(|name| !arguments.contains_key(*name))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "Invalid arguments: {} is missing {}",
+                self.name,
+                missing.join(", ")
+            );
         }
 
-        Ok(true)
+        Ok(())
     }
 
     pub fn to_json(&self) -> serde_json::Value {
         let mut props = serde_json::Map::new();
 
         let parse_type = |t: &str| -> String {
-            match t {
+            // Only the outer type, so `list[str]` is still an array
+            match t.split('[').next().unwrap_or(t).trim() {
                 "str" => "string".into(),
                 "int" => "integer".into(),
                 "float" => "number".into(),
@@ -92,13 +130,17 @@ impl Tool {
         };
 
         for (name, (prop_type, desc)) in &self.properties {
-            props.insert(
-                name.clone(),
+            // Untyped arguments take anything
+            let prop = if prop_type.is_empty() {
+                serde_json::json!({ "description": desc })
+            } else {
                 serde_json::json!({
                     "type": parse_type(prop_type),
                     "description": desc
-                }),
-            );
+                })
+            };
+
+            props.insert(name.clone(), prop);
         }
 
         serde_json::json!({
@@ -116,79 +158,341 @@ impl Tool {
     }
 }
 
+/// A call the llm made, read from whichever format it was written in
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ToolCall {
+    /// A call written as JSON, like `{"name": "...", "arguments": {...}}` with the arguments under `key`
+    fn from_json(json: &serde_json::Value, key: &str) -> Option<Self> {
+        Some(ToolCall {
+            name: json.get("name")?.as_str()?.into(),
+            arguments: json.get(key)?.as_object()?.clone(),
+        })
+    }
+}
+
+/// Written like a python call, `name(a="x", b=2)`, for logs and for the user to look over
+impl std::fmt::Display for ToolCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arguments = self
+            .arguments
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        write!(f, "{}({})", self.name, arguments)
+    }
+}
+
 pub struct Tools {
     pub tools: Vec<Tool>,
-    pub tool_file_path: String,
+    /// The python file the functions come from, if there is one
+    pub tool_file_path: Option<String>,
+    /// Tools from manifests, which run as a program of their own on every call
+    executables: HashMap<String, ExecutableTool>,
+    /// MCP servers that stay up for as long as the assistant, and which one each of their tools is on
+    mcp_servers: Vec<Mutex<McpClient>>,
+    mcp_tools: HashMap<String, usize>,
+    /// The assistant's built-in tools, which run right here rather than in a sandbox, and the
+    /// names they got to keep
+    builtins: Option<Builtins>,
+    builtin_tools: HashSet<String>,
+    pub sandbox: ToolSandbox,
+    pub permissions: ToolPermissions,
+    /// Started on first use and kept for the calls after. Tools with a sandbox policy of their
+    /// own get a worker of their own, keyed by name, the rest share the one under `None`.
+    workers: Mutex<HashMap<Option<String>, ToolWorker>>,
 }
 
-pub trait ToJson {
-    fn to_json(&self) -> Result<String, serde_json::Error>;
-}
+impl Tools {
+    fn new(tools: Vec<Tool>, tool_file_path: Option<String>) -> Self {
+        Tools {
+            tools,
+            tool_file_path,
+            executables: HashMap::new(),
+            mcp_servers: Vec::new(),
+            mcp_tools: HashMap::new(),
+            builtins: None,
+            builtin_tools: HashSet::new(),
+            sandbox: ToolSandbox::default(),
+            permissions: ToolPermissions::default(),
+            workers: Mutex::new(HashMap::new()),
+        }
+    }
 
-impl ToJson for Vec<Tool> {
-    fn to_json(&self) -> Result<std::string::String, serde_json::Error> {
-        let tools: Vec<serde_json::Value> = self.iter().map(|t| t.to_json()).collect();
-        serde_json::to_string(&tools)
+    /// Adds tools from a manifest. Names that are already taken keep the tool they had.
+    pub fn add_executables(&mut self, executables: Vec<(Tool, ExecutableTool)>) {
+        for (tool, executable) in executables {
+            if self.is_taken(&tool.name) {
+                continue;
+            }
+
+            self.executables.insert(tool.name.clone(), executable);
+            self.tools.push(tool);
+        }
+    }
+
+    /// Adds the enabled built-in tools, apart from any whose name is already taken
+    pub fn add_builtins(&mut self, builtins: Builtins) {
+        for tool in builtins.tools() {
+            if self.is_taken(&tool.name) {
+                continue;
+            }
+
+            self.builtin_tools.insert(tool.name.clone());
+            self.tools.push(tool);
+        }
+
+        self.builtins = Some(builtins);
+    }
+
+    /// Starts an MCP server and adds the tools it lists, under the server's own sandbox policy
+    /// if it has one
+    pub fn add_mcp_server(&mut self, server: &McpServer) -> anyhow::Result<()> {
+        let mut client = McpClient::start(server, self.sandbox.policy_for(&server.name))?;
+        let tools = client.list_tools()?;
+        let index = self.mcp_servers.len();
+
+        tracing::info!(server = %server.name, tools = tools.len(), "added tools from MCP server");
+
+        for tool in tools {
+            if self.is_taken(&tool.name) {
+                continue;
+            }
+
+            self.mcp_tools.insert(tool.name.clone(), index);
+            self.tools.push(tool);
+        }
+
+        self.mcp_servers.push(Mutex::new(client));
+        Ok(())
+    }
+
+    fn is_taken(&self, name: &str) -> bool {
+        let taken = self.tools.iter().any(|tool| tool.name == name);
+        if taken {
+            tracing::warn!(
+                "there's already a tool called {}, skipping the new one",
+                name
+            );
+        }
+        taken
+    }
+
+    /// Starts python ahead of the first call, so that one isn't any slower than the rest
+    pub fn start_worker(&self) -> anyhow::Result<()> {
+        if self.tool_file_path.is_none() {
+            return Ok(());
+        }
+
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        self.worker(&mut workers, None).map(|_| ())
+    }
+
+    /// Runs `tool` wherever it lives. Anything that can take a while is stopped at `deadline`.
+    fn call(
+        &self,
+        tool: &str,
+        arguments: &serde_json::Map<String, serde_json::Value>,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<ToolResult> {
+        if self.builtin_tools.contains(tool)
+            && let Some(builtins) = &self.builtins
+            && let Some(result) = builtins.call(tool, arguments)
+        {
+            return Ok(result);
+        }
+
+        if let Some(executable) = self.executables.get(tool) {
+            return executable.run(arguments, self.sandbox.policy_for(tool), deadline);
+        }
+
+        if let Some(&server) = self.mcp_tools.get(tool) {
+            return self.mcp_servers[server]
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .call(tool, arguments, deadline);
+        }
+
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        self.worker(&mut workers, Some(tool))?
+            .call(tool, arguments, deadline)
+    }
+
+    /// The worker that runs `tool`, started again if it stopped or the file changed
+    fn worker<'a>(
+        &self,
+        workers: &'a mut HashMap<Option<String>, ToolWorker>,
+        tool: Option<&str>,
+    ) -> anyhow::Result<&'a mut ToolWorker> {
+        let path = self
+            .tool_file_path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("There's no python tool file"))?;
+        let key = tool
+            .filter(|tool| self.sandbox.tools.contains_key(*tool))
+            .map(String::from);
+        let policy = tool.map_or(&self.sandbox.policy, |tool| self.sandbox.policy_for(tool));
+
+        if workers.get_mut(&key).is_none_or(ToolWorker::is_stale) {
+            // Stopped before its replacement loads the file
+            workers.remove(&key);
+            let worker = ToolWorker::spawn(Path::new(path), policy)?;
+            workers.insert(key.clone(), worker);
+        }
+
+        workers
+            .get_mut(&key)
+            .ok_or_else(|| anyhow::anyhow!("The tool worker isn't running"))
     }
 }
 
-fn verify_arg_type_and_value(value: String) -> anyhow::Result<bool> {
-    let str_match = Regex::new(r#"^"([^"]|\\")*[^"\\]"$"#)?;
-    let int_match = Regex::new(r#"^\d*\d$"#)?;
-    let float_match = Regex::new(r#"^\d*\.\d*\d$"#)?;
-    let bool_match = Regex::new(r#"^(True|False)$"#)?;
+/// Everything a tool call left behind. Kept on the tool's snippet in the conversation, while
+/// the llm gets it as text from `for_llm`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the tool never ran, because it wasn't allowed to
+    pub exit_code: Option<i32>,
+    /// What the function returned, as JSON
+    pub value: Option<serde_json::Value>,
+    /// The exception it raised and where, or why it wasn't run
+    pub error: Option<String>,
+    /// Whether any of it was cut down to fit `max_result_tokens`
+    #[serde(default)]
+    pub truncated: bool,
+}
 
-    // let list_match = |v: String| if let Ok(reg) = Regex::new(r#"^\[\]$"#) && reg.is_match(&value) && value.split(",").all(|v| verify_arg_type_and_value(None, v.into()).unwrap_or(false)) {true} else {false};
-    // let dict_match = |v: String| if let Ok(reg) = Regex::new(r#"^\{\}$"#) && reg.is_match(&value) && value.split(",").all(|v| if let Some(v) = v.split(":").last() && verify_arg_type_and_value(None, v.into()).unwrap_or(false) {true} else {false}) {true} else {false};
+/// Cutting stops after this many goes even if it still doesn't fit, the llm can cope
+const MAX_TRUNCATION_ROUNDS: usize = 16;
 
-    {
-        if str_match.is_match(&value)
-            || int_match.is_match(&value)
-            || float_match.is_match(&value)
-            || bool_match.is_match(&value)
-        // list_match(value.clone()) ||
-        // dict_match(value.clone())
+impl ToolResult {
+    /// For calls that were refused, with the reason for the llm to pass on
+    pub fn not_run(reason: String) -> Self {
+        ToolResult {
+            error: Some(reason),
+            ..ToolResult::default()
+        }
+    }
+
+    /// What the llm reads. A tool that just returns something comes out as only that, anything
+    /// else it did gets labelled after it.
+    pub fn for_llm(&self) -> String {
+        let mut parts = Vec::new();
+
+        if !self.stdout.trim().is_empty() {
+            parts.push(self.stdout.trim_end().to_string());
+        }
+        match &self.value {
+            None | Some(serde_json::Value::Null) => {}
+            Some(serde_json::Value::String(text)) => parts.push(text.clone()),
+            Some(value) => parts.push(value.to_string()),
+        }
+        if !self.stderr.trim().is_empty() {
+            parts.push(format!("stderr:\n{}", self.stderr.trim_end()));
+        }
+        if let Some(code) = self.exit_code
+            && code != 0
         {
-            return Ok(true);
+            parts.push(format!("Exit code: {}", code));
+        }
+        if let Some(error) = &self.error {
+            parts.push(if self.exit_code.is_some() {
+                format!("Error: {}", error)
+            } else {
+                error.clone()
+            });
+        }
+        if self.truncated {
+            parts.push("(Some of the output was left out, it was too long)".into());
         }
+
+        parts.join("\n")
     }
 
-    Ok(false)
+    /// Cuts the middle out of the longest of stdout, stderr and the value until `for_llm` fits
+    /// in `token_budget`. Tracebacks are already short, so they're left alone.
+    pub fn truncate_to(&mut self, token_budget: usize, count_tokens: impl Fn(&str) -> usize) {
+        for _ in 0..MAX_TRUNCATION_ROUNDS {
+            if count_tokens(&self.for_llm()) <= token_budget {
+                return;
+            }
+
+            let value = match &self.value {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(text)) => text.clone(),
+                Some(value) => value.to_string(),
+            };
+            let longest = self.stdout.len().max(self.stderr.len()).max(value.len());
+            if longest == 0 {
+                return;
+            }
+
+            self.truncated = true;
+            if longest == self.stdout.len() {
+                self.stdout = cut_middle(&self.stdout);
+            } else if longest == self.stderr.len() {
+                self.stderr = cut_middle(&self.stderr);
+            } else {
+                // Half a JSON document isn't JSON any more
+                self.value = Some(serde_json::Value::String(cut_middle(&value)));
+            }
+        }
+    }
 }
 
-pub fn run_tool(tools: &Tools, command: &str) -> anyhow::Result<String> {
-    // Validate the command is safe before executing
-    if tools.tools.iter().all(|el| {
-        if let Ok(val) = el.verify_command(command) {
-            !val
-        } else {
-            true
-        }
-    }) {
-        return Err(Error::msg(
-            "Command forbidden\nFor safety, only boolean, float, int, and string params are allowed.\nThe programmer also notes that he is lazy and can't be bothered to add more.",
-        ));
-    }
-
-    let mut segments: Vec<&str> = tools.tool_file_path.split("/").collect();
-    let file = segments.pop().unwrap_or_default();
-
-    let command = Command::new("python")
-        .current_dir(segments.join("/"))
-        .args([
-            "-c",
-            &format!(
-                "from {} import *; print({})",
-                file.strip_suffix(".py").unwrap_or("tools"),
-                command
-            ),
-        ])
-        .output()?;
+/// Keeps the first and last quarter, since output tends to matter most where it starts and ends
+fn cut_middle(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let keep = chars.len() / 4;
+    let head: String = chars[..keep].iter().collect();
+    let tail: String = chars[chars.len() - keep..].iter().collect();
+
+    format!(
+        "{}\n[... {} characters left out ...]\n{}",
+        head,
+        chars.len() - 2 * keep,
+        tail
+    )
+}
 
-    Ok(String::from_utf8(command.stdout)?)
+pub trait ToJson {
+    fn to_json(&self) -> Result<String, serde_json::Error>;
+}
+
+impl ToJson for Vec<Tool> {
+    fn to_json(&self) -> Result<std::string::String, serde_json::Error> {
+        let tools: Vec<serde_json::Value> = self.iter().map(|t| t.to_json()).collect();
+        serde_json::to_string(&tools)
+    }
+}
+
+/// Runs a call from the llm in wherever its tool lives. The arguments go to python as JSON and
+/// are checked against the function's type hints there, so nothing the llm wrote is evaluated.
+/// A call still going at `deadline` is stopped, even if its sandbox policy would give it longer.
+pub fn run_tool(
+    tools: &Tools,
+    call: &ToolCall,
+    deadline: Option<Instant>,
+) -> anyhow::Result<ToolResult> {
+    let tool = tools
+        .tools
+        .iter()
+        .find(|tool| tool.name == call.name)
+        .ok_or_else(|| anyhow::anyhow!("There's no tool called {}", call.name))?;
+    tool.check_arguments(&call.arguments)?;
+
+    tools.call(&call.name, &call.arguments, deadline)
 }
 
-pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<String> {
+/// Every call in `text` written in `format`, in the order they were made
+pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<Vec<ToolCall>> {
     let trimmed = text.trim();
 
     match format {
@@ -196,25 +500,18 @@ pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<String> {
             // {"name": "function_name", "parameters": {...}}
             serde_json::from_str::<serde_json::Value>(trimmed)
                 .ok()
-                .and_then(|json| {
-                    let name = json.get("name")?.as_str()?;
-                    let params = json.get("parameters")?.as_object()?;
-
-                    let args = params
-                        .iter()
-                        .map(|(k, v)| format!("{}={}", k, serde_json::to_string(v).unwrap()))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-
-                    Some(format!("{}({})", name, args))
-                })
+                .and_then(|json| ToolCall::from_json(&json, "parameters"))
+                .map(|call| vec![call])
         }
 
         ToolFormat::PythonCall => {
             // <|python_tag|>function_name.call(arg1="val1", arg2="val2")
             trimmed.find("<|python_tag|>").and_then(|start| {
                 let call_str = &trimmed[start + 14..].trim();
-                call_str.rfind(')').map(|end| call_str[..=end].to_string())
+                call_str
+                    .rfind(')')
+                    .and_then(|end| parse_call(&call_str[..=end]))
+                    .map(|call| vec![call])
             })
         }
 
@@ -226,20 +523,8 @@ pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<String> {
                     let json_str = &trimmed[json_start..end];
                     serde_json::from_str::<serde_json::Value>(json_str)
                         .ok()
-                        .and_then(|json| {
-                            let name = json.get("name")?.as_str()?;
-                            let params = json.get("arguments")?.as_object()?;
-
-                            let args = params
-                                .iter()
-                                .map(|(k, v)| {
-                                    format!("{}={}", k, serde_json::to_string(v).unwrap())
-                                })
-                                .collect::<Vec<_>>()
-                                .join(", ");
-
-                            Some(format!("{}({})", name, args))
-                        })
+                        .and_then(|json| ToolCall::from_json(&json, "arguments"))
+                        .map(|call| vec![call])
                 })
             })
         }
@@ -248,40 +533,32 @@ pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<String> {
             // <|tool_call_start|>[function_name(args)]<|tool_call_end|>
             trimmed.find("<|tool_call_start|>").and_then(|start| {
                 let call_start = start + 19; // length of "<|tool_call_start|>"
-                trimmed.find("<|tool_call_end|>").map(|end| {
+                trimmed.find("<|tool_call_end|>").and_then(|end| {
                     let inner = trimmed[call_start..end].trim();
                     // Strip surrounding brackets if present
                     let inner = inner.strip_prefix('[').unwrap_or(inner);
                     let inner = inner.strip_suffix(']').unwrap_or(inner);
-                    inner.to_string()
+                    split_tool_calls(inner)
+                        .into_iter()
+                        .map(parse_call)
+                        .collect()
                 })
             })
         }
 
         ToolFormat::ToolCallXml => {
-            // <tool_call>{"name": "...", "arguments": {...}}</tool_call>
-            trimmed.find("<tool_call>").and_then(|start| {
-                let json_start = start + 11; // length of "<tool_call>"
-                trimmed.find("</tool_call>").and_then(|end| {
-                    let json_str = trimmed[json_start..end].trim();
-                    serde_json::from_str::<serde_json::Value>(json_str)
-                        .ok()
-                        .and_then(|json| {
-                            let name = json.get("name")?.as_str()?;
-                            let params = json.get("arguments")?.as_object()?;
-
-                            let args = params
-                                .iter()
-                                .map(|(k, v)| {
-                                    format!("{}={}", k, serde_json::to_string(v).unwrap())
-                                })
-                                .collect::<Vec<_>>()
-                                .join(", ");
-
-                            Some(format!("{}({})", name, args))
-                        })
+            // <tool_call>{"name": "...", "arguments": {...}}</tool_call>, once for every call
+            let calls: Vec<ToolCall> = trimmed
+                .split("<tool_call>")
+                .skip(1)
+                .map(|block| {
+                    let json_str = block.split("</tool_call>").next()?.trim();
+                    let json = serde_json::from_str::<serde_json::Value>(json_str).ok()?;
+                    ToolCall::from_json(&json, "arguments")
                 })
-            })
+                .collect::<Option<Vec<_>>>()?;
+
+            if calls.is_empty() { None } else { Some(calls) }
         }
 
         ToolFormat::ToolCallXmlFunction => {
@@ -303,7 +580,7 @@ pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<String> {
                         el[name_start..].find('>').map(|name_end| {
                             let name = &el[name_start..name_start + name_end];
                             // Extract all <parameter=KEY>\nVALUE\n</parameter> blocks
-                            let mut args = Vec::new();
+                            let mut arguments = serde_json::Map::new();
                             let mut search = &el[name_start + name_end..];
                             while let Some(p_start) = search.find("<parameter=") {
                                 let key_start = p_start + 11;
@@ -313,13 +590,7 @@ pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<String> {
                                     let val_start = key_start + key_end + 1;
                                     search[val_start..].find("</parameter>").map(|val_end| {
                                         let value = search[val_start..val_start + val_end].trim();
-                                        // Try to keep value as JSON, else quote as string
-                                        let json_val = serde_json::Value::String(value.to_string());
-                                        args.push(format!(
-                                            "{}={}",
-                                            key,
-                                            serde_json::to_string(&json_val).unwrap()
-                                        ));
+                                        arguments.insert(key.into(), value.into());
                                         search = &search[val_start + val_end + 12..];
                                     })
                                 });
@@ -327,12 +598,14 @@ pub fn parse_tool_call(text: &str, format: ToolFormat) -> Option<String> {
                                     break;
                                 } // avoid infinite loop on parse failure
                             }
-                            format!("{}({})", name, args.join(", "))
+                            ToolCall {
+                                name: name.into(),
+                                arguments,
+                            }
                         })
                     })
                 })
                 .collect::<Option<Vec<_>>>()
-                .map(|v| v.join(","))
         }
     }
 }
@@ -346,11 +619,32 @@ pub fn supports_tools(chat_template: &str) -> bool {
         || chat_template.contains("<tool_call>")
 }
 
-pub fn is_start_of_tool_call(text: &str) -> bool {
-    text.contains("<|tool_call_start|>")
-        || text.contains("<|python_tag|>")
-        || text.contains("functools[")
-        || text.contains("<tool_call>")
+const TOOL_CALL_STARTS: [(&str, ToolFormat); 4] = [
+    ("<|tool_call_start|>", ToolFormat::ToolCallTags),
+    ("<|python_tag|>", ToolFormat::PythonCall),
+    ("functools[", ToolFormat::Functools),
+    ("<tool_call>", ToolFormat::ToolCallXml),
+];
+
+/// The format of the first tool call started in `text`, going by the marker it starts with.
+/// `<tool_call>` starts both kinds of XML call, so those come back as `ToolCallXml`. Plain JSON
+/// calls have no marker, so they're never found here.
+pub fn tool_call_marker(text: &str) -> Option<ToolFormat> {
+    TOOL_CALL_STARTS
+        .iter()
+        .filter_map(|(start, format)| text.find(start).map(|position| (position, *format)))
+        .min_by_key(|(position, _)| *position)
+        .map(|(_, format)| format)
+}
+
+/// Whatever the llm said before it started calling tools. Plain JSON calls are the whole
+/// message, so there's nothing before those.
+pub fn text_before_tool_call(text: &str) -> &str {
+    TOOL_CALL_STARTS
+        .iter()
+        .filter_map(|(start, _)| text.find(start))
+        .min()
+        .map_or("", |start| text[..start].trim())
 }
 
 pub fn is_tool_call_complete(text: &str) -> bool {
@@ -360,27 +654,184 @@ pub fn is_tool_call_complete(text: &str) -> bool {
     if text.contains("<tool_call>") && text.contains("</tool_call>") {
         return true;
     }
-    if text.contains("<|python_tag|>")
-        && let Some(pos) = text.find("<|python_tag|>")
-            && text[pos..].contains(')') {
-                return true;
+    if let Some(pos) = text.find("<|python_tag|>")
+        && closing_bracket(&text[pos + 14..], 0).is_some()
+    {
+        return true;
+    }
+    if let Some(pos) = text.find("functools[")
+        && closing_bracket(&text[pos + 10..], 1).is_some()
+    {
+        return true;
+    }
+    if text.trim().starts_with('{')
+        && text.trim().ends_with('}')
+        && let Ok(json) = serde_json::from_str::<serde_json::Value>(text.trim())
+        && json.get("name").and_then(|v| v.as_str()).is_some()
+        && json.get("parameters").is_some()
+    {
+        return true;
+    }
+    false
+}
+
+/// Where the brackets opened in `text`, along with `depth` already open before it, are all
+/// closed again. Brackets inside quoted strings don't count.
+fn closing_bracket(text: &str, mut depth: usize) -> Option<usize> {
+    let mut opened = depth > 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for (index, ch) in text.char_indices() {
+        if let Some(open_quote) = quote {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == open_quote {
+                quote = None;
             }
-    if text.contains("functools[")
-        && let Some(pos) = text.find("functools[")
-            && text[pos..].contains(']') {
-                return true;
+            continue;
+        }
+
+        match ch {
+            '"' | '\'' => quote = Some(ch),
+            '(' | '[' | '{' => {
+                depth += 1;
+                opened = true;
             }
-    if text.trim().starts_with('{') && text.trim().ends_with('}')
-        && let Ok(json) = serde_json::from_str::<serde_json::Value>(text.trim())
-            && json.get("name").and_then(|v| v.as_str()).is_some()
-                && json.get("parameters").is_some()
-            {
-                return true;
+            ')' | ']' | '}' => {
+                depth = depth.saturating_sub(1);
+                if opened && depth == 0 {
+                    return Some(index);
+                }
             }
-    false
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[derive(Deserialize)]
+struct IntrospectedFunction {
+    name: String,
+    description: String,
+    parameters: Vec<IntrospectedParameter>,
 }
 
+#[derive(Deserialize)]
+struct IntrospectedParameter {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    description: String,
+    default: Option<String>,
+    required: bool,
+}
+
+/// Everything an assistant can call: the functions in its python file, then the tools in each
+/// manifest, then the ones on each MCP server, then the built-in ones, in order. `None` if it has
+/// none of them.
+pub fn load_tools(
+    tool_path: Option<&str>,
+    manifests: &[String],
+    mcp_servers: &[McpServer],
+    builtins: Option<Builtins>,
+    sandbox: &ToolSandbox,
+) -> Option<Tools> {
+    if tool_path.is_none() && manifests.is_empty() && mcp_servers.is_empty() && builtins.is_none() {
+        return None;
+    }
+
+    let mut tools = match tool_path {
+        Some(path) => parse_python_functions(path.into()),
+        None => Tools::new(Vec::new(), None),
+    };
+    tools.sandbox = sandbox.clone();
+
+    for path in manifests {
+        match manifest::load(Path::new(path)) {
+            Ok(executables) => tools.add_executables(executables),
+            Err(e) => tracing::warn!("couldn't load the tool manifest {}: {:?}", path, e),
+        }
+    }
+
+    for server in mcp_servers {
+        if let Err(e) = tools.add_mcp_server(server) {
+            tracing::warn!("couldn't use the MCP server {}: {:?}", server.name, e);
+        }
+    }
+
+    if let Some(builtins) = builtins {
+        tools.add_builtins(builtins);
+    }
+
+    Some(tools)
+}
+
+/// Finds the tools in a python file, with argument types, defaults and descriptions
+/// from the docstring. Falls back to a much rougher regex if python can't be run.
 pub fn parse_python_functions(directory: String) -> Tools {
+    match introspect_python_functions(&directory) {
+        Ok(tools) => tools,
+        Err(e) => {
+            tracing::warn!(
+                "couldn't introspect {} with python, falling back to regex: {:?}",
+                directory,
+                e
+            );
+            parse_python_functions_with_regex(directory)
+        }
+    }
+}
+
+/// Has python parse the file (without running it) and describe each public top level function
+fn introspect_python_functions(path: &str) -> anyhow::Result<Tools> {
+    let output = Command::new("python")
+        .args(["-c", INTROSPECT_SCRIPT, path])
+        .output()?;
+
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let functions: Vec<IntrospectedFunction> = serde_json::from_slice(&output.stdout)?;
+
+    let tools = functions
+        .into_iter()
+        .map(|function| {
+            let mut properties = HashMap::new();
+            let mut required = Vec::new();
+
+            for parameter in function.parameters {
+                let description = match parameter.default {
+                    Some(default) if parameter.description.is_empty() => {
+                        format!("Defaults to {}", default)
+                    }
+                    Some(default) => format!("{} (defaults to {})", parameter.description, default),
+                    None => parameter.description,
+                };
+
+                if parameter.required {
+                    required.push(parameter.name.clone());
+                }
+                properties.insert(parameter.name, (parameter.kind, description));
+            }
+
+            Tool {
+                name: function.name,
+                description: function.description,
+                properties,
+                required,
+            }
+        })
+        .collect();
+
+    Ok(Tools::new(tools, Some(path.into())))
+}
+
+fn parse_python_functions_with_regex(directory: String) -> Tools {
     let content = fs::read_to_string(&directory).expect("Failed to read file");
 
     // Match: def function_name(args):
@@ -404,15 +855,12 @@ pub fn parse_python_functions(directory: String) -> Tools {
         });
     }
 
-    Tools {
-        tool_file_path: directory,
-        tools,
-    }
+    Tools::new(tools, Some(directory))
 }
 
 
-/// Tries all tool call formats and returns the parsed command if found
-pub fn try_parse_tool_call(text: &str) -> Option<(ToolFormat, String)> {
+/// Tries all tool call formats and returns the calls in the first one that matches
+pub fn try_parse_tool_call(text: &str) -> Option<(ToolFormat, Vec<ToolCall>)> {
     if let Some(cmd) = parse_tool_call(text, ToolFormat::JsonStandard) {
         return Some((ToolFormat::JsonStandard, cmd));
     }
@@ -434,39 +882,93 @@ pub fn try_parse_tool_call(text: &str) -> Option<(ToolFormat, String)> {
     None
 }
 
-/// Splits multiple tool calls (e.g., "func1(), func2(arg=1)") into individual calls
-pub fn split_tool_calls(calls: &str) -> Vec<String> {
+/// Splits multiple tool calls (e.g., "func1(), func2(arg=1)") into individual calls.
+/// Brackets and commas inside quoted arguments are part of the argument.
+fn split_tool_calls(calls: &str) -> Vec<&str> {
     let mut results = Vec::new();
-    let mut current = String::new();
-    let mut paren_depth = 0;
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for (index, ch) in calls.char_indices() {
+        if let Some(open_quote) = quote {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == open_quote {
+                quote = None;
+            }
+            continue;
+        }
 
-    for ch in calls.chars() {
         match ch {
-            '(' => {
-                paren_depth += 1;
-                current.push(ch);
+            '"' | '\'' => quote = Some(ch),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                results.push(calls[start..index].trim());
+                start = index + 1;
             }
-            ')' => {
-                paren_depth -= 1;
-                current.push(ch);
-            }
-            ',' if paren_depth == 0 => {
-                let trimmed = current.trim().to_string();
-                if !trimmed.is_empty() {
-                    results.push(trimmed);
-                }
-                current.clear();
+            _ => {}
+        }
+    }
+    results.push(calls[start..].trim());
+
+    results.retain(|call| !call.is_empty());
+    results
+}
+
+/// Reads a call like `name(a="x", b=2)` into its name and a JSON object of its arguments
+pub fn parse_call(call: &str) -> Option<ToolCall> {
+    let open = call.find('(')?;
+    let close = call.rfind(')').filter(|close| *close > open)?;
+    // <|python_tag|> calls look like name.call(...)
+    let name = call[..open].trim().trim_end_matches(".call").to_string();
+    let args = &call[open + 1..close];
+
+    let mut arguments = serde_json::Map::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0;
+    let mut segments = Vec::new();
+
+    for ch in args.chars() {
+        match ch {
+            '"' | '\'' if quote.is_none() => quote = Some(ch),
+            c if Some(c) == quote && !current.ends_with('\\') => quote = None,
+            '[' | '{' | '(' if quote.is_none() => depth += 1,
+            ']' | '}' | ')' if quote.is_none() => depth -= 1,
+            ',' if quote.is_none() && depth == 0 => {
+                segments.push(std::mem::take(&mut current));
+                continue;
             }
-            _ => current.push(ch),
+            _ => {}
         }
+        current.push(ch);
     }
+    segments.push(current);
 
-    let trimmed = current.trim().to_string();
-    if !trimmed.is_empty() {
-        results.push(trimmed);
+    for segment in segments {
+        let Some((key, value)) = segment.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+
+        let value = match value {
+            "True" => serde_json::Value::Bool(true),
+            "False" => serde_json::Value::Bool(false),
+            "None" => serde_json::Value::Null,
+            v => serde_json::from_str(v).unwrap_or_else(|_| {
+                serde_json::Value::String(v.trim_matches(|c| c == '"' || c == '\'').into())
+            }),
+        };
+
+        arguments.insert(key.trim().into(), value);
     }
 
-    results
+    Some(ToolCall { name, arguments })
 }
 
 fn parse_arguments(args_str: &str) -> (HashMap<String, (String, String)>, Vec<String>) {
@@ -497,3 +999,232 @@ fn parse_arguments(args_str: &str) -> (HashMap<String, (String, String)>, Vec<St
 
     (properties, required)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_file;
+
+    fn write_tool_file(source: &str) -> std::path::PathBuf {
+        temp_file("tools", "py", source)
+    }
+
+    fn introspect(source: &str) -> Vec<Tool> {
+        let path = write_tool_file(source);
+        let tools = introspect_python_functions(&path.to_string_lossy()).unwrap();
+        let _ = fs::remove_file(&path);
+        tools.tools
+    }
+
+    #[test]
+    fn google_style_docs_and_awkward_signatures() {
+        let tools = introspect(
+            r#"
+import functools
+
+@functools.cache
+def get_weather(
+    city: str,
+    days: Optional[int] = 3,
+    units: str = "metric, please",
+    *args,
+    verbose: bool = False,
+    **kwargs,
+):
+    '''Get the weather forecast.
+
+    Args:
+        city (str): The city to look up.
+            Include the country if it's ambiguous.
+        days: How many days ahead.
+
+    Returns:
+        The forecast.
+    '''
+
+def _helper():
+    """Not a tool"""
+"#,
+        );
+
+        assert_eq!(tools.len(), 1);
+        let tool = &tools[0];
+        assert_eq!(tool.name, "get_weather");
+        assert_eq!(tool.description, "Get the weather forecast.");
+        assert_eq!(tool.required, vec!["city"]);
+
+        assert_eq!(
+            tool.properties["city"],
+            (
+                "str".into(),
+                "The city to look up. Include the country if it's ambiguous.".into()
+            )
+        );
+        assert_eq!(
+            tool.properties["days"],
+            ("int".into(), "How many days ahead. (defaults to 3)".into())
+        );
+        assert_eq!(
+            tool.properties["units"],
+            ("str".into(), "Defaults to 'metric, please'".into())
+        );
+        assert_eq!(tool.properties["verbose"].0, "bool");
+        assert!(!tool.properties.contains_key("args"));
+        assert!(!tool.properties.contains_key("kwargs"));
+    }
+
+    #[test]
+    fn numpy_style_docs_and_untyped_functions() {
+        let tools = introspect(
+            r#"
+def tag(names: list[str], limit: int | None = None):
+    """Tags things
+
+    Parameters
+    ----------
+    names : list of str
+        The names
+        to tag
+    limit : int, optional
+        At most this many
+    """
+
+def ping(host):
+    return host
+"#,
+        );
+
+        assert_eq!(tools.len(), 2);
+        assert_eq!(
+            tools[0].properties["names"],
+            ("list[str]".into(), "The names to tag".into())
+        );
+        assert_eq!(tools[0].properties["limit"].0, "int");
+        assert_eq!(tools[0].required, vec!["names"]);
+
+        assert_eq!(tools[1].name, "ping");
+        assert_eq!(tools[1].description, "");
+        assert_eq!(tools[1].properties["host"], (String::new(), String::new()));
+    }
+
+    #[test]
+    fn runner_checks_arguments_against_type_hints() {
+        let path = write_tool_file(
+            r#"
+from typing import Literal
+
+def join(words: list[str], separator: Literal["-", "+"] = "-", times: int = 1):
+    print("joining")
+    return separator.join(words) * times
+"#,
+        );
+        let tools = introspect_python_functions(&path.to_string_lossy()).unwrap();
+
+        let run = |call: &str| {
+            let call = parse_call(call).ok_or("unreadable call")?;
+            run_tool(&tools, &call, None).map_err(|e| e.to_string())
+        };
+        let joined = run(r#"join(words=["a", "b"], separator="+", times=2)"#);
+        let wrong_type = run(r#"join(words="a b")"#);
+        let wrong_literal = run(r#"join(words=["a"], separator="*")"#);
+        let made_up = run(r#"join(words=["a"], loud=True)"#);
+        let injected = run(r#"join(words=["a"]) or __import__("os")"#);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(joined.unwrap().for_llm(), "joining\na+ba+b");
+        assert!(wrong_type.unwrap_err().contains("words should be"));
+        assert!(wrong_literal.unwrap_err().contains("separator should be"));
+        assert!(made_up.unwrap_err().contains("no argument called loud"));
+        assert!(injected.is_err());
+    }
+
+    #[test]
+    fn results_are_labelled_and_cut_down_to_the_budget() {
+        let returned = ToolResult {
+            exit_code: Some(0),
+            value: Some("sunny".into()),
+            ..ToolResult::default()
+        };
+        assert_eq!(returned.for_llm(), "sunny");
+
+        let failed = ToolResult {
+            stderr: "warning: old api\n".into(),
+            exit_code: Some(1),
+            error: Some("KeyError: 'city'".into()),
+            ..ToolResult::default()
+        };
+        assert_eq!(
+            failed.for_llm(),
+            "stderr:\nwarning: old api\nExit code: 1\nError: KeyError: 'city'"
+        );
+
+        let count_words = |text: &str| text.split_whitespace().count();
+        let mut long = ToolResult {
+            stdout: (0..1000).map(|i| format!("line {}\n", i)).collect(),
+            exit_code: Some(0),
+            value: Some(serde_json::json!({"lines": 1000})),
+            ..ToolResult::default()
+        };
+        long.truncate_to(200, count_words);
+        let text = long.for_llm();
+
+        assert!(long.truncated);
+        assert!(count_words(&text) <= 200);
+        assert!(text.starts_with("line 0\n"));
+        assert!(text.contains("line 999"));
+        assert!(text.contains("{\"lines\":1000}"));
+    }
+
+    #[test]
+    fn every_xml_tool_call_is_read() {
+        let reply = r#"Let me check both. <tool_call>
+{"name": "weather", "arguments": {"city": "Paris"}}
+</tool_call>
+<tool_call>
+{"name": "weather", "arguments": {"city": "Oslo"}}
+</tool_call>"#;
+
+        let (format, calls) = try_parse_tool_call(reply).unwrap();
+        assert_eq!(format, ToolFormat::ToolCallXml);
+        assert_eq!(
+            calls.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec![r#"weather(city="Paris")"#, r#"weather(city="Oslo")"#]
+        );
+        assert_eq!(text_before_tool_call(reply), "Let me check both.");
+        assert_eq!(
+            text_before_tool_call(r#"{"name": "weather", "parameters": {}}"#),
+            ""
+        );
+    }
+
+    #[test]
+    fn brackets_and_commas_in_quotes_stay_in_the_argument() {
+        let reply = r#"<|tool_call_start|>[note(text="a) first, b) second"), weather(city='Oslo, Norway')]<|tool_call_end|>"#;
+
+        let (format, calls) = try_parse_tool_call(reply).unwrap();
+        assert_eq!(format, ToolFormat::ToolCallTags);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "note");
+        assert_eq!(calls[0].arguments["text"], "a) first, b) second");
+        assert_eq!(calls[1].name, "weather");
+        assert_eq!(calls[1].arguments["city"], "Oslo, Norway");
+    }
+
+    #[test]
+    fn calls_are_only_complete_once_their_brackets_close() {
+        let python = r#"<|python_tag|>note.call(text="a) first, b) second""#;
+        assert!(!is_tool_call_complete(python));
+        assert!(is_tool_call_complete(&format!("{})", python)));
+
+        let functools = r#"functools[{"name": "note", "arguments": {"text": "[1] and ]"}}"#;
+        assert!(!is_tool_call_complete(functools));
+        assert!(is_tool_call_complete(&format!("{}]", functools)));
+
+        assert_eq!(
+            tool_call_marker("Sure. <tool_call>"),
+            Some(ToolFormat::ToolCallXml)
+        );
+        assert_eq!(tool_call_marker("functools["), Some(ToolFormat::Functools));
+        assert_eq!(tool_call_marker(r#"{"name": "note"}"#), None);
+    }
+}
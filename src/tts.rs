@@ -1,26 +1,82 @@
 use std::process::{Command, Stdio};
-use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
 use regex::Regex;
 
-use crate::state::{LifeCycleState, LlmState, StateHandle};
+use crate::logging::{self, WorkerHandle};
+use crate::metrics;
+use crate::state::{Event, LifeCycleState, LlmState, StateHandle, Subscription};
 
 pub struct TtsHandle {
-    _handle: JoinHandle<()>,
+    pub handle: WorkerHandle,
 }
 
-pub fn spawn_tts_thread(state: StateHandle, model_path: String) -> TtsHandle {
-    let handle = thread::spawn(move || {
-        let _ = run_tts_loop(state, model_path);
+/// Says things out loud. Piper in the app, something that only records what it was given in tests.
+pub trait Speaker: Send + 'static {
+    /// Gets `text` ready to be played
+    fn synthesize(&mut self, text: &str) -> anyhow::Result<()>;
+    /// Plays whatever was synthesized last, returning once it's finished
+    fn play(&mut self) -> anyhow::Result<()>;
+}
+
+/// Runs piper into a wav file, then plays it with ffplay
+pub struct Piper {
+    model_path: String,
+}
+
+impl Piper {
+    pub fn new(model_path: String) -> Self {
+        Piper { model_path }
+    }
+}
+
+impl Speaker for Piper {
+    fn synthesize(&mut self, text: &str) -> anyhow::Result<()> {
+        Command::new("piper")
+            .args([
+                "--model",
+                &self.model_path,
+                "--output_file",
+                "out.wav",
+                "--",
+                text,
+            ])
+            .spawn()?
+            .wait()?;
+
+        Ok(())
+    }
+
+    fn play(&mut self) -> anyhow::Result<()> {
+        Command::new("ffplay")
+            .args(["-nodisp", "-autoexit", "out.wav"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?
+            .wait()?;
+
+        Ok(())
+    }
+}
+
+pub fn spawn_tts_thread(state: StateHandle, speaker: impl Speaker) -> TtsHandle {
+    // Subscribed before the thread starts, so nothing queued while it's starting up gets missed
+    let events = state.subscribe();
+    let handle = logging::spawn_worker("tts", state.clone(), move || {
+        run_tts_loop(state, events, speaker)
     });
 
-    TtsHandle { _handle: handle }
+    TtsHandle { handle }
 }
 
-fn run_tts_loop(state: StateHandle, model_path: String) -> anyhow::Result<()> {
+fn run_tts_loop(
+    state: StateHandle,
+    events: Subscription,
+    mut speaker: impl Speaker,
+) -> anyhow::Result<()> {
     let re = Regex::new(r"(<think>[\s\S]*?<\/think>)*(\**)*")?;
 
-    while state.subscribe().recv().is_ok() {
+    while events.wait() {
         let mut current_state = state.read();
 
         if current_state.life_cycle_state == LifeCycleState::ShuttingDown {
@@ -39,29 +95,26 @@ fn run_tts_loop(state: StateHandle, model_path: String) -> anyhow::Result<()> {
                     continue;
                 }
 
-                Command::new("piper")
-                    .args([
-                        "--model",
-                        &model_path,
-                        "--output_file",
-                        "out.wav",
-                        "--",
-                        &format!("{}", re.replace_all(&text, "")),
-                    ])
-                    .spawn()?
-                    .wait()?;
+                tracing::debug!(text = %text, "speaking");
+
+                speaker.synthesize(&re.replace_all(&text, ""))?;
 
                 state.update(|s| {
                     s.llm_state = LlmState::RunningTts;
+
+                    // As close to the first sample being played as we get without our own audio output
+                    if let Some(timings) = s.turn_timings.as_mut()
+                        && timings.first_audio.is_none()
+                        && timings.turn_complete.is_none()
+                    {
+                        timings.first_audio = Some(Instant::now());
+                    }
                 });
 
                 // Play the audio
-                Command::new("ffplay")
-                    .args(["-nodisp", "-autoexit", "out.wav"])
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .spawn()?
-                    .wait()?;
+                state.publish(Event::TtsStarted);
+                speaker.play()?;
+                state.publish(Event::TtsFinished);
             }
 
             current_state = state.read();
@@ -72,6 +125,15 @@ fn run_tts_loop(state: StateHandle, model_path: String) -> anyhow::Result<()> {
                 });
             }
         }
+
+        // Everything's been said, so the turn is over if the llm is done with it too
+        if current_state.tts_commands.is_empty()
+            && current_state.turn_timings.as_ref().is_some_and(|timings| {
+                timings.generation_done.is_some() && timings.turn_complete.is_none()
+            })
+        {
+            state.update(metrics::complete_turn);
+        }
     }
 
     Ok(())
@@ -1,18 +1,21 @@
-use std::{
-    io::{self, Write},
-    thread::{self, JoinHandle},
-};
+use std::io::{self, Write};
 
 use crossterm::terminal;
 use regex::Regex;
 
-use crate::state::{ConversationSnippet, LifeCycleState, LlmRole, LlmState, State, StateHandle};
+use crate::config::Assistant;
+use crate::logging::{self, WorkerHandle};
+use crate::metrics::TurnMetrics;
+use crate::state::{
+    ConversationSnippet, LifeCycleState, LlmRole, LlmState, MemoryView, State, StateHandle,
+};
 
 pub fn run_ui_loop(state: StateHandle, model_name: String, enable_word_by_word_response: bool) {
     let re = Regex::new(r"(<think>[\s\S]*?<\/think>)*").ok();
+    let events = state.subscribe();
     let mut previous_state = state.read();
 
-    loop {
+    while events.wait() {
         let s = state.read();
         if s == previous_state
             || s.life_cycle_state == LifeCycleState::Initializing
@@ -28,12 +31,13 @@ pub fn run_ui_loop(state: StateHandle, model_name: String, enable_word_by_word_r
         }
 
         let _ = print_conversation(s, &re, &model_name);
+        // Tokens can come in faster than the terminal redraws, let a few pile up
         std::thread::sleep(std::time::Duration::from_millis(8));
     }
 }
 
 pub struct UiHandle {
-    _handle: JoinHandle<()>,
+    pub handle: WorkerHandle,
 }
 
 pub fn spawn_ui_thread(
@@ -41,11 +45,12 @@ pub fn spawn_ui_thread(
     model_name: String,
     enable_word_by_word_response: bool,
 ) -> UiHandle {
-    let handle = thread::spawn(move || {
+    let handle = logging::spawn_worker("ui", state.clone(), move || {
         run_ui_loop(state, model_name, enable_word_by_word_response);
+        Ok(())
     });
 
-    UiHandle { _handle: handle }
+    UiHandle { handle }
 }
 
 /// Clears the screen and moves cursor to top
@@ -101,6 +106,28 @@ pub fn status_pruning() {
     flush();
 }
 
+pub fn status_recovering() {
+    status("Remembering previous session...");
+}
+
+pub fn journal_recovery_prompt(messages: usize) {
+    show_cursor();
+    print!(
+        "The last session didn't shut down cleanly ({} messages).\n\rRemember it? (Y/n): ",
+        messages
+    );
+    flush();
+}
+
+pub fn status_memories_kept_in_journal() {
+    clear_screen();
+    show_cursor();
+    print!(
+        "The LLM stopped so this conversation couldn't be remembered now, it will be next time."
+    );
+    flush();
+}
+
 pub fn status_goodbye() {
     clear_screen();
     show_cursor();
@@ -110,58 +137,35 @@ pub fn status_goodbye() {
 
 // === Conversation Display ===
 
-fn print_conversation(state: State, re: &Option<Regex>, model_name: &String) -> anyhow::Result<()> {
-    clear_screen();
-    print!("=== Conversation ===\n\r");
+fn print_memories(view: &MemoryView, memories_in_system_prompt: bool) {
+    print!("=== Memories ===\n\r");
 
-    let history = state.conversation;
+    if view.memories.is_empty() {
+        print!("Nothing remembered yet.\n\r");
+    }
 
-    for ConversationSnippet {
-        message,
-        role,
-        is_tool_call,
-    } in history
-    {
-        if is_tool_call {
-            continue;
+    for (i, memory) in view.memories.iter().enumerate() {
+        if i == view.selected {
+            print!("\x1b[7m{}\x1b[0m\n\r", memory);
+        } else {
+            print!("{}\n\r", memory);
         }
+    }
 
-        match role {
-            LlmRole::Assistant => {
-                if let Some(reg) = &re
-                    && state.is_hiding_think_tags
-                {
-                    print!(
-                        "{}: {}\n\r",
-                        model_name,
-                        reg.replace_all(&message.replace("\n", "\n\r"), "").trim()
-                    );
-                } else {
-                    print!("{}: {}\n\r", model_name, message.replace("\n", "\n\r"));
-                }
-            }
-            LlmRole::User => print!("\nYou: {}\n\n\r", message),
-            _ => (),
-        }
+    print!("---\n\r");
+    if memories_in_system_prompt {
+        print!("Changes apply from the next session.\n\r");
     }
+    print!("↑/↓ select, e edit, x delete, esc close\n\r");
+}
 
-    match state.llm_state {
-        LlmState::RunningInference => print!("---\n\rThinking...\n\r"),
-        LlmState::RunningTts | LlmState::InitializingTts => print!("---\n\r"),
-        LlmState::AwaitingInput => {
-            if state.user_mute {
-                print!("---\n\r");
-            } else if state.is_only_responding_after_name
-                && match state.time_since_name_was_said {
-                    None => true,
-                    Some(instant) => instant.elapsed().as_secs() > 5,
-                }
-            {
-                print!("---\n\rListening for {}...\r\n", model_name);
-            } else {
-                print!("---\n\rListening...\n\r");
-            }
-        }
+fn print_conversation(state: State, re: &Option<Regex>, model_name: &String) -> anyhow::Result<()> {
+    clear_screen();
+
+    if let Some(view) = &state.memory_view {
+        print_memories(view, state.memories_in_system_prompt);
+    } else {
+        print_history(&state, re, model_name);
     }
 
     if let Some((buffer, cursor_pos)) = state.text_input {
@@ -173,8 +177,10 @@ fn print_conversation(state: State, re: &Option<Regex>, model_name: &String) ->
 
         let (width, _height) = terminal::size()?;
 
-        if state.is_editing {
-            print!("[Editing last message]\n\r");
+        if state.memory_view.is_some() {
+            print!("[Editing memory]\n\r");
+        } else if state.is_editing {
+            print!("[Editing message, ↑/↓ to pick another]\n\r");
         }
 
         print!("> {}", buffer);
@@ -209,24 +215,189 @@ fn print_conversation(state: State, re: &Option<Regex>, model_name: &String) ->
     Ok(())
 }
 
+fn print_history(state: &State, re: &Option<Regex>, model_name: &String) {
+    print!("=== Conversation ===\n\r");
+
+    for (
+        index,
+        ConversationSnippet {
+            message,
+            role,
+            is_tool_call,
+            ..
+        },
+    ) in state.conversation.iter().enumerate()
+    {
+        if *is_tool_call {
+            continue;
+        }
+
+        match role {
+            LlmRole::Assistant => {
+                if let Some(reg) = re
+                    && state.is_hiding_think_tags
+                {
+                    print!(
+                        "{}: {}\n\r",
+                        model_name,
+                        reg.replace_all(&message.replace("\n", "\n\r"), "").trim()
+                    );
+                } else {
+                    print!("{}: {}\n\r", model_name, message.replace("\n", "\n\r"));
+                }
+            }
+            LlmRole::User => {
+                let marker = if state.edit_target == Some(index) {
+                    " [editing]".to_string()
+                } else if let Some((position, count)) = state.conversation.branch_info(index) {
+                    format!(" [branch {}/{}]", position, count)
+                } else {
+                    String::new()
+                };
+
+                print!("\nYou{}: {}\n\n\r", marker, message)
+            }
+            _ => (),
+        }
+    }
+
+    if state.conversation.last_branch_point().is_some()
+        && state.llm_state != LlmState::RunningInference
+    {
+        print!("[/] to switch branches\n\r");
+    }
+
+    if state.reply_alternatives.len() > 1 && state.llm_state != LlmState::RunningInference {
+        print!(
+            "[{}/{}] ←/→ to switch replies\n\r",
+            state.selected_alternative + 1,
+            state.reply_alternatives.len()
+        );
+    }
+
+    if let Some(metrics) = &state.last_turn_metrics {
+        print!("{}\n\r", format_metrics(metrics));
+    }
+
+    for error in &state.worker_errors {
+        print!("\x1b[31m{}\x1b[0m\n\r", error);
+    }
+
+    if let Some(status) = &state.status_line {
+        print!("\x1b[2m{}\x1b[0m\n\r", status);
+    }
+
+    if let Some(pending) = &state.pending_tool_call {
+        print!(
+            "---\n\r\x1b[33mRun {}? (y/n, or say yes or no)\x1b[0m\n\r",
+            pending.call
+        );
+        return;
+    }
+
+    if state.pending_forget.is_some() {
+        print!("---\n\r\x1b[33mForget that? (y/n, or say yes or no)\x1b[0m\n\r");
+        return;
+    }
+
+    match state.llm_state {
+        LlmState::RunningInference => print!("---\n\rThinking...\n\r"),
+        LlmState::RunningTts | LlmState::InitializingTts => print!("---\n\r"),
+        LlmState::AwaitingInput => {
+            if state.user_mute {
+                print!("---\n\r");
+            } else if state.is_waiting_for_name
+                || state.is_only_responding_after_name
+                    && match state.time_since_name_was_said {
+                        None => true,
+                        Some(instant) => instant.elapsed().as_secs() > 5,
+                    }
+            {
+                print!("---\n\rListening for {}...\r\n", model_name);
+            } else {
+                print!("---\n\rListening...\n\r");
+            }
+        }
+    }
+}
+
+/// One line summary of the last turn, dimmed so it stays out of the way
+fn format_metrics(metrics: &TurnMetrics) -> String {
+    let seconds = |ms: Option<u64>| match ms {
+        Some(ms) => format!("{:.2}s", ms as f32 / 1000.0),
+        None => "-".into(),
+    };
+
+    let mut parts = vec![
+        format!("first audio {}", seconds(metrics.time_to_first_audio_ms)),
+        format!("first token {}", seconds(metrics.first_token_ms)),
+    ];
+
+    if let Some(tokens_per_second) = metrics.tokens_per_second {
+        parts.push(format!("{:.1} tok/s", tokens_per_second));
+    }
+
+    if let Some(real_time_factor) = metrics.stt_real_time_factor {
+        parts.push(format!("STT RTF {:.2}", real_time_factor));
+    }
+
+    format!("\x1b[2m{}\x1b[0m", parts.join(" | "))
+}
+
 // === Cleanup ===
 
 pub fn restore_cursor() {
+    // The input thread may not have been the one to shut things down
+    let _ = terminal::disable_raw_mode();
     show_cursor();
     flush();
 }
 
 // === Errors ===
 
-pub fn error_stream(e: impl std::fmt::Debug) {
-    println!("GOT STREAM ERROR {:?}", e);
-}
-
 #[allow(dead_code)]
 pub fn debug_audio_captured() {
     println!("AUDIO CAPTURED");
 }
 
+// === Benchmark ===
+
+pub fn benchmark_header(assistant: &Assistant, prompt_tokens: usize, max_tokens: usize) {
+    print!(
+        "=== Benchmark: {} ===\n\rPrompt: {} tokens, generating up to {}\n\r\n\r",
+        assistant.name, prompt_tokens, max_tokens
+    );
+    flush();
+}
+
+pub fn benchmark_result(label: &str, tokens: usize, seconds: f32, acceptance: Option<f32>) {
+    print!(
+        "  {:<16} {:>4} tokens in {:>6.2}s = {:>6.2} tokens/s",
+        label,
+        tokens,
+        seconds,
+        tokens as f32 / seconds.max(f32::EPSILON)
+    );
+
+    if let Some(acceptance) = acceptance {
+        print!(" ({:.0}% of draft tokens accepted)", acceptance * 100.0);
+    }
+
+    print!("\n\r");
+    flush();
+}
+
+pub fn benchmark_summary(speedup: f32, outputs_match: bool) {
+    print!("\n\rSpeedup: {:.2}x\n\r", speedup);
+
+    if !outputs_match {
+        // Checking a batch of tokens can round slightly differently to decoding them one by one
+        print!("The two runs wrote slightly different text\n\r");
+    }
+
+    flush();
+}
+
 // === Assistant Selection ===
 
 pub fn assistant_selection_header() {
@@ -4,7 +4,7 @@ use ringbuf::{
 };
 use webrtc_vad::Vad;
 
-use crate::state::{LifeCycleState, StateHandle};
+use crate::state::StateHandle;
 
 fn downsample_to_16k_box(input: &[f32], in_rate: u32) -> Vec<f32> {
     let step = in_rate as f32 / 16_000.0;
@@ -73,7 +73,7 @@ pub fn run_vad(
     let mut speaking_len = 0;
 
     loop {
-        if state.read().life_cycle_state == LifeCycleState::ShuttingDown {
+        if state.is_shutting_down() {
             break;
         }
 
@@ -0,0 +1,80 @@
+# A tiny MCP server for the tests, speaking just enough of the protocol over stdio.
+# Its tools come in two pages, and it pings the client in the middle of a call.
+import json
+import os
+import sys
+
+TOOLS = [
+    {
+        "name": "add",
+        "description": "Adds two numbers",
+        "inputSchema": {
+            "type": "object",
+            "properties": {
+                "a": {"type": "number", "description": "The first number"},
+                "b": {"type": "number", "description": "The second number"},
+            },
+            "required": ["a", "b"],
+        },
+    },
+    {
+        "name": "greet",
+        "description": "Says the greeting it was started with",
+        "inputSchema": {"type": "object", "properties": {}},
+    },
+    {
+        "name": "fail",
+        "description": "Always fails",
+        "inputSchema": {"type": "object", "properties": {}},
+    },
+]
+
+
+def send(message):
+    print(json.dumps({"jsonrpc": "2.0", **message}), flush=True)
+
+
+def call(name, arguments):
+    if name == "add":
+        send({"method": "notifications/message", "params": {"level": "info", "data": "adding"}})
+        send({"id": "ping-1", "method": "ping"})
+        total = arguments["a"] + arguments["b"]
+        return {"content": [{"type": "text", "text": str(total)}]}
+    if name == "greet":
+        return {"content": [], "structuredContent": {"greeting": os.environ["FIXTURE_GREETING"]}}
+    if name == "fail":
+        return {"content": [{"type": "text", "text": "it broke"}], "isError": True}
+    return None
+
+
+for line in sys.stdin:
+    message = json.loads(line)
+    # Notifications, and the client's answers to our pings
+    if "method" not in message or "id" not in message:
+        continue
+
+    method = message["method"]
+    params = message.get("params") or {}
+    result = None
+
+    if method == "initialize":
+        result = {
+            "protocolVersion": params["protocolVersion"],
+            "capabilities": {"tools": {}},
+            "serverInfo": {"name": "fixture", "version": "1.0"},
+        }
+    elif method == "tools/list":
+        if params.get("cursor") == "page-2":
+            result = {"tools": TOOLS[2:]}
+        else:
+            result = {"tools": TOOLS[:2], "nextCursor": "page-2"}
+    elif method == "tools/call":
+        result = call(params["name"], params.get("arguments") or {})
+        if result is None:
+            send({"id": message["id"], "error": {"code": -32602, "message": f"no tool called {params['name']}"}})
+            continue
+
+    if result is None:
+        send({"id": message["id"], "error": {"code": -32601, "message": f"unknown method {method}"}})
+    else:
+        send({"id": message["id"], "result": result})
@@ -0,0 +1,69 @@
+"""Writes a 16kHz mono WAV of vowel-like sounds the VAD takes for speech, between stretches of
+silence. Not words, so it's only good for testing what the VAD picks up.
+
+    python synth_speech.py speech.wav 1.5 6
+    python synth_speech.py blip.wav 0.3 1
+"""
+
+import math
+import struct
+import sys
+import wave
+
+RATE = 16000
+
+# Formant frequency, bandwidth and gain for "ah", "ee" and "eh"
+VOWELS = [
+    [(730, 90, 1.0), (1090, 110, 0.5), (2440, 170, 0.25)],
+    [(270, 60, 1.0), (2290, 100, 0.4), (3010, 120, 0.2)],
+    [(570, 80, 1.0), (840, 80, 0.6), (2410, 160, 0.2)],
+]
+
+
+def formant_gain(frequency, formants):
+    return sum(
+        gain / math.sqrt(1 + ((frequency - centre) / (bandwidth / 2)) ** 2)
+        for centre, bandwidth, gain in formants
+    )
+
+
+def speech(seconds, syllables):
+    samples = []
+    phase = 0.0
+
+    for i in range(int(seconds * RATE)):
+        t = i / RATE
+        pitch = 125 + 20 * math.sin(2 * math.pi * 0.7 * t)
+        phase += 2 * math.pi * pitch / RATE
+
+        progress = t / seconds * syllables
+        formants = VOWELS[min(int(progress), syllables - 1) % len(VOWELS)]
+        envelope = math.sin(math.pi * (progress % 1.0)) ** 0.5
+
+        sample = 0.0
+        harmonic = 1
+        while harmonic * pitch < 3800:
+            gain = formant_gain(harmonic * pitch, formants) / harmonic**0.3
+            sample += gain * math.sin(harmonic * phase)
+            harmonic += 1
+        samples.append(sample * envelope)
+
+    peak = max(abs(sample) for sample in samples)
+    return [0.5 * sample / peak for sample in samples]
+
+
+def silence(seconds):
+    return [0.0] * int(seconds * RATE)
+
+
+if __name__ == "__main__":
+    path, seconds, syllables = sys.argv[1], float(sys.argv[2]), int(sys.argv[3])
+    samples = silence(0.5) + speech(seconds, syllables) + silence(1.5)
+
+    with wave.open(path, "wb") as wav:
+        wav.setnchannels(1)
+        wav.setsampwidth(2)
+        wav.setframerate(RATE)
+        wav.writeframes(
+            b"".join(struct.pack("<h", int(max(-1, min(1, x)) * 32767)) for x in samples)
+        )
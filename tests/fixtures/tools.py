@@ -0,0 +1,3 @@
+def shout(text: str):
+    """Repeats the text in capitals"""
+    return text.upper()